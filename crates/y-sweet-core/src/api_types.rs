@@ -70,6 +70,78 @@ pub struct DocCreationRequest {
     pub doc_id: Option<String>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocListResponse {
+    /// The IDs of the documents in this page, in lexicographic order.
+    pub docs: Vec<String>,
+
+    /// If more documents may remain, a cursor to pass to the next request.
+    #[serde(rename = "nextCursor")]
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocVersion {
+    /// The ID of the version, used to restore it.
+    pub id: String,
+
+    /// When the version was written, in milliseconds since the Unix epoch.
+    #[serde(rename = "createdAt")]
+    pub created_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocVersionsResponse {
+    /// The stored versions of the document, oldest first.
+    pub versions: Vec<DocVersion>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuarantinedSnapshot {
+    /// The ID of the quarantined snapshot, used to restore or discard it.
+    pub id: String,
+
+    /// When the snapshot was quarantined, in milliseconds since the Unix epoch.
+    #[serde(rename = "createdAt")]
+    pub created_at: u64,
+
+    /// The size of the snapshot in bytes.
+    pub size: usize,
+
+    /// Why the snapshot cannot be decoded, or `None` if it can be now.
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuarantineResponse {
+    /// The quarantined snapshots of the document, oldest first.
+    pub snapshots: Vec<QuarantinedSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct UnpersistedDoc {
+    #[serde(rename = "docId")]
+    pub doc_id: String,
+
+    /// How many times in a row persisting the document has failed.
+    pub failures: u32,
+
+    /// When persisting first failed, in milliseconds since the Unix epoch.
+    #[serde(rename = "failingSince")]
+    pub failing_since: u64,
+
+    /// The error from the most recent attempt.
+    #[serde(rename = "lastError")]
+    pub last_error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UnpersistedDocsResponse {
+    /// Loaded documents whose latest changes could not be persisted, longest
+    /// failing first.
+    pub docs: Vec<UnpersistedDoc>,
+}
+
 /// Validate that the document name contains only alphanumeric characters, dashes, and underscores.
 /// This is the same alphabet used by nanoid when we generate a document name.
 pub fn validate_doc_name(doc_name: &str) -> bool {
@@ -0,0 +1,113 @@
+//! Compaction of a document's stored data.
+//!
+//! The stored data normally holds a single encoding of the document, but it can
+//! also hold update entries that were not merged into it yet (e.g. when a snapshot
+//! was taken between an update and its merge) and keys that no longer belong to
+//! the document. Compaction replaces it with a fresh encoding of the live document,
+//! which has the same CRDT state.
+
+use std::time::Duration;
+
+/// When to compact documents in the background. Compaction is disabled unless at
+/// least one trigger is set.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CompactionOptions {
+    /// Compact a document that has changed when this long has passed since it was
+    /// last compacted.
+    pub interval: Option<Duration>,
+    /// Compact a document when its size has grown by this factor since it was last
+    /// compacted.
+    pub growth_factor: Option<f64>,
+}
+
+impl CompactionOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.interval.is_some() || self.growth_factor.is_some()
+    }
+}
+
+/// Growth is measured from at least this size, so that small documents are not
+/// compacted every time they grow by a few bytes.
+const MIN_GROWTH_BASE: usize = 64 * 1024;
+
+/// The size of a document's stored data before and after it was compacted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compaction {
+    pub before: usize,
+    pub after: usize,
+}
+
+impl Compaction {
+    pub fn reclaimed(&self) -> usize {
+        self.before - self.after
+    }
+}
+
+/// The size and time of a document's last compaction, which decide when it is
+/// next due.
+#[derive(Clone, Copy, Debug)]
+pub struct CompactionSchedule {
+    size: usize,
+    at: u64,
+}
+
+impl CompactionSchedule {
+    /// Start a schedule for a document of `size` bytes, at `now` milliseconds since
+    /// the Unix epoch.
+    pub fn new(size: usize, now: u64) -> Self {
+        Self { size, at: now }
+    }
+
+    pub fn is_due(&self, options: &CompactionOptions, size: usize, now: u64) -> bool {
+        if let Some(factor) = options.growth_factor {
+            if size as f64 >= self.size.max(MIN_GROWTH_BASE) as f64 * factor {
+                return true;
+            }
+        }
+        if let Some(interval) = options.interval {
+            if size != self.size && now.saturating_sub(self.at) >= interval.as_millis() as u64 {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn record(&mut self, size: usize, now: u64) {
+        self.size = size;
+        self.at = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn schedules_by_growth_and_interval() {
+        let mut schedule = CompactionSchedule::new(100_000, 0);
+
+        let growth = CompactionOptions {
+            growth_factor: Some(2.0),
+            ..Default::default()
+        };
+        assert!(!schedule.is_due(&growth, 199_999, 0));
+        assert!(schedule.is_due(&growth, 200_000, 0));
+
+        // Small documents grow from the minimum base.
+        schedule.record(10, 0);
+        assert!(!schedule.is_due(&growth, 100_000, 0));
+        assert!(schedule.is_due(&growth, 2 * MIN_GROWTH_BASE, 0));
+
+        let interval = CompactionOptions {
+            interval: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        assert!(!schedule.is_due(&interval, 20, 59_999));
+        assert!(schedule.is_due(&interval, 20, 60_000));
+        // A document that has not changed is not compacted again.
+        assert!(!schedule.is_due(&interval, 10, 60_000));
+
+        assert!(!CompactionOptions::default().is_enabled());
+        assert!(!schedule.is_due(&CompactionOptions::default(), usize::MAX, u64::MAX));
+    }
+}
@@ -1,4 +1,5 @@
 use crate::api_types::Authorization;
+use crate::limits::DocLimiter;
 use crate::sync::{
     self, awareness::Awareness, DefaultProtocol, Message, Protocol, SyncMessage, MSG_SYNC,
     MSG_SYNC_UPDATE,
@@ -34,6 +35,7 @@ pub struct DocConnection {
     authorization: Authorization,
     callback: Callback,
     closed: Arc<OnceLock<()>>,
+    limiter: Option<Arc<DocLimiter>>,
 
     /// If the client sends an awareness state, this will be set to its client ID.
     /// It is used to clear the awareness state when a client disconnects.
@@ -141,9 +143,25 @@ impl DocConnection {
             callback,
             client_id: OnceLock::new(),
             closed,
+            limiter: None,
         }
     }
 
+    /// Check updates from this connection against the document's limits. Updates
+    /// that exceed them are not applied, and the client is sent an `Auth` denial.
+    pub fn with_limiter(mut self, limiter: Arc<DocLimiter>) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// The `Auth` denial to send instead of applying `update`, if it exceeds the limits.
+    fn check_limits(&self, update: &[u8]) -> Option<Message> {
+        let limiter = self.limiter.as_ref()?;
+        let err = limiter.check_update(update.len()).err()?;
+        tracing::warn!(?err, "Rejected update exceeding document limits");
+        Some(Message::Auth(Some(err.to_string())))
+    }
+
     pub async fn send(&self, update: &[u8]) -> Result<(), anyhow::Error> {
         let msg = Message::decode_v1(update)?;
         let result = self.handle_msg(&DefaultProtocol, msg)?;
@@ -173,6 +191,9 @@ impl DocConnection {
                 }
                 SyncMessage::SyncStep2(update) => {
                     if can_write {
+                        if let Some(denial) = self.check_limits(&update) {
+                            return Ok(Some(denial));
+                        }
                         let mut awareness = a.write().unwrap();
                         protocol.handle_sync_step2(&mut awareness, Update::decode_v1(&update)?)
                     } else {
@@ -183,6 +204,9 @@ impl DocConnection {
                 }
                 SyncMessage::Update(update) => {
                     if can_write {
+                        if let Some(denial) = self.check_limits(&update) {
+                            return Ok(Some(denial));
+                        }
                         let mut awareness = a.write().unwrap();
                         protocol.handle_update(&mut awareness, Update::decode_v1(&update)?)
                     } else {
@@ -1,12 +1,23 @@
-use crate::{doc_connection::DOC_NAME, store::Store, sync::awareness::Awareness, sync_kv::SyncKv};
+use crate::{
+    compaction::{Compaction, CompactionOptions, CompactionSchedule},
+    doc_connection::DOC_NAME,
+    history::{now_millis, restore_snapshot},
+    limits::{DocLimiter, DocLimits},
+    snapshot::SnapshotData,
+    store::Store,
+    sync::awareness::Awareness,
+    sync_kv::{PersistenceOptions, SyncKv},
+};
 use anyhow::{anyhow, Context, Result};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use yrs::{updates::decoder::Decode, Doc, ReadTxn, StateVector, Subscription, Transact, Update};
 use yrs_kvstore::DocOps;
 
 pub struct DocWithSyncKv {
     awareness: Arc<RwLock<Awareness>>,
     sync_kv: Arc<SyncKv>,
+    limiter: Arc<DocLimiter>,
+    compaction: Mutex<CompactionSchedule>,
     #[allow(unused)] // acts as RAII guard
     subscription: Subscription,
 }
@@ -20,6 +31,14 @@ impl DocWithSyncKv {
         self.sync_kv.clone()
     }
 
+    pub fn limiter(&self) -> Arc<DocLimiter> {
+        self.limiter.clone()
+    }
+
+    pub fn set_limits(&self, limits: DocLimits) {
+        self.limiter.set_limits(limits);
+    }
+
     pub async fn new<F>(
         key: &str,
         store: Option<Arc<Box<dyn Store>>>,
@@ -28,7 +47,19 @@ impl DocWithSyncKv {
     where
         F: Fn() + Send + Sync + 'static,
     {
-        let sync_kv = SyncKv::new(store, key, dirty_callback)
+        Self::new_with_options(key, store, PersistenceOptions::default(), dirty_callback).await
+    }
+
+    pub async fn new_with_options<F>(
+        key: &str,
+        store: Option<Arc<Box<dyn Store>>>,
+        options: PersistenceOptions,
+        dirty_callback: F,
+    ) -> Result<Self>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let sync_kv = SyncKv::new_with_options(store, key, options, dirty_callback)
             .await
             .context("Failed to create SyncKv")?;
 
@@ -49,14 +80,42 @@ impl DocWithSyncKv {
                 sync_kv
                     .flush_doc_with(DOC_NAME, Default::default())
                     .unwrap();
+                sync_kv.log_update(&event.update);
             })
             .map_err(|_| anyhow!("Failed to subscribe to updates"))?
         };
 
         let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+
+        {
+            // If another writer changed the stored snapshot, SyncKv merges it on persist;
+            // apply it to the live document too, so that connected clients converge.
+            let awareness = Arc::downgrade(&awareness);
+            sync_kv.set_remote_update_callback(move |update| {
+                let Some(awareness) = awareness.upgrade() else {
+                    return;
+                };
+                let update = match Update::decode_v1(update) {
+                    Ok(update) => update,
+                    Err(e) => {
+                        tracing::error!(?e, "Failed to decode remote update");
+                        return;
+                    }
+                };
+                let awareness_guard = awareness.write().unwrap();
+                let mut txn = awareness_guard.doc.transact_mut();
+                txn.apply_update(update);
+            });
+        }
+
+        let limiter = Arc::new(DocLimiter::new(sync_kv.clone(), DocLimits::default()));
+        let compaction = Mutex::new(CompactionSchedule::new(sync_kv.size(), now_millis()));
+
         Ok(Self {
             awareness,
             sync_kv,
+            limiter,
+            compaction,
             subscription,
         })
     }
@@ -70,7 +129,11 @@ impl DocWithSyncKv {
         txn.encode_state_as_update_v1(&StateVector::default())
     }
 
+    /// Apply an update to the document. Fails with `LimitExceeded` if the update
+    /// is rejected by the document's limits.
     pub fn apply_update(&self, update: &[u8]) -> Result<()> {
+        self.limiter.check_update(update.len())?;
+
         let awareness_guard = self.awareness.write().unwrap();
         let doc = &awareness_guard.doc;
 
@@ -82,4 +145,39 @@ impl DocWithSyncKv {
 
         Ok(())
     }
+
+    /// Re-encode the document's stored data from the live document, dropping
+    /// anything that does not contribute to its state.
+    pub fn compact(&self) -> Result<Compaction> {
+        let compaction = {
+            let awareness_guard = self.awareness.read().unwrap();
+            let txn = awareness_guard.doc.transact();
+            self.sync_kv.compact(&txn)?
+        };
+        self.compaction
+            .lock()
+            .unwrap()
+            .record(compaction.after, now_millis());
+        Ok(compaction)
+    }
+
+    /// Compact the document if `options` say it is due. Returns `None` if it is not.
+    pub fn compact_if_due(&self, options: &CompactionOptions) -> Result<Option<Compaction>> {
+        let due =
+            self.compaction
+                .lock()
+                .unwrap()
+                .is_due(options, self.sync_kv.size(), now_millis());
+        if !due {
+            return Ok(None);
+        }
+        self.compact().map(Some)
+    }
+
+    /// Restore the document to a historical snapshot by applying the difference
+    /// as a new update, which is broadcast to connected clients and persisted.
+    pub fn restore(&self, snapshot: SnapshotData) -> Result<()> {
+        let awareness_guard = self.awareness.write().unwrap();
+        restore_snapshot(&awareness_guard.doc, snapshot)
+    }
 }
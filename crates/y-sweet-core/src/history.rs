@@ -0,0 +1,359 @@
+//! Retention of historical document snapshots.
+//!
+//! When enabled, each checkpoint may also write a copy of the snapshot to
+//! `{doc_id}/versions/{id}.ysweet`, where `id` is the zero-padded time of the
+//! checkpoint in milliseconds since the Unix epoch, so that versions sort by age.
+//! Versions created in the same millisecond get a `-{n}` suffix.
+
+use crate::{
+    api_types::DocVersion,
+    doc_connection::DOC_NAME,
+    snapshot::{decode_snapshot, SnapshotData},
+    store::{Store, StoreError},
+    sync_kv::SyncKv,
+};
+use anyhow::{anyhow, Context, Result};
+use std::time::Duration;
+use yrs::{
+    block::Prelim,
+    branch::{Branch, BranchPtr},
+    types::AsPrelim,
+    Array, ArrayRef, Doc, In, Map, MapRef, Out, ReadTxn, Text, TextRef, Transact, TransactionMut,
+    WriteTxn, XmlFragment, XmlFragmentRef,
+};
+use yrs_kvstore::DocOps;
+
+/// Which historical versions of a document to keep.
+#[derive(Clone, Debug)]
+pub struct HistoryOptions {
+    /// The maximum number of versions to keep per document.
+    pub max_versions: usize,
+    /// Versions older than this are removed.
+    pub max_age: Option<Duration>,
+    /// The minimum time between versions. Checkpoints within this interval of the
+    /// previous version do not create a new one.
+    pub min_interval: Duration,
+}
+
+fn versions_prefix(doc_id: &str) -> String {
+    format!("{}/versions/", doc_id)
+}
+
+fn version_key(doc_id: &str, version_id: &str) -> String {
+    format!("{}{}.ysweet", versions_prefix(doc_id), version_id)
+}
+
+pub(crate) fn version_id(created_at: u64, seq: u64) -> String {
+    if seq == 0 {
+        format!("{:020}", created_at)
+    } else {
+        format!("{:020}-{}", created_at, seq)
+    }
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// Parse a version ID into its creation time and sequence number within that
+/// millisecond, which is also what keeps it from escaping the versions directory.
+pub(crate) fn parse_version_id(version_id: &str) -> Option<(u64, u64)> {
+    match version_id.split_once('-') {
+        Some((created_at, seq)) => Some((parse_number(created_at)?, parse_number(seq)?)),
+        None => Some((parse_number(version_id)?, 0)),
+    }
+}
+
+pub(crate) fn now_millis() -> u64 {
+    (time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as u64
+}
+
+/// List the stored versions of a document, oldest first.
+pub async fn list_versions(store: &dyn Store, doc_id: &str) -> Result<Vec<DocVersion>> {
+    let prefix = versions_prefix(doc_id);
+    let mut versions = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = store
+            .list(&prefix, cursor.as_deref(), 1000)
+            .await
+            .context("Failed to list versions.")?;
+        for key in &page.keys {
+            let Some(id) = key
+                .strip_prefix(&prefix)
+                .and_then(|name| name.strip_suffix(".ysweet"))
+            else {
+                continue;
+            };
+            if let Some((created_at, _)) = parse_version_id(id) {
+                versions.push(DocVersion {
+                    id: id.to_string(),
+                    created_at,
+                });
+            }
+        }
+        match page.next_cursor {
+            Some(next_cursor) => cursor = Some(next_cursor),
+            None => break,
+        }
+    }
+    versions.sort_by_key(|version| parse_version_id(&version.id));
+    Ok(versions)
+}
+
+/// Read a stored version of a document, or `None` if it does not exist.
+pub async fn load_version(
+    store: &dyn Store,
+    doc_id: &str,
+    version_id: &str,
+) -> Result<Option<SnapshotData>> {
+    if parse_version_id(version_id).is_none() {
+        return Ok(None);
+    }
+    let Some(snapshot) = store
+        .get(&version_key(doc_id, version_id))
+        .await
+        .context("Failed to get version from store.")?
+    else {
+        return Ok(None);
+    };
+    Ok(Some(decode_snapshot(&snapshot)?))
+}
+
+/// Store `snapshot` as a version of the document created at `created_at`, then
+/// remove versions that fall outside of the retention options.
+pub(crate) async fn record_version(
+    store: &dyn Store,
+    doc_id: &str,
+    snapshot: Vec<u8>,
+    created_at: u64,
+    options: &HistoryOptions,
+) -> Result<()> {
+    // Never overwrite a version; another checkpoint, possibly from another
+    // server, may have created one in the same millisecond.
+    let mut seq = 0;
+    loop {
+        let key = version_key(doc_id, &version_id(created_at, seq));
+        match store.set_if(&key, snapshot.clone(), None).await {
+            Ok(_) => break,
+            Err(StoreError::Conflict(_)) => seq += 1,
+            Err(e) => return Err(e).context("Failed to write version."),
+        }
+    }
+
+    let versions = list_versions(store, doc_id).await?;
+    let excess = versions.len().saturating_sub(options.max_versions);
+    let min_created_at = options
+        .max_age
+        .map(|max_age| created_at.saturating_sub(max_age.as_millis() as u64));
+
+    for (i, version) in versions.iter().enumerate() {
+        let expired = min_created_at.is_some_and(|min| version.created_at < min);
+        if i < excess || expired {
+            tracing::info!(version = version.id, "Removing old version");
+            store
+                .remove(&version_key(doc_id, &version.id))
+                .await
+                .context("Failed to remove old version.")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Make the root-level content of `doc` equal to that of `snapshot`.
+///
+/// The change is made as ordinary edits in a transaction on `doc`: the current
+/// content is deleted and the snapshot's content is inserted anew.
+/// Connected clients receive it as a regular update and converge on the restored
+/// state, rather than diverging as they would if the document were replaced.
+pub fn restore_snapshot(doc: &Doc, snapshot: SnapshotData) -> Result<()> {
+    let historical = Doc::new();
+    {
+        let mut txn = historical.transact_mut();
+        SyncKv::from_data(snapshot)
+            .load_doc(DOC_NAME, &mut txn)
+            .map_err(|_| anyhow!("Failed to load version"))?;
+    }
+
+    let roots: Vec<(String, In)> = {
+        let txn = historical.transact();
+        txn.root_refs()
+            .map(|(name, root)| (name.to_string(), root.as_prelim(&txn)))
+            .collect()
+    };
+
+    let mut txn = doc.transact_mut();
+
+    let current: Vec<(String, Out)> = txn
+        .root_refs()
+        .map(|(name, root)| (name.to_string(), root))
+        .collect();
+    for (name, root) in current {
+        let prelim = root.as_prelim(&txn);
+        clear_root(&mut txn, &name, &prelim);
+    }
+
+    for (name, prelim) in roots {
+        match prelim {
+            In::Text(delta) => {
+                let text = txn.get_or_insert_text(name.as_str());
+                delta.integrate(&mut txn, branch_ptr(&text));
+            }
+            In::Array(array) => {
+                let root = txn.get_or_insert_array(name.as_str());
+                array.integrate(&mut txn, branch_ptr(&root));
+            }
+            In::Map(map) => {
+                let root = txn.get_or_insert_map(name.as_str());
+                map.integrate(&mut txn, branch_ptr(&root));
+            }
+            In::XmlFragment(fragment) => {
+                let root = txn.get_or_insert_xml_fragment(name.as_str());
+                fragment.integrate(&mut txn, branch_ptr(&root));
+            }
+            _ => tracing::warn!(name, "Skipping root of unsupported type during restore"),
+        }
+    }
+
+    Ok(())
+}
+
+fn branch_ptr<T: AsRef<Branch>>(shared: &T) -> BranchPtr {
+    BranchPtr::from(shared.as_ref())
+}
+
+/// Delete all of the content of the root `name`, interpreted as the type of `prelim`.
+fn clear_root(txn: &mut TransactionMut, name: &str, prelim: &In) {
+    match prelim {
+        In::Text(_) => {
+            let text: TextRef = txn.get_or_insert_text(name);
+            let len = text.len(txn);
+            text.remove_range(txn, 0, len);
+        }
+        In::Array(_) => {
+            let array: ArrayRef = txn.get_or_insert_array(name);
+            let len = array.len(txn);
+            array.remove_range(txn, 0, len);
+        }
+        In::Map(_) => {
+            let map: MapRef = txn.get_or_insert_map(name);
+            map.clear(txn);
+        }
+        In::XmlFragment(_) => {
+            let fragment: XmlFragmentRef = txn.get_or_insert_xml_fragment(name);
+            let len = fragment.len(txn);
+            fragment.remove_range(txn, 0, len);
+        }
+        _ => tracing::warn!(name, "Cannot clear root of unsupported type"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use yrs::{updates::decoder::Decode, GetString, StateVector, Update};
+
+    #[test]
+    fn restores_as_new_update() {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("text");
+        let meta = doc.get_or_insert_map("meta");
+        {
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, "hello");
+            meta.insert(&mut txn, "title", "v1");
+        }
+
+        let snapshot = {
+            let sync_kv = SyncKv::from_data(SnapshotData::new());
+            let update = doc
+                .transact()
+                .encode_state_as_update_v1(&StateVector::default());
+            sync_kv.push_update(DOC_NAME, &update).unwrap();
+            sync_kv
+                .flush_doc_with(DOC_NAME, Default::default())
+                .unwrap();
+            sync_kv.data()
+        };
+
+        let list = doc.get_or_insert_array("list");
+        {
+            let mut txn = doc.transact_mut();
+            let len = text.len(&txn);
+            text.remove_range(&mut txn, 0, len);
+            text.insert(&mut txn, 0, "goodbye");
+            meta.insert(&mut txn, "title", "v2");
+            list.push_back(&mut txn, 1);
+        }
+
+        // A client that has seen every edit so far.
+        let client = Doc::with_client_id(2);
+        {
+            let update = doc
+                .transact()
+                .encode_state_as_update_v1(&StateVector::default());
+            let mut txn = client.transact_mut();
+            txn.apply_update(Update::decode_v1(&update).unwrap());
+        }
+
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let _subscription = {
+            let updates = updates.clone();
+            doc.observe_update_v1(move |_, event| {
+                updates.lock().unwrap().push(event.update.clone());
+            })
+            .unwrap()
+        };
+
+        restore_snapshot(&doc, snapshot).unwrap();
+
+        {
+            let mut txn = client.transact_mut();
+            for update in updates.lock().unwrap().iter() {
+                txn.apply_update(Update::decode_v1(update).unwrap());
+            }
+        }
+
+        for doc in [&doc, &client] {
+            let text = doc.get_or_insert_text("text");
+            let meta = doc.get_or_insert_map("meta");
+            let list = doc.get_or_insert_array("list");
+            let txn = doc.transact();
+            assert_eq!(text.get_string(&txn), "hello");
+            assert_eq!(
+                meta.get(&txn, "title").unwrap().to_string(&txn),
+                "v1".to_string()
+            );
+            assert_eq!(list.len(&txn), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_versions_from_the_same_millisecond() {
+        let store = crate::store::memory::MemoryStore::new();
+        let options = HistoryOptions {
+            max_versions: 20,
+            max_age: None,
+            min_interval: Duration::ZERO,
+        };
+        for i in 0..12u8 {
+            record_version(&store, "doc", vec![i], 1_000, &options)
+                .await
+                .unwrap();
+        }
+
+        let versions = list_versions(&store, "doc").await.unwrap();
+        assert_eq!(versions.len(), 12);
+        assert!(versions.iter().all(|version| version.created_at == 1_000));
+        for (i, version) in versions.iter().enumerate() {
+            let key = version_key("doc", &version.id);
+            assert_eq!(store.get(&key).await.unwrap(), Some(vec![i as u8]));
+        }
+        assert!(parse_version_id("00000000000000001000-x").is_none());
+    }
+}
@@ -1,7 +1,13 @@
 pub mod api_types;
 pub mod auth;
+pub mod compaction;
 pub mod doc_connection;
 pub mod doc_sync;
+pub mod history;
+pub mod limits;
+pub mod quarantine;
+pub mod snapshot;
 pub mod store;
 pub mod sync;
 pub mod sync_kv;
+pub mod update_log;
@@ -0,0 +1,269 @@
+use crate::{store::Store, sync_kv::SyncKv};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, OnceLock, RwLock,
+};
+use thiserror::Error;
+
+#[cfg(not(feature = "sync"))]
+type ReadOnlyCallback = Box<dyn Fn() + 'static>;
+
+#[cfg(feature = "sync")]
+type ReadOnlyCallback = Box<dyn Fn() + 'static + Send + Sync>;
+
+/// Size limits on writes to a document, in bytes. `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocLimits {
+    /// The largest that the encoded document may grow to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_doc_size: Option<usize>,
+    /// The largest single update that is accepted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_update_size: Option<usize>,
+}
+
+impl DocLimits {
+    /// These limits, with any that are not set taken from `defaults`.
+    pub fn or(self, defaults: DocLimits) -> DocLimits {
+        DocLimits {
+            max_doc_size: self.max_doc_size.or(defaults.max_doc_size),
+            max_update_size: self.max_update_size.or(defaults.max_update_size),
+        }
+    }
+}
+
+/// The limits set for one document, and whether it is read-only. Stored with the
+/// document, so that they survive it being reloaded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredLimits {
+    /// The document's own limits, without the server-wide limits applied.
+    #[serde(flatten)]
+    pub limits: DocLimits,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+fn limits_key(doc_id: &str) -> String {
+    format!("{}/limits.json", doc_id)
+}
+
+/// Read a document's stored limits, or `None` if none were set.
+pub async fn load_limits(store: &dyn Store, doc_id: &str) -> Result<Option<StoredLimits>> {
+    let Some(json) = store
+        .get(&limits_key(doc_id))
+        .await
+        .context("Failed to get document limits.")?
+    else {
+        return Ok(None);
+    };
+    let limits = serde_json::from_slice(&json).context("Failed to parse document limits.")?;
+    Ok(Some(limits))
+}
+
+pub async fn save_limits(store: &dyn Store, doc_id: &str, limits: &StoredLimits) -> Result<()> {
+    let json = serde_json::to_vec(limits)?;
+    store
+        .set(&limits_key(doc_id), json)
+        .await
+        .context("Failed to set document limits.")?;
+    Ok(())
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum LimitExceeded {
+    #[error("Update of {size} bytes exceeds the limit of {limit} bytes.")]
+    UpdateTooLarge { size: usize, limit: usize },
+    #[error("Document would exceed its size limit of {limit} bytes, so it is now read-only.")]
+    DocTooLarge { limit: usize },
+    #[error("Document reached its size limit and is read-only.")]
+    ReadOnly,
+}
+
+/// Checks writes to a document against its `DocLimits`.
+///
+/// An update that would make the document larger than `max_doc_size` is rejected,
+/// and the document becomes read-only until its limits are changed. Updates larger
+/// than `max_update_size` are rejected without affecting later writes.
+pub struct DocLimiter {
+    limits: RwLock<DocLimits>,
+    read_only: AtomicBool,
+    sync_kv: Arc<SyncKv>,
+    read_only_callback: OnceLock<ReadOnlyCallback>,
+}
+
+impl DocLimiter {
+    pub fn new(sync_kv: Arc<SyncKv>, limits: DocLimits) -> Self {
+        Self {
+            limits: RwLock::new(limits),
+            read_only: AtomicBool::new(false),
+            sync_kv,
+            read_only_callback: OnceLock::new(),
+        }
+    }
+
+    pub fn limits(&self) -> DocLimits {
+        *self.limits.read().unwrap()
+    }
+
+    /// Replace the limits. This makes a read-only document writable again, until
+    /// it exceeds the new limits.
+    pub fn set_limits(&self, limits: DocLimits) {
+        *self.limits.write().unwrap() = limits;
+        self.read_only.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Make the document read-only until its limits are changed, e.g. when it is
+    /// reloaded after reaching its size limit.
+    pub fn set_read_only(&self) {
+        self.read_only.store(true, Ordering::Relaxed);
+    }
+
+    #[cfg(not(feature = "sync"))]
+    pub fn set_read_only_callback<F>(&self, callback: F)
+    where
+        F: Fn() + 'static,
+    {
+        self.set_read_only_callback_inner(Box::new(callback))
+    }
+
+    /// Register a callback that is called when the document becomes read-only by
+    /// reaching its size limit, e.g. to store that it is read-only.
+    #[cfg(feature = "sync")]
+    pub fn set_read_only_callback<F>(&self, callback: F)
+    where
+        F: Fn() + 'static + Send + Sync,
+    {
+        self.set_read_only_callback_inner(Box::new(callback))
+    }
+
+    fn set_read_only_callback_inner(&self, callback: ReadOnlyCallback) {
+        if self.read_only_callback.set(callback).is_err() {
+            tracing::warn!("Read-only callback was already set.");
+        }
+    }
+
+    /// Check whether an update of `update_size` bytes may be applied.
+    pub fn check_update(&self, update_size: usize) -> Result<(), LimitExceeded> {
+        if self.is_read_only() {
+            return Err(LimitExceeded::ReadOnly);
+        }
+
+        let limits = self.limits();
+        if let Some(limit) = limits.max_update_size {
+            if update_size > limit {
+                return Err(LimitExceeded::UpdateTooLarge {
+                    size: update_size,
+                    limit,
+                });
+            }
+        }
+        if let Some(limit) = limits.max_doc_size {
+            // An update can grow the encoded document by at most about its own
+            // size, so this rejects updates before they are applied.
+            if self.sync_kv.size() + update_size > limit {
+                if !self.read_only.swap(true, Ordering::Relaxed) {
+                    if let Some(callback) = self.read_only_callback.get() {
+                        callback();
+                    }
+                }
+                return Err(LimitExceeded::DocTooLarge { limit });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{snapshot::SnapshotData, store::memory::MemoryStore};
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn rejects_large_updates_and_documents() {
+        let sync_kv = Arc::new(SyncKv::from_data(SnapshotData::from([(
+            b"key".to_vec(),
+            vec![0; 100],
+        )])));
+        let limiter = DocLimiter::new(
+            sync_kv,
+            DocLimits {
+                max_doc_size: Some(200),
+                max_update_size: Some(50),
+            },
+        );
+        let became_read_only = Arc::new(AtomicUsize::new(0));
+        {
+            let became_read_only = became_read_only.clone();
+            limiter.set_read_only_callback(move || {
+                became_read_only.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+
+        assert_eq!(limiter.check_update(50), Ok(()));
+        assert_eq!(
+            limiter.check_update(51),
+            Err(LimitExceeded::UpdateTooLarge {
+                size: 51,
+                limit: 50
+            })
+        );
+        assert!(!limiter.is_read_only());
+
+        limiter.set_limits(DocLimits {
+            max_doc_size: Some(120),
+            max_update_size: None,
+        });
+        assert_eq!(
+            limiter.check_update(30),
+            Err(LimitExceeded::DocTooLarge { limit: 120 })
+        );
+        assert_eq!(limiter.check_update(1), Err(LimitExceeded::ReadOnly));
+        assert_eq!(became_read_only.load(Ordering::Relaxed), 1);
+
+        // Raising the limit makes the document writable again.
+        limiter.set_limits(DocLimits::default());
+        assert_eq!(limiter.check_update(1000), Ok(()));
+    }
+
+    #[test]
+    fn falls_back_to_default_limits() {
+        let defaults = DocLimits {
+            max_doc_size: Some(1000),
+            max_update_size: Some(100),
+        };
+        let limits = DocLimits {
+            max_doc_size: Some(5000),
+            max_update_size: None,
+        };
+        assert_eq!(
+            limits.or(defaults),
+            DocLimits {
+                max_doc_size: Some(5000),
+                max_update_size: Some(100),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn stores_limits() {
+        let store = MemoryStore::default();
+        assert_eq!(load_limits(&store, "doc").await.unwrap(), None);
+
+        let limits = StoredLimits {
+            limits: DocLimits {
+                max_doc_size: Some(1000),
+                max_update_size: None,
+            },
+            read_only: true,
+        };
+        save_limits(&store, "doc", &limits).await.unwrap();
+        assert_eq!(load_limits(&store, "doc").await.unwrap(), Some(limits));
+    }
+}
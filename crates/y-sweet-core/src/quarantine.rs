@@ -0,0 +1,316 @@
+//! Recovery of documents whose snapshot cannot be decoded.
+//!
+//! With `RecoveryPolicy::Quarantine`, an unreadable snapshot is copied to
+//! `{doc_id}/quarantine/{id}`, where `id` is the zero-padded time it was
+//! quarantined in milliseconds since the Unix epoch, with a `-{n}` suffix if
+//! another snapshot was quarantined in the same millisecond. The document is then loaded
+//! from its most recent readable historical version, if any, plus its update log,
+//! and written in place of the unreadable snapshot.
+
+use crate::{
+    api_types::QuarantinedSnapshot,
+    history::{list_versions, load_version, now_millis, parse_version_id, version_id},
+    snapshot::{decode_snapshot, SnapshotData},
+    store::{Store, StoreError},
+};
+use anyhow::{anyhow, Context, Result};
+use std::str::FromStr;
+
+/// What to do when a document's snapshot cannot be decoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecoveryPolicy {
+    /// Fail to load the document, leaving the snapshot in place.
+    #[default]
+    Fail,
+    /// Quarantine the snapshot and recover the document from its history and
+    /// update log.
+    Quarantine,
+}
+
+impl FromStr for RecoveryPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "quarantine" => Ok(Self::Quarantine),
+            _ => Err(anyhow!(
+                "invalid recovery policy (expected \"fail\" or \"quarantine\")"
+            )),
+        }
+    }
+}
+
+fn quarantine_prefix(doc_id: &str) -> String {
+    format!("{}/quarantine/", doc_id)
+}
+
+fn quarantine_key(doc_id: &str, id: &str) -> String {
+    format!("{}{}", quarantine_prefix(doc_id), id)
+}
+
+/// Parse a quarantine ID into its creation time and sequence number within that
+/// millisecond, which is also what keeps it from escaping the quarantine directory.
+fn parse_quarantine_id(id: &str) -> Option<(u64, u64)> {
+    parse_version_id(id)
+}
+
+/// Copy `snapshot` into quarantine and return its ID. Never overwrites an
+/// existing quarantined snapshot, even one quarantined in the same millisecond.
+async fn quarantine_snapshot(
+    store: &dyn Store,
+    doc_id: &str,
+    snapshot: Vec<u8>,
+    created_at: u64,
+) -> Result<String> {
+    let mut seq = 0;
+    loop {
+        let id = version_id(created_at, seq);
+        match store
+            .set_if(&quarantine_key(doc_id, &id), snapshot.clone(), None)
+            .await
+        {
+            Ok(_) => return Ok(id),
+            Err(StoreError::Conflict(_)) => seq += 1,
+            Err(e) => return Err(e).context("Failed to quarantine snapshot."),
+        }
+    }
+}
+
+/// Quarantine an unreadable snapshot and return the data to load the document
+/// from instead.
+pub(crate) async fn recover(
+    store: &dyn Store,
+    doc_id: &str,
+    snapshot: Vec<u8>,
+    error: anyhow::Error,
+) -> Result<SnapshotData> {
+    let quarantine_id = quarantine_snapshot(store, doc_id, snapshot, now_millis()).await?;
+
+    let (recovered_from, data) = match latest_readable_version(store, doc_id).await? {
+        Some((version_id, data)) => (Some(version_id), data),
+        None => (None, SnapshotData::new()),
+    };
+
+    tracing::error!(
+        event = "snapshot_quarantined",
+        doc_id,
+        quarantine_id,
+        recovered_from = ?recovered_from,
+        error = format!("{:#}", error),
+        "Quarantined unreadable snapshot and recovered the document."
+    );
+    Ok(data)
+}
+
+/// The most recent historical version of a document that can be decoded.
+async fn latest_readable_version(
+    store: &dyn Store,
+    doc_id: &str,
+) -> Result<Option<(String, SnapshotData)>> {
+    let versions = list_versions(store, doc_id).await?;
+    for version in versions.into_iter().rev() {
+        match load_version(store, doc_id, &version.id).await {
+            Ok(Some(data)) => return Ok(Some((version.id, data))),
+            Ok(None) => {}
+            Err(e) => tracing::warn!(?e, version = version.id, "Skipping unreadable version"),
+        }
+    }
+    Ok(None)
+}
+
+/// List the quarantined snapshots of a document, oldest first, with the reason
+/// each one cannot be decoded.
+pub async fn list_quarantined(store: &dyn Store, doc_id: &str) -> Result<Vec<QuarantinedSnapshot>> {
+    let prefix = quarantine_prefix(doc_id);
+    let mut snapshots = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = store
+            .list(&prefix, cursor.as_deref(), 1000)
+            .await
+            .context("Failed to list quarantined snapshots.")?;
+        for key in &page.keys {
+            let Some(id) = key.strip_prefix(&prefix) else {
+                continue;
+            };
+            let Some((created_at, _)) = parse_quarantine_id(id) else {
+                continue;
+            };
+            let Some(snapshot) = store
+                .get(key)
+                .await
+                .context("Failed to get quarantined snapshot.")?
+            else {
+                continue;
+            };
+            snapshots.push(QuarantinedSnapshot {
+                id: id.to_string(),
+                created_at,
+                size: snapshot.len(),
+                error: decode_snapshot(&snapshot).err().map(|e| format!("{:#}", e)),
+            });
+        }
+        match page.next_cursor {
+            Some(next_cursor) => cursor = Some(next_cursor),
+            None => break,
+        }
+    }
+    snapshots.sort_by_key(|snapshot| parse_quarantine_id(&snapshot.id));
+    Ok(snapshots)
+}
+
+/// Read a quarantined snapshot, or `None` if it does not exist.
+pub async fn load_quarantined(
+    store: &dyn Store,
+    doc_id: &str,
+    id: &str,
+) -> Result<Option<Vec<u8>>> {
+    if parse_quarantine_id(id).is_none() {
+        return Ok(None);
+    }
+    store
+        .get(&quarantine_key(doc_id, id))
+        .await
+        .context("Failed to get quarantined snapshot.")
+}
+
+/// Remove a quarantined snapshot. Returns `false` if it does not exist.
+pub async fn remove_quarantined(store: &dyn Store, doc_id: &str, id: &str) -> Result<bool> {
+    if parse_quarantine_id(id).is_none() {
+        return Ok(false);
+    }
+    let key = quarantine_key(doc_id, id);
+    if !store.exists(&key).await? {
+        return Ok(false);
+    }
+    store
+        .remove(&key)
+        .await
+        .context("Failed to remove quarantined snapshot.")?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        history::HistoryOptions,
+        store::memory::MemoryStore,
+        sync_kv::{PersistenceOptions, SyncKv},
+    };
+    use std::{sync::Arc, time::Duration};
+
+    const CORRUPTED: &[u8] = b"YSWEET\x02garbage";
+
+    #[tokio::test]
+    async fn recovers_from_history() {
+        let store = MemoryStore::new();
+        let shared: Arc<Box<dyn Store>> = Arc::new(Box::new(store.clone()));
+        let options = PersistenceOptions {
+            history: Some(HistoryOptions {
+                max_versions: 10,
+                max_age: None,
+                min_interval: Duration::ZERO,
+            }),
+            recovery: RecoveryPolicy::Quarantine,
+            ..Default::default()
+        };
+
+        let sync_kv = SyncKv::new_with_options(Some(shared.clone()), "doc", options.clone(), || ())
+            .await
+            .unwrap();
+        sync_kv.set(b"foo", b"bar");
+        sync_kv.persist().await.unwrap();
+
+        Store::set(&store, "doc/data.ysweet", CORRUPTED.to_vec())
+            .await
+            .unwrap();
+
+        let failing = PersistenceOptions {
+            recovery: RecoveryPolicy::Fail,
+            ..options.clone()
+        };
+        assert!(
+            SyncKv::new_with_options(Some(shared.clone()), "doc", failing, || ())
+                .await
+                .is_err()
+        );
+        assert!(list_quarantined(&store, "doc").await.unwrap().is_empty());
+
+        let sync_kv = SyncKv::new_with_options(Some(shared.clone()), "doc", options, || ())
+            .await
+            .unwrap();
+        assert_eq!(sync_kv.get(b"foo"), Some(b"bar".to_vec()));
+
+        let quarantined = list_quarantined(&store, "doc").await.unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].size, CORRUPTED.len());
+        assert!(quarantined[0].error.is_some());
+        assert_eq!(
+            load_quarantined(&store, "doc", &quarantined[0].id)
+                .await
+                .unwrap(),
+            Some(CORRUPTED.to_vec())
+        );
+
+        // The recovered document replaces the unreadable snapshot.
+        let snapshot = store.get("doc/data.ysweet").await.unwrap().unwrap();
+        assert!(decode_snapshot(&snapshot).is_ok());
+
+        assert!(remove_quarantined(&store, "doc", &quarantined[0].id)
+            .await
+            .unwrap());
+        assert!(!remove_quarantined(&store, "doc", "../data.ysweet")
+            .await
+            .unwrap());
+        assert!(list_quarantined(&store, "doc").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn quarantines_once() {
+        let store = MemoryStore::new();
+        let shared: Arc<Box<dyn Store>> = Arc::new(Box::new(store.clone()));
+        let options = PersistenceOptions {
+            recovery: RecoveryPolicy::Quarantine,
+            ..Default::default()
+        };
+        Store::set(&store, "doc/data.ysweet", CORRUPTED.to_vec())
+            .await
+            .unwrap();
+
+        // Load the document twice without editing it in between.
+        for _ in 0..2 {
+            SyncKv::new_with_options(Some(shared.clone()), "doc", options.clone(), || ())
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(list_quarantined(&store, "doc").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn quarantines_in_the_same_millisecond() {
+        let store = MemoryStore::new();
+        let first = quarantine_snapshot(&store, "doc", b"first".to_vec(), 1000)
+            .await
+            .unwrap();
+        let second = quarantine_snapshot(&store, "doc", b"second".to_vec(), 1000)
+            .await
+            .unwrap();
+        assert_ne!(first, second);
+
+        let quarantined = list_quarantined(&store, "doc").await.unwrap();
+        let ids: Vec<&str> = quarantined.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(ids, vec![first.as_str(), second.as_str()]);
+        assert!(quarantined.iter().all(|s| s.created_at == 1000));
+        assert_eq!(
+            load_quarantined(&store, "doc", &first).await.unwrap(),
+            Some(b"first".to_vec())
+        );
+        assert_eq!(
+            load_quarantined(&store, "doc", &second).await.unwrap(),
+            Some(b"second".to_vec())
+        );
+    }
+}
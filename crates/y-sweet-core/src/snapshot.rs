@@ -0,0 +1,320 @@
+//! Encoding of `.ysweet` snapshots.
+//!
+//! A legacy snapshot is a bare bincode-serialized map of yrs-kvstore entries.
+//! Every other snapshot starts with `MAGIC`, a format version, and an encoding
+//! byte that says how the payload is compressed. Read literally, a legacy
+//! snapshot's first eight bytes are the little-endian entry count, which can
+//! never be large enough to collide with `MAGIC`.
+//!
+//! Format 1 follows this with the payload. Format 2, which is written now, follows
+//! it with a length-prefixed bincode `SnapshotHeader`, the length-prefixed payload,
+//! and a SHA-256 checksum of everything before it. Readers ignore header fields
+//! they do not know, so fields can be added without a new format version.
+
+use crate::history::now_millis;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, str::FromStr};
+
+pub type SnapshotData = BTreeMap<Vec<u8>, Vec<u8>>;
+
+const MAGIC: &[u8; 6] = b"YSWEET";
+/// The header is followed directly by the payload, without a checksum.
+const FORMAT_V1: u8 = 1;
+const FORMAT_VERSION: u8 = 2;
+const CHECKSUM_LEN: usize = 32;
+
+/// Compression applied to snapshots when they are written. Snapshots are always
+/// read according to their own header, regardless of this setting.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SnapshotCompression {
+    /// Write an uncompressed snapshot.
+    #[default]
+    None,
+    /// Write an LZ4-compressed snapshot.
+    Lz4,
+}
+
+impl SnapshotCompression {
+    fn id(&self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Lz4 => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self> {
+        match id {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Lz4),
+            _ => bail!("Unknown snapshot compression {}", id),
+        }
+    }
+}
+
+impl FromStr for SnapshotCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "lz4" => Ok(Self::Lz4),
+            _ => Err(anyhow!(
+                "invalid snapshot compression (expected \"none\" or \"lz4\")"
+            )),
+        }
+    }
+}
+
+/// Metadata stored with a snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotHeader {
+    /// The version of y-sweet that wrote the snapshot.
+    pub ysweet_version: String,
+    /// When the snapshot was written, in milliseconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+impl SnapshotHeader {
+    fn now() -> Self {
+        Self {
+            ysweet_version: env!("CARGO_PKG_VERSION").to_string(),
+            created_at: now_millis(),
+        }
+    }
+}
+
+/// A snapshot split into its parts, with its checksum verified.
+struct Container<'a> {
+    compression: SnapshotCompression,
+    header: Option<SnapshotHeader>,
+    payload: &'a [u8],
+}
+
+/// Reads the fields of a snapshot in order, reporting where it is truncated.
+struct Reader<'a> {
+    snapshot: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize, field: &str) -> Result<&'a [u8]> {
+        let remaining = self.snapshot.len() - self.pos;
+        ensure!(
+            len <= remaining,
+            "Snapshot is truncated: expected {} bytes of {} at offset {}, but only {} remain.",
+            len,
+            field,
+            self.pos,
+            remaining
+        );
+        let bytes = &self.snapshot[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn take_u8(&mut self, field: &str) -> Result<u8> {
+        Ok(self.take(1, field)?[0])
+    }
+
+    fn take_len(&mut self, field: &str) -> Result<usize> {
+        let bytes = self.take(8, field)?;
+        let len = u64::from_le_bytes(bytes.try_into().unwrap());
+        usize::try_from(len).map_err(|_| anyhow!("Snapshot {} of {} is too large.", field, len))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.snapshot[self.pos..];
+        self.pos = self.snapshot.len();
+        rest
+    }
+}
+
+/// Parse the container around a snapshot's payload. Returns `None` for a legacy
+/// snapshot, which has no container.
+fn parse_container(snapshot: &[u8]) -> Result<Option<Container<'_>>> {
+    if !snapshot.starts_with(MAGIC) {
+        return Ok(None);
+    }
+
+    let mut reader = Reader { snapshot, pos: 0 };
+    reader.take(MAGIC.len(), "magic bytes")?;
+    let version = reader.take_u8("format version")?;
+    if version > FORMAT_VERSION {
+        bail!(
+            "Snapshot format version {} is newer than the latest version this y-sweet can read ({}).",
+            version,
+            FORMAT_VERSION
+        );
+    }
+    let compression = SnapshotCompression::from_id(reader.take_u8("encoding")?)?;
+
+    if version == FORMAT_V1 {
+        return Ok(Some(Container {
+            compression,
+            header: None,
+            payload: reader.rest(),
+        }));
+    }
+    if version != FORMAT_VERSION {
+        bail!("Unsupported snapshot format version {}", version);
+    }
+
+    let header_len = reader.take_len("header length")?;
+    let header = reader.take(header_len, "header")?;
+    let payload_len = reader.take_len("payload length")?;
+    let payload = reader.take(payload_len, "payload")?;
+    let checked_len = reader.pos;
+    let checksum = reader.take(CHECKSUM_LEN, "checksum")?;
+    ensure!(
+        reader.rest().is_empty(),
+        "Snapshot has {} unexpected bytes after its checksum.",
+        snapshot.len() - checked_len - CHECKSUM_LEN
+    );
+    ensure!(
+        Sha256::digest(&snapshot[..checked_len]).as_slice() == checksum,
+        "Snapshot checksum does not match its contents; the snapshot is corrupted."
+    );
+
+    let header = bincode::deserialize(header).context("Failed to deserialize snapshot header.")?;
+    Ok(Some(Container {
+        compression,
+        header: Some(header),
+        payload,
+    }))
+}
+
+pub fn encode_snapshot(data: &SnapshotData, compression: SnapshotCompression) -> Result<Vec<u8>> {
+    encode_snapshot_with_header(data, compression, &SnapshotHeader::now())
+}
+
+fn encode_snapshot_with_header(
+    data: &SnapshotData,
+    compression: SnapshotCompression,
+    header: &SnapshotHeader,
+) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(data).context("Failed to serialize.")?;
+    let payload = match compression {
+        SnapshotCompression::None => payload,
+        SnapshotCompression::Lz4 => lz4_flex::compress_prepend_size(&payload),
+    };
+    let header = bincode::serialize(header).context("Failed to serialize snapshot header.")?;
+
+    // The magic bytes, version and encoding, two lengths, and the sections.
+    let mut snapshot =
+        Vec::with_capacity(MAGIC.len() + 2 + 16 + header.len() + payload.len() + CHECKSUM_LEN);
+    snapshot.extend_from_slice(MAGIC);
+    snapshot.push(FORMAT_VERSION);
+    snapshot.push(compression.id());
+    snapshot.extend_from_slice(&(header.len() as u64).to_le_bytes());
+    snapshot.extend_from_slice(&header);
+    snapshot.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    snapshot.extend_from_slice(&payload);
+    let checksum = Sha256::digest(&snapshot);
+    snapshot.extend_from_slice(&checksum);
+    Ok(snapshot)
+}
+
+/// Read the header of a snapshot, verifying its checksum. Returns `None` for
+/// snapshots written before headers were added.
+pub fn decode_snapshot_header(snapshot: &[u8]) -> Result<Option<SnapshotHeader>> {
+    Ok(parse_container(snapshot)?.and_then(|container| container.header))
+}
+
+pub fn decode_snapshot(snapshot: &[u8]) -> Result<SnapshotData> {
+    let Some(container) = parse_container(snapshot)? else {
+        return bincode::deserialize(snapshot)
+            .context("Failed to deserialize legacy snapshot; it may be truncated or corrupted.");
+    };
+
+    let payload = match container.compression {
+        SnapshotCompression::None => container.payload.to_vec(),
+        SnapshotCompression::Lz4 => lz4_flex::decompress_size_prepended(container.payload)
+            .context("Failed to decompress snapshot.")?,
+    };
+
+    bincode::deserialize(&payload).context("Failed to deserialize snapshot payload.")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample_data() -> SnapshotData {
+        let mut data = SnapshotData::new();
+        data.insert(b"foo".to_vec(), b"bar".repeat(100));
+        data.insert(b"baz".to_vec(), vec![0; 1000]);
+        data
+    }
+
+    fn sample_header() -> SnapshotHeader {
+        SnapshotHeader {
+            ysweet_version: "1.2.3".to_string(),
+            created_at: 1_700_000_000_000,
+        }
+    }
+
+    #[test]
+    fn round_trips_lz4() {
+        let data = sample_data();
+        let snapshot = encode_snapshot(&data, SnapshotCompression::Lz4).unwrap();
+        assert!(snapshot.starts_with(MAGIC));
+        assert!(snapshot.len() < bincode::serialize(&data).unwrap().len());
+        assert_eq!(decode_snapshot(&snapshot).unwrap(), data);
+    }
+
+    #[test]
+    fn stores_header() {
+        let data = sample_data();
+        let snapshot =
+            encode_snapshot_with_header(&data, SnapshotCompression::None, &sample_header())
+                .unwrap();
+        assert_eq!(snapshot[MAGIC.len()], FORMAT_VERSION);
+        assert_eq!(
+            decode_snapshot_header(&snapshot).unwrap(),
+            Some(sample_header())
+        );
+        assert_eq!(decode_snapshot(&snapshot).unwrap(), data);
+
+        let snapshot = encode_snapshot(&data, SnapshotCompression::None).unwrap();
+        let header = decode_snapshot_header(&snapshot).unwrap().unwrap();
+        assert_eq!(header.ysweet_version, env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn reads_legacy_snapshots() {
+        let data = sample_data();
+        let legacy = bincode::serialize(&data).unwrap();
+        assert_eq!(decode_snapshot(&legacy).unwrap(), data);
+        assert_eq!(decode_snapshot_header(&legacy).unwrap(), None);
+
+        let empty = bincode::serialize(&SnapshotData::new()).unwrap();
+        assert_eq!(decode_snapshot(&empty).unwrap(), SnapshotData::new());
+
+        // Format 1: LZ4-compressed, without a header or checksum.
+        let mut v1 = MAGIC.to_vec();
+        v1.push(FORMAT_V1);
+        v1.push(SnapshotCompression::Lz4.id());
+        v1.extend_from_slice(&lz4_flex::compress_prepend_size(&legacy));
+        assert_eq!(decode_snapshot(&v1).unwrap(), data);
+    }
+
+    #[test]
+    fn rejects_damaged_snapshots() {
+        let snapshot = encode_snapshot(&sample_data(), SnapshotCompression::Lz4).unwrap();
+
+        let err = decode_snapshot(&snapshot[..snapshot.len() - 40]).unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{}", err);
+
+        let mut corrupted = snapshot.clone();
+        corrupted[snapshot.len() / 2] ^= 1;
+        let err = decode_snapshot(&corrupted).unwrap_err();
+        assert!(err.to_string().contains("checksum"), "{}", err);
+
+        let mut newer = snapshot.clone();
+        newer[MAGIC.len()] = FORMAT_VERSION + 1;
+        let err = decode_snapshot(&newer).unwrap_err();
+        assert!(err.to_string().contains("newer"), "{}", err);
+    }
+}
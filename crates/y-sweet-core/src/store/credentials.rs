@@ -0,0 +1,512 @@
+use super::{Result, StoreError};
+use reqwest::{Client, Method, RequestBuilder, Url};
+use serde::Deserialize;
+use std::{
+    env,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+const DEFAULT_PROFILE: &str = "default";
+const DEFAULT_STS_ENDPOINT: &str = "https://sts.amazonaws.com";
+const DEFAULT_INSTANCE_METADATA_ENDPOINT: &str = "http://169.254.169.254";
+
+/// How long an instance metadata session token is valid for.
+const INSTANCE_METADATA_TOKEN_TTL_SECONDS: &str = "21600";
+
+/// The instance metadata service is only reachable from EC2, so give up on it
+/// quickly elsewhere rather than stalling the rest of the chain.
+#[cfg_attr(target_arch = "wasm32", allow(unused))]
+const INSTANCE_METADATA_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A set of AWS credentials, which may be temporary.
+#[derive(Clone, Debug)]
+pub struct AwsCredentials {
+    pub key: String,
+    pub secret: String,
+    pub token: Option<String>,
+    /// When temporary credentials stop working. `None` for long-lived credentials.
+    pub expiration: Option<OffsetDateTime>,
+}
+
+/// Where `S3Store` gets its credentials from.
+///
+/// Credentials are loaded when the store is first used, and temporary credentials
+/// are loaded again shortly before they expire.
+#[derive(Clone, Debug)]
+pub enum CredentialsProvider {
+    /// Fixed credentials, e.g. from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`.
+    Static(AwsCredentials),
+    /// A profile in a shared credentials file, like `~/.aws/credentials`.
+    Profile { path: PathBuf, profile: String },
+    /// Temporary credentials for a role, from exchanging a web identity token read
+    /// from a file with STS. This is how EKS grants service accounts access to AWS.
+    WebIdentity {
+        token_file: PathBuf,
+        role_arn: String,
+        session_name: String,
+        sts_endpoint: String,
+    },
+    /// Temporary credentials for the instance's role, from the EC2 instance
+    /// metadata service (IMDSv2).
+    InstanceMetadata { endpoint: String },
+    /// Each provider in turn, until one of them returns credentials.
+    Chain(Vec<CredentialsProvider>),
+}
+
+impl CredentialsProvider {
+    /// The standard AWS credential chain, configured from the environment:
+    ///
+    /// 1. `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`.
+    /// 2. The `AWS_PROFILE` (or `default`) profile of `AWS_SHARED_CREDENTIALS_FILE`
+    ///    (or `~/.aws/credentials`).
+    /// 3. `AWS_WEB_IDENTITY_TOKEN_FILE` and `AWS_ROLE_ARN`.
+    /// 4. The instance metadata service, unless `AWS_EC2_METADATA_DISABLED=true`.
+    pub fn default_chain() -> Self {
+        let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+        let mut chain = Vec::new();
+
+        if let (Some(key), Some(secret)) = (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY"))
+        {
+            chain.push(Self::Static(AwsCredentials {
+                key,
+                secret,
+                token: var("AWS_SESSION_TOKEN"),
+                expiration: None,
+            }));
+        }
+
+        let credentials_file = var("AWS_SHARED_CREDENTIALS_FILE")
+            .map(PathBuf::from)
+            .or_else(|| var("HOME").map(|home| PathBuf::from(home).join(".aws/credentials")));
+        if let Some(path) = credentials_file {
+            chain.push(Self::Profile {
+                path,
+                profile: var("AWS_PROFILE").unwrap_or_else(|| DEFAULT_PROFILE.to_string()),
+            });
+        }
+
+        if let (Some(token_file), Some(role_arn)) =
+            (var("AWS_WEB_IDENTITY_TOKEN_FILE"), var("AWS_ROLE_ARN"))
+        {
+            let sts_endpoint = var("AWS_ENDPOINT_URL_STS").unwrap_or_else(|| {
+                var("AWS_REGION").map_or_else(
+                    || DEFAULT_STS_ENDPOINT.to_string(),
+                    |region| format!("https://sts.{}.amazonaws.com", region),
+                )
+            });
+            chain.push(Self::WebIdentity {
+                token_file: token_file.into(),
+                role_arn,
+                session_name: var("AWS_ROLE_SESSION_NAME").unwrap_or_else(|| "y-sweet".to_string()),
+                sts_endpoint,
+            });
+        }
+
+        if var("AWS_EC2_METADATA_DISABLED").is_none_or(|disabled| disabled != "true") {
+            chain.push(Self::InstanceMetadata {
+                endpoint: var("AWS_EC2_METADATA_SERVICE_ENDPOINT")
+                    .unwrap_or_else(|| DEFAULT_INSTANCE_METADATA_ENDPOINT.to_string()),
+            });
+        }
+
+        Self::Chain(chain)
+    }
+
+    pub async fn load(&self, client: &Client) -> Result<AwsCredentials> {
+        match self {
+            Self::Static(credentials) => Ok(credentials.clone()),
+            Self::Profile { path, profile } => load_profile(path, profile).await,
+            Self::WebIdentity {
+                token_file,
+                role_arn,
+                session_name,
+                sts_endpoint,
+            } => load_web_identity(client, token_file, role_arn, session_name, sts_endpoint).await,
+            Self::InstanceMetadata { endpoint } => load_instance_metadata(client, endpoint).await,
+            Self::Chain(providers) => {
+                let mut errors = Vec::new();
+                for provider in providers {
+                    match Box::pin(provider.load(client)).await {
+                        Ok(credentials) => return Ok(credentials),
+                        Err(e) => errors.push(e.to_string()),
+                    }
+                }
+                Err(StoreError::NotAuthorized(format!(
+                    "No AWS credentials found. {}",
+                    errors.join(" ")
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn read_to_string(path: &Path) -> std::io::Result<String> {
+    tokio::fs::read_to_string(path).await
+}
+
+// Workers have no file system, so this fails as a missing file would and the
+// chain moves on to the next provider.
+#[cfg(target_arch = "wasm32")]
+async fn read_to_string(path: &Path) -> std::io::Result<String> {
+    std::fs::read_to_string(path)
+}
+
+async fn load_profile(path: &Path, profile: &str) -> Result<AwsCredentials> {
+    let contents = read_to_string(path).await.map_err(|e| {
+        StoreError::NotAuthorized(format!("Could not read {}: {}.", path.display(), e))
+    })?;
+
+    let mut in_profile = false;
+    let (mut key, mut secret, mut token) = (None, None, None);
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            in_profile = section.trim() == profile;
+            continue;
+        }
+        let Some((name, value)) = line.split_once('=').filter(|_| in_profile) else {
+            continue;
+        };
+        let value = Some(value.trim().to_string());
+        match name.trim() {
+            "aws_access_key_id" => key = value,
+            "aws_secret_access_key" => secret = value,
+            "aws_session_token" => token = value,
+            _ => {}
+        }
+    }
+
+    match (key, secret) {
+        (Some(key), Some(secret)) => Ok(AwsCredentials {
+            key,
+            secret,
+            token,
+            expiration: None,
+        }),
+        _ => Err(StoreError::NotAuthorized(format!(
+            "Profile {:?} in {} has no credentials.",
+            profile,
+            path.display()
+        ))),
+    }
+}
+
+fn parse_expiration(expiration: &str) -> Result<OffsetDateTime> {
+    OffsetDateTime::parse(expiration, &Rfc3339)
+        .map_err(|e| StoreError::NotAuthorized(format!("Invalid credentials expiration. {e}")))
+}
+
+async fn send(request: RequestBuilder) -> Result<String> {
+    let response = request
+        .send()
+        .await
+        .map_err(|e| StoreError::ConnectionError(format!("Error loading credentials. {e}")))?;
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .map_err(|e| StoreError::ConnectionError(format!("Error loading credentials. {e}")))?;
+    if !status.is_success() {
+        return Err(StoreError::NotAuthorized(format!(
+            "Received {} while loading credentials. {}",
+            status, body
+        )));
+    }
+    Ok(body)
+}
+
+/// Extract the text of the first `<name>` element of an XML document.
+fn xml_field<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+    let (_, rest) = body.split_once(&format!("<{}>", name))?;
+    let (value, _) = rest.split_once(&format!("</{}>", name))?;
+    Some(value)
+}
+
+async fn load_web_identity(
+    client: &Client,
+    token_file: &Path,
+    role_arn: &str,
+    session_name: &str,
+    sts_endpoint: &str,
+) -> Result<AwsCredentials> {
+    // The token file is rotated by the platform, so it is read on every load.
+    let token = read_to_string(token_file).await.map_err(|e| {
+        StoreError::NotAuthorized(format!("Could not read {}: {}.", token_file.display(), e))
+    })?;
+
+    let mut url: Url = sts_endpoint
+        .parse()
+        .map_err(|e| StoreError::NotAuthorized(format!("Invalid STS endpoint. {e}")))?;
+    url.query_pairs_mut()
+        .append_pair("Action", "AssumeRoleWithWebIdentity")
+        .append_pair("Version", "2011-06-15")
+        .append_pair("RoleArn", role_arn)
+        .append_pair("RoleSessionName", session_name)
+        .append_pair("WebIdentityToken", token.trim());
+    let body = send(client.get(url)).await?;
+
+    let field = |name| {
+        xml_field(&body, name).ok_or_else(|| {
+            StoreError::NotAuthorized(format!(
+                "Expected {} in AssumeRoleWithWebIdentity response.",
+                name
+            ))
+        })
+    };
+    Ok(AwsCredentials {
+        key: field("AccessKeyId")?.to_string(),
+        secret: field("SecretAccessKey")?.to_string(),
+        token: Some(field("SessionToken")?.to_string()),
+        expiration: Some(parse_expiration(field("Expiration")?)?),
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InstanceMetadataCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: String,
+    expiration: String,
+}
+
+async fn load_instance_metadata(client: &Client, endpoint: &str) -> Result<AwsCredentials> {
+    let endpoint = endpoint.trim_end_matches('/');
+    let request = |method, path: &str| {
+        let request = client.request(method, format!("{}{}", endpoint, path));
+        #[cfg(not(target_arch = "wasm32"))]
+        let request = request.timeout(INSTANCE_METADATA_TIMEOUT);
+        request
+    };
+
+    let token = send(request(Method::PUT, "/latest/api/token").header(
+        "x-aws-ec2-metadata-token-ttl-seconds",
+        INSTANCE_METADATA_TOKEN_TTL_SECONDS,
+    ))
+    .await?;
+    let roles = send(
+        request(Method::GET, "/latest/meta-data/iam/security-credentials/")
+            .header("x-aws-ec2-metadata-token", &token),
+    )
+    .await?;
+    let role = roles
+        .lines()
+        .next()
+        .filter(|role| !role.is_empty())
+        .ok_or_else(|| StoreError::NotAuthorized("The instance has no IAM role.".to_string()))?;
+    let body = send(
+        request(
+            Method::GET,
+            &format!("/latest/meta-data/iam/security-credentials/{}", role),
+        )
+        .header("x-aws-ec2-metadata-token", &token),
+    )
+    .await?;
+
+    let credentials: InstanceMetadataCredentials = serde_json::from_str(&body).map_err(|e| {
+        StoreError::NotAuthorized(format!("Invalid instance metadata credentials. {e}"))
+    })?;
+    Ok(AwsCredentials {
+        key: credentials.access_key_id,
+        secret: credentials.secret_access_key,
+        token: Some(credentials.token),
+        expiration: Some(parse_expiration(&credentials.expiration)?),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use axum::{
+        extract::{Path, Query, State},
+        http::{HeaderMap, StatusCode},
+        response::{IntoResponse, Response},
+        routing::{get, put},
+        Router,
+    };
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    /// A fake instance metadata service and STS.
+    pub(crate) struct FakeMetadata {
+        /// How many times credentials were loaded from the instance metadata service.
+        pub loads: usize,
+        /// When the credentials it hands out expire.
+        pub expiration: OffsetDateTime,
+    }
+
+    async fn imds_token(headers: HeaderMap) -> Response {
+        if headers
+            .get("x-aws-ec2-metadata-token-ttl-seconds")
+            .is_none()
+        {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+        "session-token".into_response()
+    }
+
+    async fn imds_credentials(
+        State(metadata): State<Arc<Mutex<FakeMetadata>>>,
+        Path(role): Path<String>,
+        headers: HeaderMap,
+    ) -> Response {
+        if headers
+            .get("x-aws-ec2-metadata-token")
+            .map(|t| t.as_bytes())
+            != Some(b"session-token")
+        {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        let mut metadata = metadata.lock().unwrap();
+        metadata.loads += 1;
+        serde_json::json!({
+            "Code": "Success",
+            "AccessKeyId": format!("{}-key-{}", role, metadata.loads),
+            "SecretAccessKey": "secret",
+            "Token": "token",
+            "Expiration": metadata.expiration.format(&Rfc3339).unwrap(),
+        })
+        .to_string()
+        .into_response()
+    }
+
+    async fn sts(
+        State(metadata): State<Arc<Mutex<FakeMetadata>>>,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> Response {
+        if query.get("WebIdentityToken").map(String::as_str) != Some("web-identity-token") {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+        let expiration = metadata.lock().unwrap().expiration;
+        format!(
+            "<AssumeRoleWithWebIdentityResponse><AssumeRoleWithWebIdentityResult><Credentials>\
+             <AccessKeyId>{}-key</AccessKeyId><SecretAccessKey>secret</SecretAccessKey>\
+             <SessionToken>token</SessionToken><Expiration>{}</Expiration>\
+             </Credentials></AssumeRoleWithWebIdentityResult></AssumeRoleWithWebIdentityResponse>",
+            query["RoleSessionName"],
+            expiration.format(&Rfc3339).unwrap()
+        )
+        .into_response()
+    }
+
+    /// Serve a fake instance metadata service at the returned endpoint, and STS at
+    /// its `/sts` path. Credentials are valid for an hour unless changed.
+    pub(crate) async fn fake_metadata_server() -> (String, Arc<Mutex<FakeMetadata>>) {
+        let metadata = Arc::new(Mutex::new(FakeMetadata {
+            loads: 0,
+            expiration: OffsetDateTime::now_utc() + Duration::from_secs(60 * 60),
+        }));
+        let app = Router::new()
+            .route("/latest/api/token", put(imds_token))
+            .route(
+                "/latest/meta-data/iam/security-credentials/",
+                get(|| async { "role\n" }),
+            )
+            .route(
+                "/latest/meta-data/iam/security-credentials/:role",
+                get(imds_credentials),
+            )
+            .route("/sts", get(sts))
+            .with_state(metadata.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{}", addr), metadata)
+    }
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("y-sweet-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn reads_profiles() {
+        let path = temp_file(
+            "credentials",
+            "[default]\naws_access_key_id = default-key\naws_secret_access_key = secret\n\n\
+             # A comment.\n[other]\naws_access_key_id=other-key\naws_secret_access_key=secret\n\
+             aws_session_token=token\n",
+        );
+        let client = Client::new();
+
+        let provider = CredentialsProvider::Profile {
+            path: path.clone(),
+            profile: "other".to_string(),
+        };
+        let credentials = provider.load(&client).await.unwrap();
+        assert_eq!(credentials.key, "other-key");
+        assert_eq!(credentials.token.as_deref(), Some("token"));
+
+        let provider = CredentialsProvider::Profile {
+            path: path.clone(),
+            profile: "missing".to_string(),
+        };
+        assert!(matches!(
+            provider.load(&client).await,
+            Err(StoreError::NotAuthorized(_))
+        ));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn loads_temporary_credentials() {
+        let (endpoint, metadata) = fake_metadata_server().await;
+        let expiration = metadata.lock().unwrap().expiration;
+        let client = Client::new();
+
+        let credentials = CredentialsProvider::InstanceMetadata {
+            endpoint: endpoint.clone(),
+        }
+        .load(&client)
+        .await
+        .unwrap();
+        assert_eq!(credentials.key, "role-key-1");
+        assert_eq!(
+            credentials.expiration.map(OffsetDateTime::unix_timestamp),
+            Some(expiration.unix_timestamp())
+        );
+
+        let token_file = temp_file("web-identity-token", "web-identity-token\n");
+        let credentials = CredentialsProvider::WebIdentity {
+            token_file: token_file.clone(),
+            role_arn: "arn:aws:iam::123456789012:role/y-sweet".to_string(),
+            session_name: "session".to_string(),
+            sts_endpoint: format!("{}/sts", endpoint),
+        }
+        .load(&client)
+        .await
+        .unwrap();
+        assert_eq!(credentials.key, "session-key");
+        assert_eq!(credentials.token.as_deref(), Some("token"));
+        std::fs::remove_file(token_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn chain_uses_first_available_provider() {
+        let (endpoint, _) = fake_metadata_server().await;
+        let provider = CredentialsProvider::Chain(vec![
+            CredentialsProvider::Profile {
+                path: env::temp_dir().join("y-sweet-missing-credentials"),
+                profile: DEFAULT_PROFILE.to_string(),
+            },
+            CredentialsProvider::InstanceMetadata { endpoint },
+        ]);
+        let credentials = provider.load(&Client::new()).await.unwrap();
+        assert_eq!(credentials.key, "role-key-1");
+
+        let provider = CredentialsProvider::Chain(vec![]);
+        assert!(matches!(
+            provider.load(&Client::new()).await,
+            Err(StoreError::NotAuthorized(_))
+        ));
+    }
+}
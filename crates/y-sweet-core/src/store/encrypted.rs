@@ -0,0 +1,515 @@
+//! A `Store` wrapper that encrypts objects at rest with AES-256-GCM.
+//!
+//! Each object is encrypted with a fresh random data key, and the data key is
+//! itself encrypted ("wrapped") with a key from a [`KeyRing`]. The ID of the
+//! wrapping key is stored alongside the object, so keys can be rotated: new
+//! writes use the active key, and objects written under older keys still
+//! decrypt as long as those keys remain in the ring.
+//!
+//! Encrypted object layout:
+//!
+//! ```text
+//! MAGIC | version (2) | key ID length (1) | key ID | wrapped key nonce (12)
+//!       | wrapped data key (48) | data nonce (12) | ciphertext
+//! ```
+//!
+//! The data key is wrapped with the format version and key ID as associated data,
+//! so that the header cannot be altered without failing to unwrap it. Version 1
+//! objects, whose data key is not bound to the header, are still readable.
+//!
+//! Objects without `MAGIC` are rejected, since anyone who can write to the
+//! underlying store could otherwise plant unauthenticated content. To migrate a
+//! store that holds unencrypted objects, enable `with_unencrypted_reads` until
+//! every object has been rewritten.
+
+use super::{ListResult, ObjectVersion, Result, Store, StoreError};
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use async_trait::async_trait;
+use std::{collections::HashMap, str::FromStr};
+
+const MAGIC: &[u8; 5] = b"YSENC";
+/// The data key is wrapped without associated data.
+const FORMAT_V1: u8 = 1;
+const FORMAT_VERSION: u8 = 2;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const WRAPPED_KEY_LEN: usize = KEY_LEN + 16;
+
+/// A set of named 256-bit keys, one of which is used to encrypt new objects.
+#[derive(Clone)]
+pub struct KeyRing {
+    active_key_id: String,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+impl KeyRing {
+    /// Create a key ring from `(key ID, key)` pairs. The first key is the active key.
+    pub fn new(keys: Vec<(String, [u8; KEY_LEN])>) -> anyhow::Result<Self> {
+        let active_key_id = keys
+            .first()
+            .map(|(id, _)| id.clone())
+            .ok_or_else(|| anyhow::anyhow!("At least one encryption key is required."))?;
+
+        let mut ring = HashMap::new();
+        for (id, key) in keys {
+            if id.is_empty() || id.len() > u8::MAX as usize {
+                anyhow::bail!("Encryption key IDs must be between 1 and 255 bytes.");
+            }
+            let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+            if ring.insert(id.clone(), cipher).is_some() {
+                anyhow::bail!("Duplicate encryption key ID {}.", id);
+            }
+        }
+
+        Ok(Self {
+            active_key_id,
+            keys: ring,
+        })
+    }
+}
+
+impl FromStr for KeyRing {
+    type Err = anyhow::Error;
+
+    /// Parse a comma-separated list of `key_id:base64_key` entries, e.g.
+    /// `2024-06:<key>,2023-01:<key>`. The first entry is the active key.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut keys = Vec::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (id, key) = entry
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("Expected key_id:base64_key, got {}.", entry))?;
+            let key = data_encoding::BASE64
+                .decode(key.as_bytes())
+                .map_err(|e| anyhow::anyhow!("Invalid base64 for key {}: {}", id, e))?;
+            let key: [u8; KEY_LEN] = key
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Key {} must be {} bytes.", id, KEY_LEN))?;
+            keys.push((id.to_string(), key));
+        }
+        Self::new(keys)
+    }
+}
+
+pub struct EncryptedStore {
+    inner: Box<dyn Store>,
+    key_ring: KeyRing,
+    allow_unencrypted_reads: bool,
+}
+
+/// The associated data that binds a wrapped data key to its object's header.
+fn wrap_aad(version: u8, key_id: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(1 + key_id.len());
+    aad.push(version);
+    aad.extend_from_slice(key_id);
+    aad
+}
+
+impl EncryptedStore {
+    pub fn new(inner: Box<dyn Store>, key_ring: KeyRing) -> Self {
+        Self {
+            inner,
+            key_ring,
+            allow_unencrypted_reads: false,
+        }
+    }
+
+    /// Return objects that are not encrypted as they are, instead of failing.
+    /// Only meant for migrating a store to encryption: unencrypted objects are
+    /// not authenticated.
+    pub fn with_unencrypted_reads(mut self, allow: bool) -> Self {
+        self.allow_unencrypted_reads = allow;
+        self
+    }
+
+    /// Encrypt `value`, binding the ciphertext to `key` so that objects cannot be
+    /// swapped between keys undetected.
+    fn encrypt(&self, key: &str, value: &[u8]) -> Result<Vec<u8>> {
+        let key_id = &self.key_ring.active_key_id;
+        let wrapping_cipher = &self.key_ring.keys[key_id];
+
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let data_cipher = Aes256Gcm::new(&data_key);
+
+        let wrap_nonce = Aes256Gcm::generate_nonce(OsRng);
+        let wrapped_key = wrapping_cipher
+            .encrypt(
+                &wrap_nonce,
+                Payload {
+                    msg: data_key.as_slice(),
+                    aad: &wrap_aad(FORMAT_VERSION, key_id.as_bytes()),
+                },
+            )
+            .map_err(|_| StoreError::EncryptionError("Failed to wrap data key.".to_string()))?;
+
+        let data_nonce = Aes256Gcm::generate_nonce(OsRng);
+        let ciphertext = data_cipher
+            .encrypt(
+                &data_nonce,
+                Payload {
+                    msg: value,
+                    aad: key.as_bytes(),
+                },
+            )
+            .map_err(|_| StoreError::EncryptionError("Failed to encrypt object.".to_string()))?;
+
+        let mut result = Vec::with_capacity(
+            MAGIC.len() + 2 + key_id.len() + 2 * NONCE_LEN + WRAPPED_KEY_LEN + ciphertext.len(),
+        );
+        result.extend_from_slice(MAGIC);
+        result.push(FORMAT_VERSION);
+        result.push(key_id.len() as u8);
+        result.extend_from_slice(key_id.as_bytes());
+        result.extend_from_slice(&wrap_nonce);
+        result.extend_from_slice(&wrapped_key);
+        result.extend_from_slice(&data_nonce);
+        result.extend_from_slice(&ciphertext);
+        Ok(result)
+    }
+
+    fn decrypt(&self, key: &str, value: Vec<u8>) -> Result<Vec<u8>> {
+        let Some(rest) = value.strip_prefix(MAGIC.as_slice()) else {
+            if self.allow_unencrypted_reads {
+                tracing::warn!(
+                    key,
+                    "Read unencrypted object; it will be encrypted when next written."
+                );
+                return Ok(value);
+            }
+            return Err(StoreError::EncryptionError(format!(
+                "Object {} is not encrypted.",
+                key
+            )));
+        };
+
+        let invalid =
+            || StoreError::EncryptionError(format!("Malformed encrypted object {}.", key));
+        let (&version, rest) = rest.split_first().ok_or_else(invalid)?;
+        if version != FORMAT_V1 && version != FORMAT_VERSION {
+            return Err(StoreError::EncryptionError(format!(
+                "Unsupported encryption format version {} for {}.",
+                version, key
+            )));
+        }
+
+        let (&key_id_len, rest) = rest.split_first().ok_or_else(invalid)?;
+        let key_id_len = key_id_len as usize;
+        if rest.len() < key_id_len + 2 * NONCE_LEN + WRAPPED_KEY_LEN {
+            return Err(invalid());
+        }
+        let (key_id, rest) = rest.split_at(key_id_len);
+        let (wrap_nonce, rest) = rest.split_at(NONCE_LEN);
+        let (wrapped_key, rest) = rest.split_at(WRAPPED_KEY_LEN);
+        let (data_nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        let aad = match version {
+            FORMAT_V1 => Vec::new(),
+            _ => wrap_aad(version, key_id),
+        };
+        let key_id = String::from_utf8_lossy(key_id);
+        let wrapping_cipher = self.key_ring.keys.get(key_id.as_ref()).ok_or_else(|| {
+            StoreError::EncryptionError(format!(
+                "Object {} is encrypted with unknown key {}.",
+                key, key_id
+            ))
+        })?;
+
+        let data_key = wrapping_cipher
+            .decrypt(
+                Nonce::from_slice(wrap_nonce),
+                Payload {
+                    msg: wrapped_key,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| {
+                StoreError::EncryptionError(format!("Failed to unwrap data key for {}.", key))
+            })?;
+        let data_cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key));
+
+        data_cipher
+            .decrypt(
+                Nonce::from_slice(data_nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: key.as_bytes(),
+                },
+            )
+            .map_err(|_| StoreError::EncryptionError(format!("Failed to decrypt {}.", key)))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.inner.get(key).await? {
+            Some(value) => Ok(Some(self.decrypt(key, value)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        let value = self.encrypt(key, &value)?;
+        self.inner.set(key, value).await
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        match self.inner.get_versioned(key).await? {
+            Some((value, version)) => Ok(Some((self.decrypt(key, value)?, version))),
+            None => Ok(None),
+        }
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        let value = self.encrypt(key, &value)?;
+        self.inner.set_if(key, value, expected).await
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl Store for EncryptedStore {
+    async fn init(&self) -> Result<()> {
+        self.inner.init().await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.get(key).await
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.set(key, value).await
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        self.inner.remove(key).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        self.inner.exists(key).await
+    }
+
+    async fn list(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<ListResult> {
+        self.inner.list(prefix, cursor, limit).await
+    }
+
+    async fn list_docs(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<ListResult> {
+        self.inner.list_docs(prefix, cursor, limit).await
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        self.get_versioned(key).await
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<ObjectVersion>> {
+        self.inner.get_version(key).await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        self.set_if(key, value, expected).await
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[async_trait(?Send)]
+impl Store for EncryptedStore {
+    async fn init(&self) -> Result<()> {
+        self.inner.init().await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.get(key).await
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.set(key, value).await
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        self.inner.remove(key).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        self.inner.exists(key).await
+    }
+
+    async fn list(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<ListResult> {
+        self.inner.list(prefix, cursor, limit).await
+    }
+
+    async fn list_docs(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<ListResult> {
+        self.inner.list_docs(prefix, cursor, limit).await
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        self.get_versioned(key).await
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<ObjectVersion>> {
+        self.inner.get_version(key).await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        self.set_if(key, value, expected).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+
+    fn key_ring(keys: &[(&str, u8)]) -> KeyRing {
+        KeyRing::new(
+            keys.iter()
+                .map(|(id, byte)| (id.to_string(), [*byte; KEY_LEN]))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn encrypts_and_rotates_keys() {
+        let inner = MemoryStore::default();
+        let store = EncryptedStore::new(Box::new(inner.clone()), key_ring(&[("old", 1)]));
+        store.set("a/data.ysweet", b"hello".to_vec()).await.unwrap();
+
+        let raw = inner.get("a/data.ysweet").await.unwrap().unwrap();
+        assert!(raw.starts_with(MAGIC));
+        assert!(!raw.windows(5).any(|w| w == b"hello"));
+
+        // After rotation, old objects still decrypt and new ones use the new key.
+        let store =
+            EncryptedStore::new(Box::new(inner.clone()), key_ring(&[("new", 2), ("old", 1)]));
+        assert_eq!(
+            store.get("a/data.ysweet").await.unwrap(),
+            Some(b"hello".to_vec())
+        );
+        store.set("b/data.ysweet", b"world".to_vec()).await.unwrap();
+
+        let store = EncryptedStore::new(Box::new(inner.clone()), key_ring(&[("old", 1)]));
+        assert!(matches!(
+            store.get("b/data.ysweet").await,
+            Err(StoreError::EncryptionError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_tampering_and_plaintext() {
+        let inner = MemoryStore::default();
+        let store = EncryptedStore::new(Box::new(inner.clone()), key_ring(&[("k", 1)]));
+
+        inner
+            .set("legacy/data.ysweet", b"plain".to_vec())
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.get("legacy/data.ysweet").await,
+            Err(StoreError::EncryptionError(_))
+        ));
+        let migrating = EncryptedStore::new(Box::new(inner.clone()), key_ring(&[("k", 1)]))
+            .with_unencrypted_reads(true);
+        assert_eq!(
+            migrating.get("legacy/data.ysweet").await.unwrap(),
+            Some(b"plain".to_vec())
+        );
+
+        // An object moved to another key fails to decrypt.
+        store
+            .set("a/data.ysweet", b"secret".to_vec())
+            .await
+            .unwrap();
+        let raw = inner.get("a/data.ysweet").await.unwrap().unwrap();
+        inner.set("b/data.ysweet", raw.clone()).await.unwrap();
+        assert!(store.get("b/data.ysweet").await.is_err());
+
+        let mut corrupted = raw.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        inner.set("a/data.ysweet", corrupted).await.unwrap();
+        assert!(store.get("a/data.ysweet").await.is_err());
+
+        // The wrapped data key is bound to the format version in the header.
+        let mut downgraded = raw;
+        downgraded[MAGIC.len()] = FORMAT_V1;
+        inner.set("a/data.ysweet", downgraded).await.unwrap();
+        assert!(store.get("a/data.ysweet").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_format_v1() {
+        let inner = MemoryStore::default();
+        let ring = key_ring(&[("k", 1)]);
+        let wrapping_cipher = &ring.keys["k"];
+        let data_key = [3; KEY_LEN];
+        let data_cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key));
+        let nonce = [0; NONCE_LEN];
+
+        let mut v1 = MAGIC.to_vec();
+        v1.push(FORMAT_V1);
+        v1.push(1);
+        v1.extend_from_slice(b"k");
+        v1.extend_from_slice(&nonce);
+        v1.extend(
+            wrapping_cipher
+                .encrypt(Nonce::from_slice(&nonce), data_key.as_slice())
+                .unwrap(),
+        );
+        v1.extend_from_slice(&nonce);
+        v1.extend(
+            data_cipher
+                .encrypt(
+                    Nonce::from_slice(&nonce),
+                    Payload {
+                        msg: b"old",
+                        aad: b"a/data.ysweet",
+                    },
+                )
+                .unwrap(),
+        );
+        inner.set("a/data.ysweet", v1).await.unwrap();
+
+        let store = EncryptedStore::new(Box::new(inner), ring);
+        assert_eq!(
+            store.get("a/data.ysweet").await.unwrap(),
+            Some(b"old".to_vec())
+        );
+    }
+
+    #[test]
+    fn parses_key_ring() {
+        let key = data_encoding::BASE64.encode(&[7; KEY_LEN]);
+        let ring: KeyRing = format!("k2:{key}, k1:{key}").parse().unwrap();
+        assert_eq!(ring.active_key_id, "k2");
+        assert_eq!(ring.keys.len(), 2);
+
+        assert!("".parse::<KeyRing>().is_err());
+        assert!("k1".parse::<KeyRing>().is_err());
+        assert!("k1:AAAA".parse::<KeyRing>().is_err());
+        assert!(format!("k1:{key},k1:{key}").parse::<KeyRing>().is_err());
+    }
+}
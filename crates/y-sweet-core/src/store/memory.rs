@@ -0,0 +1,324 @@
+use super::{ListResult, ObjectVersion, Result, StoreError};
+use crate::store::Store;
+use async_trait::async_trait;
+use std::{
+    collections::BTreeMap,
+    ops::Bound,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Default)]
+struct MemoryStoreData {
+    objects: BTreeMap<String, (Vec<u8>, u64)>,
+    next_version: u64,
+}
+
+impl MemoryStoreData {
+    fn insert(&mut self, key: &str, value: Vec<u8>) -> ObjectVersion {
+        self.next_version += 1;
+        self.objects
+            .insert(key.to_owned(), (value, self.next_version));
+        ObjectVersion(self.next_version.to_string())
+    }
+}
+
+/// A store that keeps objects in memory, for development and tests.
+///
+/// Nothing is written to disk, so all data is lost when the process exits, but
+/// documents go through the same persistence code paths as with a durable store.
+/// Clones share the same data.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    data: Arc<Mutex<MemoryStoreData>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of objects in the store.
+    pub fn len(&self) -> usize {
+        self.data.lock().unwrap().objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    async fn init(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.objects.get(key).map(|(value, _)| value.clone()))
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.data.lock().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        self.data.lock().unwrap().objects.remove(key);
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.data.lock().unwrap().objects.contains_key(key))
+    }
+
+    async fn list(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<ListResult> {
+        let data = self.data.lock().unwrap();
+        let start = match cursor {
+            Some(cursor) if cursor >= prefix => cursor,
+            _ => prefix,
+        };
+        let mut keys: Vec<String> = data
+            .objects
+            .range::<str, _>((Bound::Included(start), Bound::Unbounded))
+            .map(|(key, _)| key)
+            .skip_while(|key| cursor == Some(key.as_str()))
+            .take_while(|key| key.starts_with(prefix))
+            .take(limit.saturating_add(1))
+            .cloned()
+            .collect();
+        let next_cursor = if keys.len() > limit {
+            keys.truncate(limit);
+            keys.last().cloned()
+        } else {
+            None
+        };
+        Ok(ListResult { keys, next_cursor })
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .objects
+            .get(key)
+            .map(|(value, version)| (value.clone(), ObjectVersion(version.to_string()))))
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<ObjectVersion>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .objects
+            .get(key)
+            .map(|(_, version)| ObjectVersion(version.to_string())))
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        let mut data = self.data.lock().unwrap();
+        let current = data
+            .objects
+            .get(key)
+            .map(|(_, version)| ObjectVersion(version.to_string()));
+        if current.as_ref() != expected {
+            return Err(StoreError::Conflict(format!(
+                "Expected version {:?} of {}, found {:?}.",
+                expected, key, current
+            )));
+        }
+        Ok(data.insert(key, value))
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[async_trait(?Send)]
+impl Store for MemoryStore {
+    async fn init(&self) -> Result<()> {
+        self.init().await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.get(key).await
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.set(key, value).await
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        self.remove(key).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        self.exists(key).await
+    }
+
+    async fn list(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<ListResult> {
+        self.list(prefix, cursor, limit).await
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        self.get_versioned(key).await
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<ObjectVersion>> {
+        self.get_version(key).await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        self.set_if(key, value, expected).await
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl Store for MemoryStore {
+    async fn init(&self) -> Result<()> {
+        self.init().await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.get(key).await
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.set(key, value).await
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        self.remove(key).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        self.exists(key).await
+    }
+
+    async fn list(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<ListResult> {
+        self.list(prefix, cursor, limit).await
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        self.get_versioned(key).await
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<ObjectVersion>> {
+        self.get_version(key).await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        self.set_if(key, value, expected).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn lists_in_pages() {
+        let store = MemoryStore::new();
+        for key in ["a/1", "a/2", "a/3", "b/1"] {
+            Store::set(&store, key, vec![]).await.unwrap();
+        }
+
+        let page = Store::list(&store, "a/", None, 2).await.unwrap();
+        assert_eq!(page.keys, vec!["a/1", "a/2"]);
+        let page = Store::list(&store, "a/", page.next_cursor.as_deref(), 2)
+            .await
+            .unwrap();
+        assert_eq!(page.keys, vec!["a/3"]);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[tokio::test]
+    async fn lists_docs_in_doc_id_order() {
+        let store = MemoryStore::new();
+        for key in [
+            "a-b/data.ysweet",
+            "a-b/versions/1.ysweet",
+            "a/data.ysweet",
+            "a/log/marker",
+            "a0/data.ysweet",
+            "b/other",
+            "b/c/data.ysweet",
+        ] {
+            Store::set(&store, key, vec![]).await.unwrap();
+        }
+
+        for limit in [1, 2, 10] {
+            let mut docs = Vec::new();
+            let mut cursor = None;
+            loop {
+                let page = Store::list_docs(&store, "", cursor.as_deref(), limit)
+                    .await
+                    .unwrap();
+                assert!(page.keys.len() <= limit);
+                docs.extend(page.keys);
+                match page.next_cursor {
+                    Some(next_cursor) => cursor = Some(next_cursor),
+                    None => break,
+                }
+            }
+            assert_eq!(docs, vec!["a", "a-b", "a0"]);
+        }
+
+        let page = Store::list_docs(&store, "a-", None, 10).await.unwrap();
+        assert_eq!(page.keys, vec!["a-b"]);
+
+        // A doc ID can be extended more than once, e.g. `a-b-c` sorts before both
+        // `a-b` and `a` by key.
+        Store::set(&store, "a-b-c/data.ysweet", vec![])
+            .await
+            .unwrap();
+        for (prefix, expected) in [
+            ("", vec!["a", "a-b", "a-b-c", "a0"]),
+            ("a-b", vec!["a-b", "a-b-c"]),
+        ] {
+            let mut docs = Vec::new();
+            let mut cursor = None;
+            loop {
+                let page = Store::list_docs(&store, prefix, cursor.as_deref(), 1)
+                    .await
+                    .unwrap();
+                docs.extend(page.keys);
+                match page.next_cursor {
+                    Some(next_cursor) => cursor = Some(next_cursor),
+                    None => break,
+                }
+            }
+            assert_eq!(docs, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn set_if_checks_version() {
+        let store = MemoryStore::new();
+        let version = Store::set_if(&store, "doc", b"a".to_vec(), None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            Store::set_if(&store, "doc", b"b".to_vec(), None).await,
+            Err(StoreError::Conflict(_))
+        ));
+
+        // Versions change on every write, even if the content does not.
+        let next = Store::set_if(&store, "doc", b"a".to_vec(), Some(&version))
+            .await
+            .unwrap();
+        assert_ne!(version, next);
+        assert!(matches!(
+            Store::set_if(&store, "doc", b"c".to_vec(), Some(&version)).await,
+            Err(StoreError::Conflict(_))
+        ));
+    }
+}
@@ -0,0 +1,549 @@
+use super::{ListResult, ObjectVersion, Result, StoreError};
+use crate::store::Store;
+use async_trait::async_trait;
+use std::{collections::BTreeSet, str::FromStr, sync::Arc};
+
+/// Objects whose last write or removal may not have reached the mirror are
+/// recorded by empty marker objects in the primary, under this prefix, so that
+/// they are repaired even if the process restarts. Doc IDs cannot start with `.`.
+const PENDING_PREFIX: &str = ".mirror/pending/";
+
+/// How many keys to compare between the stores at a time when reconciling.
+const RECONCILE_BATCH_SIZE: usize = 1000;
+
+fn pending_key(key: &str) -> String {
+    format!("{}{}", PENDING_PREFIX, key)
+}
+
+/// How strictly a [`MirrorStore`] requires the mirror.
+///
+/// In both modes, a failed write to the mirror is queued for
+/// [`MirrorStore::reconcile`], so that the mirror catches up with the primary.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MirrorConsistency {
+    /// The mirror may be unavailable. A write succeeds once the primary has it,
+    /// and failed writes to the mirror are logged as warnings.
+    #[default]
+    Primary,
+    /// The mirror must be available when the store is initialized, and a write
+    /// only succeeds if both stores have it. The primary is written first, so a
+    /// write that fails on the mirror has still reached the primary.
+    Both,
+}
+
+impl FromStr for MirrorConsistency {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "primary" => Ok(Self::Primary),
+            "both" => Ok(Self::Both),
+            _ => anyhow::bail!(
+                "Unknown mirror consistency {:?}, expected \"primary\" or \"both\"",
+                s
+            ),
+        }
+    }
+}
+
+/// Whether an error means that the store could not be reached, as opposed to
+/// the store rejecting the request.
+fn is_unavailable(error: &StoreError) -> bool {
+    matches!(
+        error,
+        StoreError::ConnectionError(_) | StoreError::Throttled(_)
+    )
+}
+
+/// A store that writes every object to both a primary store and a mirror, e.g.
+/// to keep a backup in a second bucket or on local disk.
+///
+/// Reads are served by the primary, falling back to the mirror when the primary
+/// is unavailable. Object versions are always the primary's, so conditional
+/// writes are decided by the primary and then copied to the mirror.
+///
+/// Clones share the same stores.
+#[derive(Clone)]
+pub struct MirrorStore {
+    primary: Arc<dyn Store>,
+    mirror: Arc<dyn Store>,
+    consistency: MirrorConsistency,
+}
+
+impl MirrorStore {
+    pub fn new(
+        primary: Box<dyn Store>,
+        mirror: Box<dyn Store>,
+        consistency: MirrorConsistency,
+    ) -> Self {
+        Self {
+            primary: Arc::from(primary),
+            mirror: Arc::from(mirror),
+            consistency,
+        }
+    }
+
+    /// Queue `key` for reconciliation if the write to the mirror failed. The
+    /// failure is returned in [`MirrorConsistency::Both`] mode.
+    async fn mirrored<T>(&self, key: &str, result: Result<T>) -> Result<()> {
+        let Err(e) = result else {
+            return Ok(());
+        };
+        if let Err(e) = self.primary.set(&pending_key(key), Vec::new()).await {
+            tracing::error!(?e, key, "Failed to queue object for mirror reconciliation.");
+        }
+        match self.consistency {
+            MirrorConsistency::Primary => {
+                tracing::warn!(?e, key, "Failed to write to mirror; it will be reconciled.");
+                Ok(())
+            }
+            MirrorConsistency::Both => Err(e),
+        }
+    }
+
+    /// Copy objects that are missing from the mirror, or whose last write to the
+    /// mirror failed, from the primary. Only keys starting with `prefix` are
+    /// scanned for missing copies, by comparing listings of both stores in
+    /// batches. Returns the number of objects copied.
+    pub async fn reconcile(&self, prefix: &str) -> Result<usize> {
+        let mut copied = self.repair_pending().await?;
+
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = self
+                .primary
+                .list(prefix, cursor.as_deref(), RECONCILE_BATCH_SIZE)
+                .await?;
+            let Some(last) = page.keys.last() else {
+                break;
+            };
+            let mirrored = self.mirror_keys(prefix, cursor.as_deref(), last).await?;
+            for key in &page.keys {
+                if key.starts_with(PENDING_PREFIX) || mirrored.contains(key) {
+                    continue;
+                }
+                self.repair(key).await?;
+                copied += 1;
+            }
+            match page.next_cursor {
+                Some(next_cursor) => cursor = Some(next_cursor),
+                None => break,
+            }
+        }
+
+        Ok(copied)
+    }
+
+    /// Repair the objects queued by failed writes to the mirror.
+    async fn repair_pending(&self) -> Result<usize> {
+        let mut repaired = 0;
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = self
+                .primary
+                .list(PENDING_PREFIX, cursor.as_deref(), RECONCILE_BATCH_SIZE)
+                .await?;
+            for marker in &page.keys {
+                let Some(key) = marker.strip_prefix(PENDING_PREFIX) else {
+                    continue;
+                };
+                // Remove the marker first, so that a write that fails while the
+                // object is repaired queues it again.
+                self.primary.remove(marker).await?;
+                if let Err(e) = self.repair(key).await {
+                    self.primary.set(marker, Vec::new()).await?;
+                    return Err(e);
+                }
+                repaired += 1;
+            }
+            match page.next_cursor {
+                Some(next_cursor) => cursor = Some(next_cursor),
+                None => return Ok(repaired),
+            }
+        }
+    }
+
+    /// The keys in the mirror that start with `prefix` and sort after `after`,
+    /// up to and including `through`.
+    async fn mirror_keys(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        through: &str,
+    ) -> Result<BTreeSet<String>> {
+        let mut keys = BTreeSet::new();
+        let mut cursor = after.map(str::to_string);
+        loop {
+            let page = self
+                .mirror
+                .list(prefix, cursor.as_deref(), RECONCILE_BATCH_SIZE)
+                .await?;
+            for key in page.keys {
+                if key.as_str() > through {
+                    return Ok(keys);
+                }
+                keys.insert(key);
+            }
+            match page.next_cursor {
+                Some(next_cursor) => cursor = Some(next_cursor),
+                None => return Ok(keys),
+            }
+        }
+    }
+
+    /// Make the mirror's copy of `key` match the primary's.
+    async fn repair(&self, key: &str) -> Result<()> {
+        tracing::info!(key, "Repairing mirrored object.");
+        match self.primary.get(key).await? {
+            Some(value) => self.mirror.set(key, value).await,
+            None => self.mirror.remove(key).await,
+        }
+    }
+
+    async fn init(&self) -> Result<()> {
+        self.primary.init().await?;
+        match self.mirror.init().await {
+            Err(e) if self.consistency == MirrorConsistency::Primary => {
+                tracing::warn!(?e, "Failed to initialize mirror store.");
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.primary.get(key).await {
+            Err(e) if is_unavailable(&e) => {
+                tracing::warn!(?e, key, "Primary store unavailable; reading from mirror.");
+                self.mirror.get(key).await
+            }
+            result => result,
+        }
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.primary.set(key, value.clone()).await?;
+        let result = self.mirror.set(key, value).await;
+        self.mirrored(key, result).await
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        self.primary.remove(key).await?;
+        let result = self.mirror.remove(key).await;
+        self.mirrored(key, result).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        match self.primary.exists(key).await {
+            Err(e) if is_unavailable(&e) => self.mirror.exists(key).await,
+            result => result,
+        }
+    }
+
+    async fn list(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<ListResult> {
+        match self.primary.list(prefix, cursor, limit).await {
+            Err(e) if is_unavailable(&e) => self.mirror.list(prefix, cursor, limit).await,
+            result => result,
+        }
+    }
+
+    async fn list_docs(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<ListResult> {
+        match self.primary.list_docs(prefix, cursor, limit).await {
+            Err(e) if is_unavailable(&e) => self.mirror.list_docs(prefix, cursor, limit).await,
+            result => result,
+        }
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        match self.primary.get_versioned(key).await {
+            // The mirror's version will not match the primary's, so a conditional
+            // write based on it fails with a conflict rather than overwriting.
+            Err(e) if is_unavailable(&e) => {
+                tracing::warn!(?e, key, "Primary store unavailable; reading from mirror.");
+                self.mirror.get_versioned(key).await
+            }
+            result => result,
+        }
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<ObjectVersion>> {
+        self.primary.get_version(key).await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        let version = self.primary.set_if(key, value.clone(), expected).await?;
+        let result = self.mirror.set(key, value).await;
+        self.mirrored(key, result).await?;
+        Ok(version)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[async_trait(?Send)]
+impl Store for MirrorStore {
+    async fn init(&self) -> Result<()> {
+        self.init().await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.get(key).await
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.set(key, value).await
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        self.remove(key).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        self.exists(key).await
+    }
+
+    async fn list(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<ListResult> {
+        self.list(prefix, cursor, limit).await
+    }
+
+    async fn list_docs(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<ListResult> {
+        self.list_docs(prefix, cursor, limit).await
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        self.get_versioned(key).await
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<ObjectVersion>> {
+        self.get_version(key).await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        self.set_if(key, value, expected).await
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl Store for MirrorStore {
+    async fn init(&self) -> Result<()> {
+        self.init().await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.get(key).await
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.set(key, value).await
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        self.remove(key).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        self.exists(key).await
+    }
+
+    async fn list(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<ListResult> {
+        self.list(prefix, cursor, limit).await
+    }
+
+    async fn list_docs(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<ListResult> {
+        self.list_docs(prefix, cursor, limit).await
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        self.get_versioned(key).await
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<ObjectVersion>> {
+        self.get_version(key).await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        self.set_if(key, value, expected).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// A store that can be made to fail every request, as if it were unreachable.
+    #[derive(Clone, Default)]
+    struct FlakyStore {
+        inner: MemoryStore,
+        down: Arc<AtomicBool>,
+    }
+
+    impl FlakyStore {
+        fn set_down(&self, down: bool) {
+            self.down.store(down, Ordering::SeqCst);
+        }
+
+        fn check(&self) -> Result<()> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(StoreError::ConnectionError("Store is down.".to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl Store for FlakyStore {
+        async fn init(&self) -> Result<()> {
+            self.check()
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            self.check()?;
+            self.inner.get(key).await
+        }
+
+        async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+            self.check()?;
+            self.inner.set(key, value).await
+        }
+
+        async fn remove(&self, key: &str) -> Result<()> {
+            self.check()?;
+            self.inner.remove(key).await
+        }
+
+        async fn exists(&self, key: &str) -> Result<bool> {
+            self.check()?;
+            self.inner.exists(key).await
+        }
+
+        async fn list(
+            &self,
+            prefix: &str,
+            cursor: Option<&str>,
+            limit: usize,
+        ) -> Result<ListResult> {
+            self.check()?;
+            self.inner.list(prefix, cursor, limit).await
+        }
+
+        async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+            self.check()?;
+            self.inner.get_versioned(key).await
+        }
+
+        async fn set_if(
+            &self,
+            key: &str,
+            value: Vec<u8>,
+            expected: Option<&ObjectVersion>,
+        ) -> Result<ObjectVersion> {
+            self.check()?;
+            self.inner.set_if(key, value, expected).await
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_mirror_and_reconciles() {
+        let primary = FlakyStore::default();
+        let mirror = FlakyStore::default();
+        let store = MirrorStore::new(
+            Box::new(primary.clone()),
+            Box::new(mirror.clone()),
+            MirrorConsistency::Primary,
+        );
+
+        store.set_if("a", b"1".to_vec(), None).await.unwrap();
+        assert_eq!(mirror.inner.get("a").await.unwrap(), Some(b"1".to_vec()));
+
+        primary.set_down(true);
+        assert_eq!(store.get("a").await.unwrap(), Some(b"1".to_vec()));
+        assert!(store.set("b", b"2".to_vec()).await.is_err());
+        primary.set_down(false);
+
+        // The mirror misses a write, and is repaired by reconciliation.
+        mirror.set_down(true);
+        store.set("a", b"2".to_vec()).await.unwrap();
+        store.set("b", b"3".to_vec()).await.unwrap();
+        mirror.set_down(false);
+        primary.inner.set("c", b"4".to_vec()).await.unwrap();
+
+        // The missed writes are recorded in the primary, so they are repaired
+        // even after a restart.
+        drop(store);
+        let store = MirrorStore::new(
+            Box::new(primary.clone()),
+            Box::new(mirror.clone()),
+            MirrorConsistency::Primary,
+        );
+        assert_eq!(store.reconcile("").await.unwrap(), 3);
+        for (key, value) in [("a", "2"), ("b", "3"), ("c", "4")] {
+            assert_eq!(
+                mirror.inner.get(key).await.unwrap(),
+                Some(value.as_bytes().to_vec())
+            );
+        }
+        assert_eq!(store.reconcile("").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn requires_mirror_if_configured() {
+        let primary = FlakyStore::default();
+        let mirror = FlakyStore::default();
+        let store = MirrorStore::new(
+            Box::new(primary.clone()),
+            Box::new(mirror.clone()),
+            MirrorConsistency::Both,
+        );
+
+        mirror.set_down(true);
+        assert!(store.init().await.is_err());
+
+        // Writes fail if the mirror does not get them, but are still queued for
+        // reconciliation, since they reached the primary.
+        assert!(matches!(
+            store.set_if("a", b"1".to_vec(), None).await,
+            Err(StoreError::ConnectionError(_))
+        ));
+        assert!(store.set("b", b"2".to_vec()).await.is_err());
+        assert!(store.remove("b").await.is_err());
+        assert_eq!(primary.inner.get("a").await.unwrap(), Some(b"1".to_vec()));
+
+        mirror.set_down(false);
+        store.set("c", b"3".to_vec()).await.unwrap();
+        assert_eq!(store.reconcile("").await.unwrap(), 2);
+        assert_eq!(mirror.inner.get("a").await.unwrap(), Some(b"1".to_vec()));
+        assert_eq!(mirror.inner.get("b").await.unwrap(), None);
+    }
+}
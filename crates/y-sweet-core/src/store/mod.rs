@@ -1,6 +1,12 @@
+pub mod credentials;
+pub mod encrypted;
+pub mod memory;
+pub mod mirror;
 pub mod s3;
 
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -13,10 +19,115 @@ pub enum StoreError {
     NotAuthorized(String),
     #[error("Error connecting to store. {0}")]
     ConnectionError(String),
+    #[error("Store is throttling requests. {0}")]
+    Throttled(String),
+    #[error("Object was modified concurrently. {0}")]
+    Conflict(String),
+    #[error("Error encrypting or decrypting object. {0}")]
+    EncryptionError(String),
 }
 
 pub type Result<T> = std::result::Result<T, StoreError>;
 
+/// A page of keys returned by [`Store::list`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ListResult {
+    /// Matching keys, in lexicographic order.
+    pub keys: Vec<String>,
+    /// If more keys may remain, the cursor to pass to the next call to `list`.
+    pub next_cursor: Option<String>,
+}
+
+/// An opaque token identifying one version of a stored object, such as an S3 ETag.
+/// Used with [`Store::set_if`] to make compare-and-swap writes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ObjectVersion(pub String);
+
+impl ObjectVersion {
+    /// A version derived from the object's contents, for stores that have no
+    /// native notion of versions.
+    pub fn from_content(value: &[u8]) -> Self {
+        Self(data_encoding::HEXLOWER.encode(&Sha256::digest(value)))
+    }
+}
+
+const LIST_PAGE_SIZE: usize = 1000;
+
+/// The key of a document's snapshot.
+fn snapshot_key(doc_id: &str) -> String {
+    format!("{}/data.ysweet", doc_id)
+}
+
+/// Implements [`Store::list_docs`] by scanning the keys under `prefix`.
+///
+/// Keys are ordered differently from doc IDs where one doc ID extends another
+/// with a character that sorts before `/`: `a-b/data.ysweet` comes before
+/// `a/data.ysweet`, but `a` comes before `a-b`. So once `limit` documents are
+/// found, the scan continues past the snapshots of any such shorter doc IDs.
+async fn list_docs_by_keys<S: Store + ?Sized>(
+    store: &S,
+    prefix: &str,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Result<ListResult> {
+    if limit == 0 {
+        return Ok(ListResult::default());
+    }
+
+    let mut docs = Vec::new();
+    let mut scan_until: Option<String> = None;
+    // Every doc ID after the cursor has a key after it, too.
+    let mut store_cursor = cursor.map(str::to_string);
+    let mut scanned_all = false;
+    'pages: loop {
+        let page = store
+            .list(prefix, store_cursor.as_deref(), LIST_PAGE_SIZE)
+            .await?;
+        for key in &page.keys {
+            if docs.len() >= limit && scan_until.as_ref().is_none_or(|until| key > until) {
+                break 'pages;
+            }
+            let Some(doc_id) = key.strip_suffix("/data.ysweet") else {
+                continue;
+            };
+            if doc_id.contains('/') || cursor.is_some_and(|cursor| doc_id <= cursor) {
+                continue;
+            }
+            // Any prefix of the doc ID that ends before such a character may be a
+            // doc whose snapshot sorts after this one.
+            for (i, _) in doc_id.bytes().enumerate().filter(|(_, b)| *b < b'/') {
+                let shorter = &doc_id[..i];
+                if shorter.starts_with(prefix) && cursor.is_none_or(|cursor| shorter > cursor) {
+                    let key = snapshot_key(shorter);
+                    if scan_until.as_ref().is_none_or(|until| key > *until) {
+                        scan_until = Some(key);
+                    }
+                }
+            }
+            docs.push(doc_id.to_string());
+        }
+        match page.next_cursor {
+            Some(next_cursor) => store_cursor = Some(next_cursor),
+            None => {
+                scanned_all = true;
+                break;
+            }
+        }
+    }
+
+    docs.sort();
+    let next_cursor = if docs.len() > limit || !scanned_all {
+        docs.truncate(limit);
+        docs.last().cloned()
+    } else {
+        None
+    };
+    Ok(ListResult {
+        keys: docs,
+        next_cursor,
+    })
+}
+
 #[cfg(target_arch = "wasm32")]
 #[async_trait(?Send)]
 pub trait Store: 'static {
@@ -25,6 +136,65 @@ pub trait Store: 'static {
     async fn set(&self, key: &str, value: Vec<u8>) -> Result<()>;
     async fn remove(&self, key: &str) -> Result<()>;
     async fn exists(&self, key: &str) -> Result<bool>;
+    /// List up to `limit` keys that start with `prefix`, in lexicographic order.
+    /// If `cursor` is given, only keys that sort after it are returned.
+    async fn list(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<ListResult>;
+
+    /// List up to `limit` IDs of documents, i.e. top-level names that have a
+    /// `{doc_id}/data.ysweet` snapshot, that start with `prefix`, ordered by doc
+    /// ID. If `cursor` is given, only IDs that sort after it are returned. The
+    /// IDs are returned as the result's `keys`, and `next_cursor` is a doc ID.
+    ///
+    /// The default implementation scans the keys returned by `list`; stores that
+    /// can list top-level names directly should override it.
+    async fn list_docs(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<ListResult> {
+        list_docs_by_keys(self, prefix, cursor, limit).await
+    }
+
+    /// Like `get`, but also returns the version of the object that was read.
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        Ok(self.get(key).await?.map(|value| {
+            let version = ObjectVersion::from_content(&value);
+            (value, version)
+        }))
+    }
+
+    /// The current version of the object, or `None` if it does not exist.
+    ///
+    /// The default implementation reads the whole object; stores should override
+    /// it with a cheaper metadata lookup.
+    async fn get_version(&self, key: &str) -> Result<Option<ObjectVersion>> {
+        Ok(self.get_versioned(key).await?.map(|(_, version)| version))
+    }
+
+    /// Write `value` only if the object is currently at version `expected`, or,
+    /// if `expected` is `None`, only if the object does not exist yet. Returns
+    /// the new version, or `StoreError::Conflict` if the precondition failed.
+    ///
+    /// The default implementation compares content hashes and is not atomic;
+    /// stores should override it with a native conditional write.
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        let current = self.get_versioned(key).await?.map(|(_, version)| version);
+        if current.as_ref() != expected {
+            return Err(StoreError::Conflict(format!(
+                "Expected version {:?} of {}, found {:?}.",
+                expected, key, current
+            )));
+        }
+        let version = ObjectVersion::from_content(&value);
+        self.set(key, value).await?;
+        Ok(version)
+    }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -35,4 +205,63 @@ pub trait Store: Send + Sync {
     async fn set(&self, key: &str, value: Vec<u8>) -> Result<()>;
     async fn remove(&self, key: &str) -> Result<()>;
     async fn exists(&self, key: &str) -> Result<bool>;
+    /// List up to `limit` keys that start with `prefix`, in lexicographic order.
+    /// If `cursor` is given, only keys that sort after it are returned.
+    async fn list(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<ListResult>;
+
+    /// List up to `limit` IDs of documents, i.e. top-level names that have a
+    /// `{doc_id}/data.ysweet` snapshot, that start with `prefix`, ordered by doc
+    /// ID. If `cursor` is given, only IDs that sort after it are returned. The
+    /// IDs are returned as the result's `keys`, and `next_cursor` is a doc ID.
+    ///
+    /// The default implementation scans the keys returned by `list`; stores that
+    /// can list top-level names directly should override it.
+    async fn list_docs(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<ListResult> {
+        list_docs_by_keys(self, prefix, cursor, limit).await
+    }
+
+    /// Like `get`, but also returns the version of the object that was read.
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        Ok(self.get(key).await?.map(|value| {
+            let version = ObjectVersion::from_content(&value);
+            (value, version)
+        }))
+    }
+
+    /// The current version of the object, or `None` if it does not exist.
+    ///
+    /// The default implementation reads the whole object; stores should override
+    /// it with a cheaper metadata lookup.
+    async fn get_version(&self, key: &str) -> Result<Option<ObjectVersion>> {
+        Ok(self.get_versioned(key).await?.map(|(_, version)| version))
+    }
+
+    /// Write `value` only if the object is currently at version `expected`, or,
+    /// if `expected` is `None`, only if the object does not exist yet. Returns
+    /// the new version, or `StoreError::Conflict` if the precondition failed.
+    ///
+    /// The default implementation compares content hashes and is not atomic;
+    /// stores should override it with a native conditional write.
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        let current = self.get_versioned(key).await?.map(|(_, version)| version);
+        if current.as_ref() != expected {
+            return Err(StoreError::Conflict(format!(
+                "Expected version {:?} of {}, found {:?}.",
+                expected, key, current
+            )));
+        }
+        let version = ObjectVersion::from_content(&value);
+        self.set(key, value).await?;
+        Ok(version)
+    }
 }
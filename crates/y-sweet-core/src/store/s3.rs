@@ -1,19 +1,33 @@
-use super::{Result, StoreError};
+use super::{
+    credentials::{AwsCredentials, CredentialsProvider},
+    ListResult, ObjectVersion, Result, StoreError,
+};
 use crate::store::Store;
 use async_trait::async_trait;
 use bytes::Bytes;
-use reqwest::{Client, Method, Response, StatusCode, Url};
-use rusty_s3::{Bucket, Credentials, S3Action};
+use data_encoding::BASE64;
+use md5::{Digest, Md5};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::{header::ETAG, Client, Method, Response, StatusCode, Url};
+use rusty_s3::{
+    actions::{CreateMultipartUpload, ListObjectsV2},
+    Bucket, Credentials, S3Action,
+};
 use serde::{Deserialize, Serialize};
-use std::sync::OnceLock;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::Duration;
 use time::OffsetDateTime;
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct S3Config {
-    pub key: String,
+    /// Static credentials. If `key` or `secret` is not set, credentials are loaded
+    /// from the standard AWS credential chain (see [`CredentialsProvider::default_chain`]).
+    #[serde(default)]
+    pub key: Option<String>,
     pub endpoint: String,
-    pub secret: String,
+    #[serde(default)]
+    pub secret: Option<String>,
     pub token: Option<String>,
     pub bucket: String,
     pub region: String,
@@ -21,25 +35,298 @@ pub struct S3Config {
 
     // Use old path-style URLs, needed to support some S3-compatible APIs (including some minio setups)
     pub path_style: bool,
+
+    /// Objects larger than this many bytes are written with a multipart upload.
+    #[serde(default = "S3Config::default_multipart_threshold")]
+    pub multipart_threshold: usize,
+
+    /// The size in bytes of each part of a multipart upload. S3 requires at least 5 MiB.
+    #[serde(default = "S3Config::default_multipart_part_size")]
+    pub multipart_part_size: usize,
+
+    /// How many times to retry a request that failed with a transient error.
+    #[serde(default = "S3Config::default_max_retries")]
+    pub max_retries: u32,
+
+    /// How long to wait for each attempt of a request before giving up on it.
+    #[serde(default = "S3Config::default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+
+    /// Server-side encryption to apply to objects when they are written.
+    #[serde(default)]
+    pub server_side_encryption: Option<ServerSideEncryption>,
+
+    /// The storage class of written objects, e.g. `STANDARD_IA`. If not set, the
+    /// bucket's default is used.
+    #[serde(default)]
+    pub storage_class: Option<String>,
+
+    /// User-defined metadata (`x-amz-meta-*`) to store with every written object.
+    /// In values, `{doc_id}` is replaced with the ID of the document that the object
+    /// belongs to, and `{version}` with the y-sweet version.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+
+    /// Tags to apply to every written object. Values can use the same placeholders
+    /// as `metadata`.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl S3Config {
+    pub const DEFAULT_MULTIPART_THRESHOLD: usize = 16 * 1024 * 1024;
+    pub const DEFAULT_MULTIPART_PART_SIZE: usize = 8 * 1024 * 1024;
+    pub const DEFAULT_MAX_RETRIES: u32 = 3;
+    pub const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+
+    fn default_multipart_threshold() -> usize {
+        Self::DEFAULT_MULTIPART_THRESHOLD
+    }
+
+    fn default_multipart_part_size() -> usize {
+        Self::DEFAULT_MULTIPART_PART_SIZE
+    }
+
+    fn default_max_retries() -> u32 {
+        Self::DEFAULT_MAX_RETRIES
+    }
+
+    fn default_request_timeout_secs() -> u64 {
+        Self::DEFAULT_REQUEST_TIMEOUT_SECS
+    }
+}
+
+/// Server-side encryption of objects by S3.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(tag = "mode", rename_all = "kebab-case")]
+pub enum ServerSideEncryption {
+    /// SSE-S3: S3 encrypts objects with keys that it manages.
+    S3,
+    /// SSE-KMS: S3 encrypts objects with a KMS key, or with the account's default
+    /// `aws/s3` key if no key ID is given.
+    Kms { key_id: Option<String> },
+    /// SSE-C: S3 encrypts objects with a base64-encoded 256-bit key that is sent
+    /// with every request, and that S3 does not store.
+    Customer { key: String },
+}
+
+impl ServerSideEncryption {
+    /// Parse a mode (`sse-s3`, `sse-kms` or `sse-c`) and the key that goes with it:
+    /// an optional key ID for SSE-KMS, or the encryption key for SSE-C.
+    pub fn parse(mode: &str, key: Option<String>) -> anyhow::Result<Self> {
+        match mode {
+            "sse-s3" => Ok(Self::S3),
+            "sse-kms" => Ok(Self::Kms { key_id: key }),
+            "sse-c" => {
+                let key = key.ok_or_else(|| anyhow::anyhow!("SSE-C requires a key"))?;
+                if decode_customer_key(&key).is_none() {
+                    anyhow::bail!("SSE-C key must be 32 bytes, base64-encoded");
+                }
+                Ok(Self::Customer { key })
+            }
+            _ => anyhow::bail!(
+                "Unknown server-side encryption mode {:?}, expected \"sse-s3\", \"sse-kms\" or \"sse-c\"",
+                mode
+            ),
+        }
+    }
+
+    /// Headers to send when an object is created.
+    fn creation_headers(&self) -> Result<Vec<(String, String)>> {
+        let header = |name: &str, value: &str| (name.to_string(), value.to_string());
+        match self {
+            Self::S3 => Ok(vec![header("x-amz-server-side-encryption", "AES256")]),
+            Self::Kms { key_id } => {
+                let mut headers = vec![header("x-amz-server-side-encryption", "aws:kms")];
+                if let Some(key_id) = key_id {
+                    headers.push(header(
+                        "x-amz-server-side-encryption-aws-kms-key-id",
+                        key_id,
+                    ));
+                }
+                Ok(headers)
+            }
+            Self::Customer { .. } => self.customer_key_headers(),
+        }
+    }
+
+    /// Headers that SSE-C requires on every request that reads or writes object data.
+    /// Fails if the SSE-C key is invalid, e.g. in a deserialized config.
+    fn customer_key_headers(&self) -> Result<Vec<(String, String)>> {
+        let Self::Customer { key } = self else {
+            return Ok(Vec::new());
+        };
+        let decoded = decode_customer_key(key).ok_or_else(|| {
+            StoreError::EncryptionError("SSE-C key must be 32 bytes, base64-encoded.".to_string())
+        })?;
+        Ok(vec![
+            (
+                "x-amz-server-side-encryption-customer-algorithm".to_string(),
+                "AES256".to_string(),
+            ),
+            (
+                "x-amz-server-side-encryption-customer-key".to_string(),
+                key.clone(),
+            ),
+            (
+                "x-amz-server-side-encryption-customer-key-md5".to_string(),
+                BASE64.encode(&Md5::digest(decoded)),
+            ),
+        ])
+    }
+}
+
+fn decode_customer_key(key: &str) -> Option<Vec<u8>> {
+    BASE64
+        .decode(key.as_bytes())
+        .ok()
+        .filter(|decoded| decoded.len() == 32)
+}
+
+/// Parse a comma-separated list of `name=value` pairs, as used for object
+/// metadata and tags in environment variables.
+pub fn parse_key_value_pairs(s: &str) -> anyhow::Result<BTreeMap<String, String>> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("Expected name=value, found {:?}", pair))?;
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
 }
 
 const PRESIGNED_URL_DURATION: Duration = Duration::from_secs(60 * 60);
 
+/// S3 rejects parts smaller than this, except for the last part of an upload.
+const MIN_MULTIPART_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// S3 allows at most this many parts in an upload.
+const MAX_MULTIPART_PARTS: usize = 10_000;
+
+/// Temporary credentials are replaced this long before they expire, so that
+/// requests signed with them do not reach S3 after they have expired.
+const CREDENTIALS_REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// How a request to S3 failed, which determines whether it may be retried.
+enum RequestFailure {
+    /// The request may succeed if it is retried, e.g. it was throttled or timed out.
+    Transient(StoreError),
+    /// Retrying the request will not change the result.
+    Permanent(StoreError),
+}
+
+impl RequestFailure {
+    fn into_error(self) -> StoreError {
+        match self {
+            RequestFailure::Transient(e) | RequestFailure::Permanent(e) => e,
+        }
+    }
+}
+
+/// The body of an error response from the S3-compatible API.
+#[derive(Deserialize)]
+struct S3ErrorResponse {
+    #[serde(rename = "Code")]
+    code: String,
+    #[serde(rename = "Message")]
+    message: Option<String>,
+}
+
+/// The body of a successful `CompleteMultipartUpload` response.
+#[derive(Deserialize)]
+struct CompleteMultipartUploadResult {
+    #[serde(rename = "ETag")]
+    etag: String,
+}
+
+fn insert_headers<'a, A: S3Action<'a>>(action: &mut A, headers: &[(String, String)]) {
+    for (name, value) in headers {
+        action.headers_mut().insert(name.clone(), value.clone());
+    }
+}
+
+/// Exponential backoff with full jitter: a random delay of up to
+/// `RETRY_BASE_DELAY * 2^attempt`, capped at `RETRY_MAX_DELAY`.
+fn backoff_delay(attempt: u32) -> Duration {
+    let max = RETRY_BASE_DELAY
+        .saturating_mul(1 << attempt.min(16))
+        .min(RETRY_MAX_DELAY);
+    max.mul_f64(rand::random::<f64>())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+
+// There is no timer available to the worker's runtime here, so retries are
+// disabled on wasm (see `S3Store::new`) and this is never reached with a delay.
+#[cfg(target_arch = "wasm32")]
+async fn sleep(_duration: Duration) {}
+
 pub struct S3Store {
     bucket: Bucket,
     _bucket_checked: OnceLock<()>,
     client: Client,
-    credentials: Credentials,
+    credentials_provider: CredentialsProvider,
+    credentials: Mutex<Option<Arc<LoadedCredentials>>>,
+    /// Held while loading credentials, so that concurrent requests wait for one
+    /// load instead of each loading them.
+    refreshing: futures::lock::Mutex<()>,
     prefix: Option<String>,
+    multipart_threshold: usize,
+    multipart_part_size: usize,
+    max_retries: u32,
+    #[cfg_attr(target_arch = "wasm32", allow(unused))]
+    request_timeout: Duration,
+    /// Headers for server-side encryption and the storage class, sent when an
+    /// object is created.
+    creation_headers: Vec<(String, String)>,
+    /// Headers sent with every request for object data, which SSE-C requires.
+    customer_key_headers: Vec<(String, String)>,
+    metadata: BTreeMap<String, String>,
+    tags: BTreeMap<String, String>,
+}
+
+struct LoadedCredentials {
+    credentials: Credentials,
+    expiration: Option<OffsetDateTime>,
+}
+
+impl LoadedCredentials {
+    fn expires_within(&self, margin: Duration) -> bool {
+        self.expiration
+            .is_some_and(|expiration| expiration - margin <= OffsetDateTime::now_utc())
+    }
 }
 
 impl S3Store {
-    pub fn new(config: S3Config) -> Self {
-        let credentials = if let Some(token) = config.token {
-            Credentials::new_with_token(config.key, config.secret, token)
-        } else {
-            Credentials::new(config.key, config.secret)
+    /// Create a store from `config`. Fails if its SSE-C key is invalid.
+    pub fn new(config: S3Config) -> Result<Self> {
+        let credentials_provider = match (&config.key, &config.secret) {
+            (Some(key), Some(secret)) => CredentialsProvider::Static(AwsCredentials {
+                key: key.clone(),
+                secret: secret.clone(),
+                token: config.token.clone(),
+                expiration: None,
+            }),
+            _ => CredentialsProvider::default_chain(),
         };
+        Self::with_credentials_provider(config, credentials_provider)
+    }
+
+    /// Create a store that ignores the credentials in `config` and gets them
+    /// from `credentials_provider` instead.
+    pub fn with_credentials_provider(
+        config: S3Config,
+        credentials_provider: CredentialsProvider,
+    ) -> Result<Self> {
         let endpoint: Url = config.endpoint.parse().expect("endpoint is a valid url");
 
         let path_style = if config.path_style {
@@ -53,64 +340,257 @@ impl S3Store {
             rusty_s3::UrlStyle::VirtualHost
         };
 
+        let server_side_encryption = config.server_side_encryption.as_ref();
+        let mut creation_headers = server_side_encryption
+            .map(ServerSideEncryption::creation_headers)
+            .transpose()?
+            .unwrap_or_default();
+        if let Some(storage_class) = &config.storage_class {
+            creation_headers.push(("x-amz-storage-class".to_string(), storage_class.clone()));
+        }
+        let customer_key_headers = server_side_encryption
+            .map(ServerSideEncryption::customer_key_headers)
+            .transpose()?
+            .unwrap_or_default();
+
         let bucket = Bucket::new(endpoint, path_style, config.bucket, config.region)
             .expect("Url has a valid scheme and host");
         let client = Client::new();
 
-        S3Store {
+        Ok(S3Store {
             bucket,
             _bucket_checked: OnceLock::new(),
             client,
-            credentials,
+            credentials_provider,
+            credentials: Mutex::new(None),
+            refreshing: futures::lock::Mutex::new(()),
             prefix: config.bucket_prefix,
+            multipart_threshold: config.multipart_threshold,
+            multipart_part_size: config.multipart_part_size.max(MIN_MULTIPART_PART_SIZE),
+            max_retries: if cfg!(target_arch = "wasm32") {
+                0
+            } else {
+                config.max_retries
+            },
+            request_timeout: Duration::from_secs(config.request_timeout_secs),
+            creation_headers,
+            customer_key_headers,
+            metadata: config.metadata,
+            tags: config.tags,
+        })
+    }
+
+    /// Headers to send when creating the object `key`: encryption, storage class,
+    /// metadata and tags.
+    fn object_headers(&self, key: &str) -> Vec<(String, String)> {
+        let doc_id = key.split('/').next().unwrap_or(key);
+        let expand = |value: &str| {
+            value
+                .replace("{doc_id}", doc_id)
+                .replace("{version}", env!("CARGO_PKG_VERSION"))
+        };
+
+        let mut headers = self.creation_headers.clone();
+        for (name, value) in &self.metadata {
+            headers.push((format!("x-amz-meta-{}", name.to_lowercase()), expand(value)));
+        }
+        if !self.tags.is_empty() {
+            let encode = |value: &str| utf8_percent_encode(value, NON_ALPHANUMERIC).to_string();
+            let tagging = self
+                .tags
+                .iter()
+                .map(|(name, value)| format!("{}={}", encode(name), encode(&expand(value))))
+                .collect::<Vec<_>>()
+                .join("&");
+            headers.push(("x-amz-tagging".to_string(), tagging));
         }
+        headers
+    }
+
+    /// The current credentials, loading them if they have not been loaded yet or
+    /// are about to expire.
+    async fn credentials(&self) -> Result<Arc<LoadedCredentials>> {
+        let seen = self.credentials.lock().unwrap().clone();
+        if let Some(seen) = &seen {
+            if !seen.expires_within(CREDENTIALS_REFRESH_MARGIN) {
+                return Ok(seen.clone());
+            }
+        }
+
+        let _refreshing = self.refreshing.lock().await;
+        let current = self.credentials.lock().unwrap().clone();
+        if let Some(current) = &current {
+            // Use credentials that another request loaded while this one waited.
+            let refreshed = match &seen {
+                Some(seen) => !Arc::ptr_eq(seen, current),
+                None => true,
+            };
+            if refreshed || !current.expires_within(CREDENTIALS_REFRESH_MARGIN) {
+                return Ok(current.clone());
+            }
+        }
+
+        let loaded = match self.credentials_provider.load(&self.client).await {
+            Ok(loaded) => loaded,
+            // Keep using credentials that have not expired yet, in case the
+            // provider recovers before they do.
+            Err(error) => match current {
+                Some(current) if !current.expires_within(Duration::ZERO) => {
+                    tracing::warn!(?error, "Failed to refresh AWS credentials.");
+                    return Ok(current);
+                }
+                _ => return Err(error),
+            },
+        };
+        let credentials = match loaded.token {
+            Some(token) => Credentials::new_with_token(loaded.key, loaded.secret, token),
+            None => Credentials::new(loaded.key, loaded.secret),
+        };
+        let loaded = Arc::new(LoadedCredentials {
+            credentials,
+            expiration: loaded.expiration,
+        });
+        *self.credentials.lock().unwrap() = Some(loaded.clone());
+        Ok(loaded)
     }
 
     async fn store_request<'a, A: S3Action<'a>>(
         &self,
         method: Method,
-        action: A,
-        body: Option<Vec<u8>>,
+        mut action: A,
+        body: Option<Bytes>,
     ) -> Result<Response> {
         let url = action.sign_with_time(PRESIGNED_URL_DURATION, &OffsetDateTime::now_utc());
-        let mut request = self.client.request(method, url);
 
-        request = if let Some(body) = body {
-            request.body(body.to_vec())
-        } else {
-            request
+        // Headers set on the action are part of the signature, so they must be sent as-is.
+        let headers: Vec<(String, String)> = action
+            .headers_mut()
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        // A conditional write that reached S3 but whose response was lost would fail its
+        // precondition when retried, so only unconditional requests are retried after
+        // errors that may have happened after S3 applied the request. The only POSTs are
+        // creating a multipart upload, where a retry at worst leaves an unused upload
+        // behind, and completing one, which S3 accepts again for the same parts.
+        let conditional = headers
+            .iter()
+            .any(|(name, _)| name == "if-match" || name == "if-none-match");
+        let idempotent = match method {
+            Method::GET | Method::HEAD | Method::DELETE => true,
+            Method::PUT | Method::POST => !conditional,
+            _ => false,
         };
 
-        let response = request.send().await;
+        let mut attempt = 0;
+        loop {
+            let failure = match self
+                .send_request(method.clone(), url.clone(), &headers, body.clone())
+                .await
+            {
+                Ok(response) => return Ok(response),
+                Err(failure) => failure,
+            };
+
+            let retry = match &failure {
+                // Throttled requests were not processed, so they are always safe to retry.
+                RequestFailure::Transient(StoreError::Throttled(_)) => true,
+                RequestFailure::Transient(_) => idempotent,
+                RequestFailure::Permanent(_) => false,
+            };
+            let error = failure.into_error();
+            if !retry || attempt >= self.max_retries {
+                return Err(error);
+            }
+
+            let delay = backoff_delay(attempt);
+            tracing::warn!(?error, attempt, ?delay, "Retrying S3 request.");
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    async fn send_request(
+        &self,
+        method: Method,
+        url: Url,
+        headers: &[(String, String)],
+        body: Option<Bytes>,
+    ) -> std::result::Result<Response, RequestFailure> {
+        let mut request = self.client.request(method, url);
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            request = request.timeout(self.request_timeout);
+        }
 
-        let response = match response {
+        let response = match request.send().await {
             Ok(response) => response,
-            Err(e) => return Err(StoreError::ConnectionError(e.to_string())),
+            Err(e) if e.is_timeout() => {
+                return Err(RequestFailure::Transient(StoreError::ConnectionError(
+                    format!("Request to S3-compatible API timed out. {e}"),
+                )))
+            }
+            Err(e) => {
+                return Err(RequestFailure::Transient(StoreError::ConnectionError(
+                    e.to_string(),
+                )))
+            }
         };
 
         match response.status() {
-            StatusCode::OK => Ok(response),
-            StatusCode::NOT_FOUND => Err(StoreError::DoesNotExist(
+            // DELETE responds with 204 No Content.
+            status if status.is_success() => Ok(response),
+            StatusCode::NOT_FOUND => Err(RequestFailure::Permanent(StoreError::DoesNotExist(
                 "Received NOT_FOUND from S3-compatible API.".to_string(),
-            )),
-            StatusCode::FORBIDDEN => Err(StoreError::NotAuthorized(
+            ))),
+            StatusCode::FORBIDDEN => Err(RequestFailure::Permanent(StoreError::NotAuthorized(
                 "Received FORBIDDEN from S3-compatible API.".to_string(),
-            )),
-            StatusCode::UNAUTHORIZED => Err(StoreError::NotAuthorized(
+            ))),
+            StatusCode::UNAUTHORIZED => Err(RequestFailure::Permanent(StoreError::NotAuthorized(
                 "Received UNAUTHORIZED from S3-compatible API.".to_string(),
+            ))),
+            StatusCode::PRECONDITION_FAILED | StatusCode::CONFLICT => {
+                Err(RequestFailure::Permanent(StoreError::Conflict(format!(
+                    "Received {} from S3-compatible API.",
+                    response.status()
+                ))))
+            }
+            // S3 responds with 503 SlowDown when throttling; other S3-compatible APIs use 429.
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+                Err(RequestFailure::Transient(StoreError::Throttled(format!(
+                    "Received {} from S3-compatible API.",
+                    response.status()
+                ))))
+            }
+            status if status.is_server_error() => Err(RequestFailure::Transient(
+                StoreError::ConnectionError(format!("Received {} from S3-compatible API.", status)),
             )),
-            _ => Err(StoreError::ConnectionError(format!(
-                "Received {} from S3-compatible API.",
-                response.status()
+            status => Err(RequestFailure::Permanent(StoreError::ConnectionError(
+                format!("Received {} from S3-compatible API.", status),
             ))),
         }
     }
 
-    async fn read_response_bytes(response: Response) -> Result<Bytes> {
-        match response.bytes().await {
-            Ok(bytes) => Ok(bytes),
-            Err(e) => Err(StoreError::ConnectionError(e.to_string())),
+    /// Read a whole response body into a buffer sized from its `Content-Length`.
+    /// Objects are still read fully into memory; this only avoids growing the
+    /// buffer, and the copy `Response::bytes` would make, for large objects.
+    async fn read_response_body(mut response: Response) -> Result<Vec<u8>> {
+        let mut body = Vec::with_capacity(response.content_length().unwrap_or_default() as usize);
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| StoreError::ConnectionError(e.to_string()))?
+        {
+            body.extend_from_slice(&chunk);
         }
+        Ok(body)
     }
 
     pub async fn init(&self) -> Result<()> {
@@ -118,7 +598,8 @@ impl S3Store {
             return Ok(());
         }
 
-        let action = self.bucket.head_bucket(Some(&self.credentials));
+        let credentials = self.credentials().await?;
+        let action = self.bucket.head_bucket(Some(&credentials.credentials));
         let result = self.store_request(Method::HEAD, action, None).await;
 
         match result {
@@ -149,15 +630,48 @@ impl S3Store {
     async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
         self.init().await?;
         let prefixed_key = self.prefixed_key(key);
-        let object_get = self
+        let credentials = self.credentials().await?;
+        let mut object_get = self
+            .bucket
+            .get_object(Some(&credentials.credentials), &prefixed_key);
+        insert_headers(&mut object_get, &self.customer_key_headers);
+        let response = self.store_request(Method::GET, object_get, None).await;
+
+        match response {
+            Ok(response) => Ok(Some(Self::read_response_body(response).await?)),
+            Err(StoreError::DoesNotExist(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn response_version(response: &Response) -> Result<ObjectVersion> {
+        response
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(|etag| ObjectVersion(etag.to_string()))
+            .ok_or_else(|| {
+                StoreError::ConnectionError(
+                    "Expected ETag header from S3-compatible API.".to_string(),
+                )
+            })
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        self.init().await?;
+        let prefixed_key = self.prefixed_key(key);
+        let credentials = self.credentials().await?;
+        let mut object_get = self
             .bucket
-            .get_object(Some(&self.credentials), &prefixed_key);
+            .get_object(Some(&credentials.credentials), &prefixed_key);
+        insert_headers(&mut object_get, &self.customer_key_headers);
         let response = self.store_request(Method::GET, object_get, None).await;
 
         match response {
             Ok(response) => {
-                let result = Self::read_response_bytes(response).await?;
-                Ok(Some(result.to_vec()))
+                let version = Self::response_version(&response)?;
+                let result = Self::read_response_body(response).await?;
+                Ok(Some((result, version)))
             }
             Err(StoreError::DoesNotExist(_)) => Ok(None),
             Err(e) => Err(e),
@@ -166,30 +680,202 @@ impl S3Store {
 
     async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
         self.init().await?;
+        self.put_object(key, value.into(), None).await?;
+        Ok(())
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        self.init().await?;
+        let condition = match expected {
+            Some(ObjectVersion(etag)) => ("if-match", etag.as_str()),
+            None => ("if-none-match", "*"),
+        };
+        self.put_object(key, value.into(), Some(condition)).await
+    }
+
+    /// Write an object, using a multipart upload if it is above the threshold.
+    /// `condition` is a conditional request header to apply to the write.
+    async fn put_object(
+        &self,
+        key: &str,
+        value: Bytes,
+        condition: Option<(&'static str, &str)>,
+    ) -> Result<ObjectVersion> {
         let prefixed_key = self.prefixed_key(key);
-        let action = self
+        let headers = self.object_headers(key);
+        if value.len() > self.multipart_threshold {
+            return self
+                .put_multipart(&prefixed_key, &headers, value, condition)
+                .await;
+        }
+
+        let credentials = self.credentials().await?;
+        let mut action = self
             .bucket
-            .put_object(Some(&self.credentials), &prefixed_key);
-        self.store_request(Method::PUT, action, Some(value)).await?;
+            .put_object(Some(&credentials.credentials), &prefixed_key);
+        insert_headers(&mut action, &headers);
+        if let Some((name, value)) = condition {
+            action.headers_mut().insert(name, value);
+        }
+        let response = self.store_request(Method::PUT, action, Some(value)).await?;
+        Self::response_version(&response)
+    }
+
+    async fn put_multipart(
+        &self,
+        prefixed_key: &str,
+        headers: &[(String, String)],
+        value: Bytes,
+        condition: Option<(&'static str, &str)>,
+    ) -> Result<ObjectVersion> {
+        let credentials = self.credentials().await?;
+        let mut action = self
+            .bucket
+            .create_multipart_upload(Some(&credentials.credentials), prefixed_key);
+        insert_headers(&mut action, headers);
+        let response = self.store_request(Method::POST, action, None).await?;
+        let body = Self::read_response_body(response).await?;
+        let upload_id = std::str::from_utf8(&body)
+            .ok()
+            .and_then(|body| CreateMultipartUpload::parse_response(body).ok())
+            .ok_or_else(|| {
+                StoreError::ConnectionError("Invalid create multipart upload response.".to_string())
+            })?
+            .upload_id()
+            .to_string();
+
+        let result = self
+            .upload_parts(prefixed_key, &upload_id, value, condition)
+            .await;
+
+        if result.is_err() {
+            if let Err(e) = self.abort_multipart(prefixed_key, &upload_id).await {
+                tracing::warn!(?e, upload_id, "Failed to abort multipart upload.");
+            }
+        }
+
+        result
+    }
+
+    async fn abort_multipart(&self, prefixed_key: &str, upload_id: &str) -> Result<()> {
+        let credentials = self.credentials().await?;
+        let action = self.bucket.abort_multipart_upload(
+            Some(&credentials.credentials),
+            prefixed_key,
+            upload_id,
+        );
+        self.store_request(Method::DELETE, action, None).await?;
         Ok(())
     }
 
+    async fn upload_parts(
+        &self,
+        prefixed_key: &str,
+        upload_id: &str,
+        value: Bytes,
+        condition: Option<(&'static str, &str)>,
+    ) -> Result<ObjectVersion> {
+        let part_size = self
+            .multipart_part_size
+            .max(value.len().div_ceil(MAX_MULTIPART_PARTS));
+
+        let mut etags = Vec::new();
+        for (i, start) in (0..value.len()).step_by(part_size).enumerate() {
+            let part = value.slice(start..(start + part_size).min(value.len()));
+            let credentials = self.credentials().await?;
+            let mut action = self.bucket.upload_part(
+                Some(&credentials.credentials),
+                prefixed_key,
+                i as u16 + 1,
+                upload_id,
+            );
+            insert_headers(&mut action, &self.customer_key_headers);
+            let response = self.store_request(Method::PUT, action, Some(part)).await?;
+            etags.push(Self::response_version(&response)?.0);
+        }
+
+        let credentials = self.credentials().await?;
+        let mut action = self.bucket.complete_multipart_upload(
+            Some(&credentials.credentials),
+            prefixed_key,
+            upload_id,
+            etags.iter().map(String::as_str),
+        );
+        if let Some((name, value)) = condition {
+            action.headers_mut().insert(name, value);
+        }
+        let body = action.clone().body();
+        let response = self
+            .store_request(Method::POST, action, Some(body.into()))
+            .await?;
+        let body = Self::read_response_body(response).await?;
+        Self::complete_response_version(&String::from_utf8_lossy(&body))
+    }
+
+    /// Extract the ETag from a `CompleteMultipartUpload` response body. S3 may
+    /// report a failed completion with a 200 status and an `Error` body.
+    fn complete_response_version(body: &str) -> Result<ObjectVersion> {
+        if let Ok(error) = quick_xml::de::from_str::<S3ErrorResponse>(body) {
+            if error.code == "PreconditionFailed" {
+                return Err(StoreError::Conflict(
+                    "Multipart upload precondition failed.".to_string(),
+                ));
+            }
+            return Err(StoreError::ConnectionError(format!(
+                "Failed to complete multipart upload: {}",
+                error.message.unwrap_or(error.code)
+            )));
+        }
+
+        quick_xml::de::from_str::<CompleteMultipartUploadResult>(body)
+            .map(|result| ObjectVersion(result.etag))
+            .map_err(|e| {
+                StoreError::ConnectionError(format!(
+                    "Invalid complete multipart upload response: {}",
+                    e
+                ))
+            })
+    }
+
     async fn remove(&self, key: &str) -> Result<()> {
         self.init().await?;
         let prefixed_key = self.prefixed_key(key);
+        let credentials = self.credentials().await?;
         let action = self
             .bucket
-            .delete_object(Some(&self.credentials), &prefixed_key);
+            .delete_object(Some(&credentials.credentials), &prefixed_key);
         self.store_request(Method::DELETE, action, None).await?;
         Ok(())
     }
 
+    async fn get_version(&self, key: &str) -> Result<Option<ObjectVersion>> {
+        self.init().await?;
+        let prefixed_key = self.prefixed_key(key);
+        let credentials = self.credentials().await?;
+        let mut action = self
+            .bucket
+            .head_object(Some(&credentials.credentials), &prefixed_key);
+        insert_headers(&mut action, &self.customer_key_headers);
+        match self.store_request(Method::HEAD, action, None).await {
+            Ok(response) => Ok(Some(Self::response_version(&response)?)),
+            Err(StoreError::DoesNotExist(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
     async fn exists(&self, key: &str) -> Result<bool> {
         self.init().await?;
         let prefixed_key = self.prefixed_key(key);
-        let action = self
+        let credentials = self.credentials().await?;
+        let mut action = self
             .bucket
-            .head_object(Some(&self.credentials), &prefixed_key);
+            .head_object(Some(&credentials.credentials), &prefixed_key);
+        insert_headers(&mut action, &self.customer_key_headers);
         let response = self.store_request(Method::HEAD, action, None).await;
         match response {
             Ok(_) => Ok(true),
@@ -197,6 +883,51 @@ impl S3Store {
             Err(e) => Err(e),
         }
     }
+
+    async fn list(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<ListResult> {
+        self.init().await?;
+        let prefixed_prefix = self.prefixed_key(prefix);
+        let credentials = self.credentials().await?;
+        let mut action = self.bucket.list_objects_v2(Some(&credentials.credentials));
+        action.with_prefix(prefixed_prefix.as_str());
+        if let Some(cursor) = cursor {
+            action.with_start_after(self.prefixed_key(cursor));
+        }
+        action.with_max_keys(limit);
+
+        let response = self.store_request(Method::GET, action, None).await?;
+        let body = Self::read_response_body(response).await?;
+        let body = std::str::from_utf8(&body)
+            .map_err(|e| StoreError::ConnectionError(format!("Invalid list response. {e}")))?;
+        let parsed = ListObjectsV2::parse_response(body)
+            .map_err(|e| StoreError::ConnectionError(format!("Invalid list response. {e}")))?;
+
+        let mut keys = Vec::with_capacity(parsed.contents.len());
+        for object in parsed.contents {
+            // We request keys with encoding-type=url, so they need to be decoded.
+            let key = percent_decode_str(&object.key)
+                .decode_utf8()
+                .map_err(|e| StoreError::ConnectionError(format!("Invalid key in list. {e}")))?;
+            let key = match &self.prefix {
+                Some(path_prefix) => key
+                    .strip_prefix(path_prefix.as_str())
+                    .and_then(|key| key.strip_prefix('/'))
+                    .map(str::to_string),
+                None => Some(key.into_owned()),
+            };
+            if let Some(key) = key {
+                keys.push(key);
+            }
+        }
+
+        let next_cursor = if parsed.next_continuation_token.is_some() {
+            keys.last().cloned()
+        } else {
+            None
+        };
+
+        Ok(ListResult { keys, next_cursor })
+    }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -214,6 +945,23 @@ impl Store for S3Store {
         self.set(key, value).await
     }
 
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        self.get_versioned(key).await
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<ObjectVersion>> {
+        self.get_version(key).await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        self.set_if(key, value, expected).await
+    }
+
     async fn remove(&self, key: &str) -> Result<()> {
         self.remove(key).await
     }
@@ -221,6 +969,10 @@ impl Store for S3Store {
     async fn exists(&self, key: &str) -> Result<bool> {
         self.exists(key).await
     }
+
+    async fn list(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<ListResult> {
+        self.list(prefix, cursor, limit).await
+    }
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -238,6 +990,23 @@ impl Store for S3Store {
         self.set(key, value).await
     }
 
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        self.get_versioned(key).await
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<ObjectVersion>> {
+        self.get_version(key).await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        self.set_if(key, value, expected).await
+    }
+
     async fn remove(&self, key: &str) -> Result<()> {
         self.remove(key).await
     }
@@ -245,4 +1014,465 @@ impl Store for S3Store {
     async fn exists(&self, key: &str) -> Result<bool> {
         self.exists(key).await
     }
+
+    async fn list(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<ListResult> {
+        self.list(prefix, cursor, limit).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::credentials::test::fake_metadata_server;
+    use super::*;
+    use axum::{
+        body::Bytes,
+        extract::{DefaultBodyLimit, State},
+        http::{HeaderMap, Method, StatusCode, Uri},
+        response::{IntoResponse, Response},
+        Router,
+    };
+    use std::{
+        collections::{BTreeMap, HashMap},
+        sync::{Arc, Mutex},
+    };
+
+    /// A minimal in-memory imitation of the parts of the S3 API that `S3Store` uses.
+    #[derive(Default)]
+    struct FakeS3 {
+        objects: HashMap<String, (Vec<u8>, String)>,
+        uploads: HashMap<String, BTreeMap<u16, Vec<u8>>>,
+        next_id: u64,
+        multipart_uploads: usize,
+        /// Statuses to respond with, in order, before handling requests normally.
+        failures: Vec<StatusCode>,
+        /// Statuses to respond to multipart upload completions with, in order.
+        completion_failures: Vec<StatusCode>,
+        requests: usize,
+        /// The access key that the last request was signed with.
+        access_key: String,
+        /// The headers of the last request.
+        headers: HeaderMap,
+    }
+
+    impl FakeS3 {
+        fn next_etag(&mut self) -> String {
+            self.next_id += 1;
+            format!("\"{}\"", self.next_id)
+        }
+
+        fn precondition_failed(&self, key: &str, headers: &HeaderMap) -> bool {
+            let current = self.objects.get(key).map(|(_, etag)| etag.as_str());
+            if headers.get("if-none-match").is_some() && current.is_some() {
+                return true;
+            }
+            if let Some(expected) = headers.get("if-match") {
+                return current != expected.to_str().ok();
+            }
+            false
+        }
+    }
+
+    async fn handle(
+        State(s3): State<Arc<Mutex<FakeS3>>>,
+        method: Method,
+        uri: Uri,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Response {
+        let mut s3 = s3.lock().unwrap();
+        s3.requests += 1;
+        s3.headers = headers.clone();
+        if let Some((_, credential)) = uri
+            .query()
+            .unwrap_or_default()
+            .split_once("X-Amz-Credential=")
+        {
+            s3.access_key = credential.split("%2F").next().unwrap().to_string();
+        }
+        if !s3.failures.is_empty() {
+            return s3.failures.remove(0).into_response();
+        }
+        let path = uri.path().trim_start_matches('/');
+        let Some((_bucket, key)) = path.split_once('/').filter(|(_, key)| !key.is_empty()) else {
+            // Bucket-level request; the only one we expect is HEAD.
+            return StatusCode::OK.into_response();
+        };
+        let key = key.to_string();
+        let query: HashMap<String, String> = uri
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (name.to_string(), value.to_string())
+            })
+            .collect();
+        let upload_id = query.get("uploadId").cloned();
+
+        match (method, upload_id) {
+            (Method::POST, None) if query.contains_key("uploads") => {
+                let upload_id = format!("upload-{}", s3.next_id);
+                s3.next_id += 1;
+                s3.uploads.insert(upload_id.clone(), BTreeMap::new());
+                format!(
+                    "<InitiateMultipartUploadResult><Bucket>bucket</Bucket><Key>{}</Key>\
+                     <UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                    key, upload_id
+                )
+                .into_response()
+            }
+            (Method::PUT, Some(upload_id)) => {
+                let part_number: u16 = query["partNumber"].parse().unwrap();
+                let etag = s3.next_etag();
+                let Some(parts) = s3.uploads.get_mut(&upload_id) else {
+                    return StatusCode::NOT_FOUND.into_response();
+                };
+                parts.insert(part_number, body.to_vec());
+                ([("etag", etag)], "").into_response()
+            }
+            (Method::POST, Some(upload_id)) => {
+                if !s3.completion_failures.is_empty() {
+                    return s3.completion_failures.remove(0).into_response();
+                }
+                if s3.precondition_failed(&key, &headers) {
+                    return (
+                        StatusCode::PRECONDITION_FAILED,
+                        "<Error><Code>PreconditionFailed</Code></Error>",
+                    )
+                        .into_response();
+                }
+                let Some(parts) = s3.uploads.remove(&upload_id) else {
+                    return StatusCode::NOT_FOUND.into_response();
+                };
+                let last = parts.len().saturating_sub(1);
+                if parts
+                    .values()
+                    .take(last)
+                    .any(|part| part.len() < MIN_MULTIPART_PART_SIZE)
+                {
+                    return "<Error><Code>EntityTooSmall</Code></Error>".into_response();
+                }
+                let data = parts.into_values().flatten().collect();
+                let etag = s3.next_etag();
+                s3.objects.insert(key, (data, etag.clone()));
+                s3.multipart_uploads += 1;
+                format!(
+                    "<CompleteMultipartUploadResult><ETag>{}</ETag></CompleteMultipartUploadResult>",
+                    etag.replace('"', "&quot;")
+                )
+                .into_response()
+            }
+            (Method::DELETE, Some(upload_id)) => {
+                s3.uploads.remove(&upload_id);
+                StatusCode::NO_CONTENT.into_response()
+            }
+            (Method::PUT, None) => {
+                if s3.precondition_failed(&key, &headers) {
+                    return StatusCode::PRECONDITION_FAILED.into_response();
+                }
+                let etag = s3.next_etag();
+                s3.objects.insert(key, (body.to_vec(), etag.clone()));
+                ([("etag", etag)], "").into_response()
+            }
+            (Method::GET, None) | (Method::HEAD, None) => match s3.objects.get(&key) {
+                Some((data, etag)) => ([("etag", etag.clone())], data.clone()).into_response(),
+                None => StatusCode::NOT_FOUND.into_response(),
+            },
+            (Method::DELETE, None) => {
+                s3.objects.remove(&key);
+                StatusCode::NO_CONTENT.into_response()
+            }
+            _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+        }
+    }
+
+    async fn fake_s3() -> (S3Store, Arc<Mutex<FakeS3>>) {
+        let credentials_provider = CredentialsProvider::Static(AwsCredentials {
+            key: "key".to_string(),
+            secret: "secret".to_string(),
+            token: None,
+            expiration: None,
+        });
+        fake_s3_with(credentials_provider, |_| {}).await
+    }
+
+    async fn fake_s3_with(
+        credentials_provider: CredentialsProvider,
+        configure: impl FnOnce(&mut S3Config),
+    ) -> (S3Store, Arc<Mutex<FakeS3>>) {
+        let s3 = Arc::new(Mutex::new(FakeS3::default()));
+        let app = Router::new()
+            .fallback(handle)
+            .layer(DefaultBodyLimit::disable())
+            .with_state(s3.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let mut config = S3Config {
+            key: None,
+            endpoint: format!("http://{}", addr),
+            secret: None,
+            token: None,
+            bucket: "bucket".to_string(),
+            region: "us-east-1".to_string(),
+            bucket_prefix: None,
+            path_style: true,
+            multipart_threshold: 1024,
+            multipart_part_size: 0,
+            max_retries: 2,
+            request_timeout_secs: 5,
+            server_side_encryption: None,
+            storage_class: None,
+            metadata: BTreeMap::new(),
+            tags: BTreeMap::new(),
+        };
+        configure(&mut config);
+        let store = S3Store::with_credentials_provider(config, credentials_provider).unwrap();
+        (store, s3)
+    }
+
+    fn large_value() -> Vec<u8> {
+        (0..2 * MIN_MULTIPART_PART_SIZE + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn uses_multipart_above_threshold() {
+        let (store, s3) = fake_s3().await;
+
+        store.set("small", b"hello".to_vec()).await.unwrap();
+        assert_eq!(store.get("small").await.unwrap().unwrap(), b"hello");
+        assert_eq!(s3.lock().unwrap().multipart_uploads, 0);
+
+        let value = large_value();
+        store.set("large", value.clone()).await.unwrap();
+        assert_eq!(s3.lock().unwrap().multipart_uploads, 1);
+        assert_eq!(store.get("large").await.unwrap().unwrap(), value);
+
+        let (_, version) = store.get_versioned("large").await.unwrap().unwrap();
+        let (_, etag) = s3.lock().unwrap().objects["large"].clone();
+        assert_eq!(version, ObjectVersion(etag));
+    }
+
+    #[tokio::test]
+    async fn conditional_multipart_write_conflicts() {
+        let (store, s3) = fake_s3().await;
+        let value = large_value();
+
+        let version = store.set_if("doc", value.clone(), None).await.unwrap();
+        assert!(matches!(
+            store.set_if("doc", value.clone(), None).await,
+            Err(StoreError::Conflict(_))
+        ));
+
+        let next = store
+            .set_if("doc", value.clone(), Some(&version))
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.set_if("doc", value, Some(&version)).await,
+            Err(StoreError::Conflict(_))
+        ));
+        assert_ne!(version, next);
+
+        // Failed uploads are aborted rather than left behind.
+        assert!(s3.lock().unwrap().uploads.is_empty());
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures() {
+        let (store, s3) = fake_s3().await;
+        store.init().await.unwrap();
+        store.set("doc", b"hello".to_vec()).await.unwrap();
+
+        s3.lock().unwrap().failures = vec![StatusCode::SERVICE_UNAVAILABLE];
+        s3.lock().unwrap().requests = 0;
+        assert_eq!(store.get("doc").await.unwrap().unwrap(), b"hello");
+        assert_eq!(s3.lock().unwrap().requests, 2);
+
+        s3.lock().unwrap().failures = vec![StatusCode::INTERNAL_SERVER_ERROR; 3];
+        assert!(matches!(
+            store.get("doc").await,
+            Err(StoreError::ConnectionError(_))
+        ));
+
+        s3.lock().unwrap().failures = vec![StatusCode::TOO_MANY_REQUESTS; 3];
+        assert!(matches!(
+            store.get("doc").await,
+            Err(StoreError::Throttled(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_conditional_writes_after_server_errors() {
+        let (store, s3) = fake_s3().await;
+        store.init().await.unwrap();
+
+        s3.lock().unwrap().failures = vec![StatusCode::INTERNAL_SERVER_ERROR];
+        s3.lock().unwrap().requests = 0;
+        assert!(matches!(
+            store.set_if("doc", b"hello".to_vec(), None).await,
+            Err(StoreError::ConnectionError(_))
+        ));
+        assert_eq!(s3.lock().unwrap().requests, 1);
+
+        // Throttled requests were not applied, so they are retried even when conditional.
+        s3.lock().unwrap().failures = vec![StatusCode::SERVICE_UNAVAILABLE];
+        store.set_if("doc", b"hello".to_vec(), None).await.unwrap();
+        assert_eq!(s3.lock().unwrap().requests, 3);
+    }
+
+    #[tokio::test]
+    async fn retries_unconditional_multipart_requests() {
+        let (store, s3) = fake_s3().await;
+        store.init().await.unwrap();
+        let value = large_value();
+
+        // Creating the upload fails once, and so does completing it.
+        s3.lock().unwrap().failures = vec![StatusCode::INTERNAL_SERVER_ERROR];
+        s3.lock().unwrap().completion_failures = vec![StatusCode::INTERNAL_SERVER_ERROR];
+        store.set("large", value.clone()).await.unwrap();
+        assert_eq!(s3.lock().unwrap().multipart_uploads, 1);
+        assert_eq!(store.get("large").await.unwrap().unwrap(), value);
+
+        // A conditional completion is not retried, and the upload is aborted.
+        s3.lock().unwrap().completion_failures = vec![StatusCode::INTERNAL_SERVER_ERROR];
+        assert!(matches!(
+            store.set_if("other", value, None).await,
+            Err(StoreError::ConnectionError(_))
+        ));
+        assert_eq!(s3.lock().unwrap().multipart_uploads, 1);
+        assert!(s3.lock().unwrap().uploads.is_empty());
+    }
+
+    #[tokio::test]
+    async fn refreshes_expiring_credentials() {
+        let (endpoint, metadata) = fake_metadata_server().await;
+        let (store, s3) =
+            fake_s3_with(CredentialsProvider::InstanceMetadata { endpoint }, |_| {}).await;
+
+        // Credentials that expire within the refresh margin are replaced on every use.
+        metadata.lock().unwrap().expiration = OffsetDateTime::now_utc() + Duration::from_secs(60);
+        store.set("doc", b"hello".to_vec()).await.unwrap();
+        assert_eq!(s3.lock().unwrap().access_key, "role-key-2");
+        store.get("doc").await.unwrap();
+        assert_eq!(s3.lock().unwrap().access_key, "role-key-3");
+
+        // Once they are valid for longer, they are reused.
+        metadata.lock().unwrap().expiration =
+            OffsetDateTime::now_utc() + Duration::from_secs(60 * 60);
+        store.get("doc").await.unwrap();
+        store.get("doc").await.unwrap();
+        assert_eq!(s3.lock().unwrap().access_key, "role-key-4");
+        assert_eq!(metadata.lock().unwrap().loads, 4);
+
+        // Concurrent requests with expiring credentials load them once.
+        metadata.lock().unwrap().expiration = OffsetDateTime::now_utc() + Duration::from_secs(60);
+        *store.credentials.lock().unwrap() = None;
+        futures::future::join_all((0..10).map(|_| store.get("doc"))).await;
+        assert_eq!(metadata.lock().unwrap().loads, 5);
+    }
+
+    #[tokio::test]
+    async fn sends_encryption_and_metadata_headers() {
+        let (store, s3) = fake_s3_with(
+            CredentialsProvider::Static(AwsCredentials {
+                key: "key".to_string(),
+                secret: "secret".to_string(),
+                token: None,
+                expiration: None,
+            }),
+            |config| {
+                config.server_side_encryption = Some(ServerSideEncryption::Kms {
+                    key_id: Some("my-key".to_string()),
+                });
+                config.storage_class = Some("STANDARD_IA".to_string());
+                config.metadata = parse_key_value_pairs("doc-id={doc_id}").unwrap();
+                config.tags = parse_key_value_pairs("app=y sweet, doc={doc_id}").unwrap();
+            },
+        )
+        .await;
+
+        store
+            .set("doc1/data.ysweet", b"hello".to_vec())
+            .await
+            .unwrap();
+        let headers = s3.lock().unwrap().headers.clone();
+        assert_eq!(headers["x-amz-server-side-encryption"], "aws:kms");
+        assert_eq!(
+            headers["x-amz-server-side-encryption-aws-kms-key-id"],
+            "my-key"
+        );
+        assert_eq!(headers["x-amz-storage-class"], "STANDARD_IA");
+        assert_eq!(headers["x-amz-meta-doc-id"], "doc1");
+        assert_eq!(headers["x-amz-tagging"], "app=y%20sweet&doc=doc1");
+
+        // Reads don't carry write-only headers.
+        store.get("doc1/data.ysweet").await.unwrap();
+        assert!(s3
+            .lock()
+            .unwrap()
+            .headers
+            .get("x-amz-storage-class")
+            .is_none());
+    }
+
+    #[test]
+    fn parses_complete_multipart_responses() {
+        let version = S3Store::complete_response_version(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+             <CompleteMultipartUploadResult><Location>x</Location>\
+             <ETag>&quot;abc-2&quot;</ETag></CompleteMultipartUploadResult>",
+        )
+        .unwrap();
+        assert_eq!(version, ObjectVersion("\"abc-2\"".to_string()));
+
+        assert!(matches!(
+            S3Store::complete_response_version(
+                "<Error><Code>PreconditionFailed</Code><Message>m</Message></Error>"
+            ),
+            Err(StoreError::Conflict(_))
+        ));
+        assert!(matches!(
+            S3Store::complete_response_version("<Error><Code>InternalError</Code></Error>"),
+            Err(StoreError::ConnectionError(_))
+        ));
+    }
+
+    #[test]
+    fn customer_keys_are_sent_with_their_digest() {
+        assert!(ServerSideEncryption::parse("sse-c", Some("c2hvcnQ=".to_string())).is_err());
+        assert!(ServerSideEncryption::parse("sse-x", None).is_err());
+
+        let key = BASE64.encode(&[7; 32]);
+        let sse = ServerSideEncryption::parse("sse-c", Some(key.clone())).unwrap();
+        let headers: BTreeMap<_, _> = sse.customer_key_headers().unwrap().into_iter().collect();
+        assert_eq!(headers["x-amz-server-side-encryption-customer-key"], key);
+        assert_eq!(
+            headers["x-amz-server-side-encryption-customer-key-md5"],
+            BASE64.encode(&Md5::digest([7; 32]))
+        );
+        assert_eq!(sse.creation_headers().unwrap().len(), 3);
+
+        // A deserialized config is not validated by `parse`, so the store checks it.
+        let config: S3Config = serde_json::from_value(serde_json::json!({
+            "key": null,
+            "secret": null,
+            "token": null,
+            "endpoint": "http://localhost:9000",
+            "region": "us-east-1",
+            "bucket": "bucket",
+            "bucket_prefix": null,
+            "path_style": true,
+            "server_side_encryption": {"mode": "customer", "key": "not base64!"},
+        }))
+        .unwrap();
+        assert!(matches!(
+            S3Store::new(config),
+            Err(StoreError::EncryptionError(_))
+        ));
+    }
 }
@@ -1,22 +1,76 @@
-use crate::store::Store;
-use anyhow::{Context, Result};
+use crate::{
+    compaction::Compaction,
+    doc_connection::DOC_NAME,
+    history::{list_versions, now_millis, record_version, HistoryOptions},
+    quarantine::{recover, RecoveryPolicy},
+    snapshot::{decode_snapshot, encode_snapshot, SnapshotCompression},
+    store::{ObjectVersion, Store, StoreError},
+    update_log::{list_segments, segment_key, UpdateLogOptions},
+};
+use anyhow::{anyhow, Context, Result};
 use std::{
     collections::BTreeMap,
     convert::Infallible,
     ops::Bound,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
     },
 };
+use yrs::{Doc, ReadTxn, StateVector, Transact};
 use yrs_kvstore::{DocOps, KVEntry};
 
+/// How many times to merge a concurrently-written snapshot and retry before giving up.
+const MAX_PERSIST_ATTEMPTS: usize = 5;
+
+#[cfg(not(feature = "sync"))]
+type RemoteUpdateCallback = Box<dyn Fn(&[u8]) + 'static>;
+
+#[cfg(feature = "sync")]
+type RemoteUpdateCallback = Box<dyn Fn(&[u8]) + 'static + Send + Sync>;
+
+/// Options controlling how a `SyncKv` persists snapshots to its store.
+#[derive(Clone, Debug, Default)]
+pub struct PersistenceOptions {
+    pub compression: SnapshotCompression,
+    /// If set, keep historical versions of the snapshot alongside it.
+    pub history: Option<HistoryOptions>,
+    /// If set, persist incremental updates to a log instead of writing the whole
+    /// snapshot on every checkpoint.
+    pub update_log: Option<UpdateLogOptions>,
+    /// What to do when the snapshot cannot be decoded.
+    pub recovery: RecoveryPolicy,
+}
+
+#[derive(Default)]
+struct LogState {
+    /// Updates made since they were last written to the log.
+    pending: Vec<Vec<u8>>,
+    /// Log segments included in `data` that have not been compacted yet.
+    segments: Vec<u64>,
+    /// The sequence number of the next segment to write.
+    next_seq: u64,
+    /// Whether the log is being compacted.
+    compacting: bool,
+}
+
 pub struct SyncKv {
     data: Arc<Mutex<BTreeMap<Vec<u8>, Vec<u8>>>>,
+    /// The size of `data`, as returned by `size`. Updated along with `data`, so
+    /// that checking it on every update is cheap.
+    size: AtomicUsize,
     store: Option<Arc<Box<dyn Store>>>,
+    doc_id: String,
     key: String,
+    options: PersistenceOptions,
     dirty: AtomicBool,
     dirty_callback: Box<dyn Fn() + Send + Sync>,
+    /// The version of the snapshot in the store that `data` is based on.
+    version: Mutex<Option<ObjectVersion>>,
+    remote_update_callback: OnceLock<RemoteUpdateCallback>,
+    /// When the most recent historical version was written, if known.
+    last_version_at: Mutex<Option<u64>>,
+    log: Mutex<LogState>,
 }
 
 impl SyncKv {
@@ -25,26 +79,110 @@ impl SyncKv {
         key: &str,
         callback: Callback,
     ) -> Result<Self> {
-        let key = format!("{}/data.ysweet", key);
+        Self::new_with_options(store, key, PersistenceOptions::default(), callback).await
+    }
+
+    pub async fn new_with_options<Callback: Fn() + Send + Sync + 'static>(
+        store: Option<Arc<Box<dyn Store>>>,
+        key: &str,
+        options: PersistenceOptions,
+        callback: Callback,
+    ) -> Result<Self> {
+        let doc_id = key.to_string();
+        let key = format!("{}/data.ysweet", doc_id);
 
-        let data = if let Some(store) = &store {
-            if let Some(snapshot) = store.get(&key).await.context("Failed to get from store.")? {
+        let mut recovered = false;
+        let (data, version) = if let Some(store) = &store {
+            if let Some((snapshot, version)) = store
+                .get_versioned(&key)
+                .await
+                .context("Failed to get from store.")?
+            {
                 tracing::info!(size=?snapshot.len(), "Loaded snapshot");
-                bincode::deserialize(&snapshot).context("Failed to deserialize.")?
+                let data = match decode_snapshot(&snapshot) {
+                    Ok(data) => data,
+                    Err(e) if options.recovery == RecoveryPolicy::Quarantine => {
+                        recovered = true;
+                        recover(store.as_ref().as_ref(), &doc_id, snapshot, e).await?
+                    }
+                    Err(e) => return Err(e.context(format!("Failed to load snapshot {}.", key))),
+                };
+                // Keep the version, so that writing the recovered document
+                // replaces a quarantined snapshot.
+                (data, Some(version))
             } else {
-                BTreeMap::new()
+                (BTreeMap::new(), None)
             }
         } else {
-            BTreeMap::new()
+            (BTreeMap::new(), None)
         };
 
-        Ok(Self {
+        let sync_kv = Self {
+            size: AtomicUsize::new(data_size(&data)),
             data: Arc::new(Mutex::new(data)),
             store,
+            doc_id,
             key,
+            options,
             dirty: AtomicBool::new(false),
             dirty_callback: Box::new(callback),
-        })
+            version: Mutex::new(version),
+            remote_update_callback: OnceLock::new(),
+            last_version_at: Mutex::new(None),
+            log: Mutex::new(LogState::default()),
+        };
+
+        if let Some(store) = &sync_kv.store {
+            // The log is only read when it is enabled, so that loading a document
+            // without it costs no more than reading the snapshot.
+            if sync_kv.options.update_log.is_some() {
+                let segments = list_segments(store.as_ref().as_ref(), &sync_kv.doc_id).await?;
+                for seq in segments {
+                    sync_kv.replay_segment(store, seq).await?;
+                }
+            }
+
+            // Replace the unreadable snapshot right away, so that loading the
+            // document again does not quarantine it again.
+            if recovered {
+                if let Err(e) = sync_kv.write_snapshot(store).await {
+                    tracing::warn!(?e, "Failed to write recovered snapshot.");
+                    sync_kv.mark_dirty();
+                }
+            }
+        }
+
+        Ok(sync_kv)
+    }
+
+    #[cfg(not(feature = "sync"))]
+    pub fn set_remote_update_callback<F>(&self, callback: F)
+    where
+        F: Fn(&[u8]) + 'static,
+    {
+        self.set_remote_update_callback_inner(Box::new(callback))
+    }
+
+    /// Register a callback that receives the state of a concurrently-written
+    /// snapshot (as a v1 update) after it has been merged during `persist`, so
+    /// that it can also be applied to the in-memory document.
+    #[cfg(feature = "sync")]
+    pub fn set_remote_update_callback<F>(&self, callback: F)
+    where
+        F: Fn(&[u8]) + 'static + Send + Sync,
+    {
+        self.set_remote_update_callback_inner(Box::new(callback))
+    }
+
+    fn set_remote_update_callback_inner(&self, callback: RemoteUpdateCallback) {
+        if self.remote_update_callback.set(callback).is_err() {
+            tracing::warn!("Remote update callback was already set.");
+        }
+    }
+
+    /// Whether the document has changed since it was last persisted.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Relaxed)
     }
 
     fn mark_dirty(&self) {
@@ -54,30 +192,310 @@ impl SyncKv {
         }
     }
 
+    /// Record an update made to the document, to be written to the update log.
+    /// Does nothing unless the update log is enabled.
+    pub fn log_update(&self, update: &[u8]) {
+        if self.options.update_log.is_some() {
+            self.log.lock().unwrap().pending.push(update.to_vec());
+        }
+    }
+
     pub async fn persist(&self) -> Result<(), Box<dyn std::error::Error>> {
-        if let Some(store) = &self.store {
-            let snapshot = {
+        // Clear the flag before writing, so that changes made while persisting
+        // mark the document dirty again. If the write fails, the document stays
+        // dirty until a later persist succeeds.
+        let was_dirty = self.dirty.swap(false, Ordering::Relaxed);
+        let result = self.write_checkpoint().await;
+        if result.is_err() && was_dirty {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+
+    async fn write_checkpoint(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        if self.options.update_log.is_some() {
+            // Compacting the log is left to `compact_log`, so that checkpoints
+            // stay cheap.
+            self.append_log(store).await?;
+        } else {
+            self.write_snapshot(store).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether changes are persisted by appending to the update log, in which
+    /// case a checkpoint does not rewrite the snapshot.
+    pub fn has_update_log(&self) -> bool {
+        self.options.update_log.is_some()
+    }
+
+    /// Whether the update log has enough segments to be compacted.
+    pub fn log_compaction_due(&self) -> bool {
+        self.options.update_log.as_ref().is_some_and(|options| {
+            let log = self.log.lock().unwrap();
+            !log.compacting && log.segments.len() >= options.compact_after_segments
+        })
+    }
+
+    /// Compact the update log into the snapshot. Does nothing if a compaction is
+    /// already running. Updates logged while it runs are kept in the log.
+    pub async fn compact_log(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        {
+            let mut log = self.log.lock().unwrap();
+            if log.compacting {
+                return Ok(());
+            }
+            log.compacting = true;
+        }
+
+        tracing::info!("Compacting update log");
+        let result = self.write_snapshot(store).await;
+        self.log.lock().unwrap().compacting = false;
+        result
+    }
+
+    /// Write the whole document as a snapshot, replacing the update log.
+    async fn write_snapshot(
+        &self,
+        store: &Arc<Box<dyn Store>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut attempts = 1;
+        let compacted = loop {
+            // Every segment applied so far is in `data`; segments appended while
+            // the snapshot is written are not, and must be kept.
+            let (snapshot, segments) = {
                 let data = self.data.lock().unwrap();
-                bincode::serialize(&*data)?
+                let segments = self.log.lock().unwrap().segments.clone();
+                (encode_snapshot(&data, self.options.compression)?, segments)
             };
+            let expected = self.version.lock().unwrap().clone();
+            let history_snapshot = self.options.history.is_some().then(|| snapshot.clone());
 
             tracing::info!(size=?snapshot.len(), "Persisting snapshot");
-            store.set(&self.key, snapshot).await?;
+            match store.set_if(&self.key, snapshot, expected.as_ref()).await {
+                Ok(version) => {
+                    *self.version.lock().unwrap() = Some(version);
+                    if let Some(snapshot) = history_snapshot {
+                        // History is best-effort; the snapshot itself was persisted.
+                        if let Err(e) = self.record_history(store, snapshot).await {
+                            tracing::warn!(?e, "Failed to record version.");
+                        }
+                    }
+                    break segments;
+                }
+                Err(StoreError::Conflict(e)) if attempts < MAX_PERSIST_ATTEMPTS => {
+                    tracing::warn!(?e, attempts, "Snapshot changed in store; merging.");
+                    self.merge_remote(store).await?;
+                    attempts += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        if compacted.is_empty() {
+            return Ok(());
+        }
+        self.log
+            .lock()
+            .unwrap()
+            .segments
+            .retain(|seq| !compacted.contains(seq));
+        for seq in compacted {
+            store
+                .remove(&segment_key(&self.doc_id, seq))
+                .await
+                .context("Failed to remove compacted log segment.")?;
+        }
+        Ok(())
+    }
+
+    /// Write pending updates to the log as a single segment.
+    async fn append_log(&self, store: &Arc<Box<dyn Store>>) -> Result<()> {
+        let pending = std::mem::take(&mut self.log.lock().unwrap().pending);
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let result = self.write_segment(store, &pending).await;
+        if result.is_err() {
+            // Keep the updates, in order, for the next attempt.
+            let mut log = self.log.lock().unwrap();
+            let newer = std::mem::replace(&mut log.pending, pending);
+            log.pending.extend(newer);
+        }
+        result
+    }
+
+    async fn write_segment(&self, store: &Arc<Box<dyn Store>>, pending: &[Vec<u8>]) -> Result<()> {
+        let segment = yrs::merge_updates_v1(pending)
+            .map_err(|e| anyhow!("Failed to merge updates: {}", e))?;
+
+        for _ in 0..MAX_PERSIST_ATTEMPTS {
+            let seq = self.log.lock().unwrap().next_seq;
+            let key = segment_key(&self.doc_id, seq);
+            tracing::info!(size=?segment.len(), seq, "Appending to update log");
+            match store.set_if(&key, segment.clone(), None).await {
+                Ok(_) => {
+                    let mut log = self.log.lock().unwrap();
+                    log.segments.push(seq);
+                    log.next_seq = seq + 1;
+                    return Ok(());
+                }
+                Err(StoreError::Conflict(e)) => {
+                    // Another writer took this sequence number; apply its updates
+                    // and try the next one.
+                    tracing::warn!(?e, seq, "Log segment already exists; merging.");
+                    self.replay_segment(store, seq).await?;
+                }
+                Err(e) => return Err(e).context("Failed to append to update log."),
+            }
+        }
+
+        Err(anyhow!(
+            "Gave up appending to update log after repeated conflicts."
+        ))
+    }
+
+    /// Apply the log segment `seq` from the store to this document.
+    async fn replay_segment(&self, store: &Arc<Box<dyn Store>>, seq: u64) -> Result<()> {
+        let update = store
+            .get(&segment_key(&self.doc_id, seq))
+            .await
+            .context("Failed to read log segment.")?;
+
+        if let Some(update) = &update {
+            tracing::info!(size=?update.len(), seq, "Replaying log segment");
+            self.apply_remote_update(update)?;
+        }
+
+        let mut log = self.log.lock().unwrap();
+        if update.is_some() {
+            log.segments.push(seq);
         }
-        self.dirty.store(false, Ordering::Relaxed);
+        log.next_seq = log.next_seq.max(seq + 1);
         Ok(())
     }
 
+    /// Write `snapshot` as a historical version, unless the previous version is
+    /// more recent than the configured interval.
+    async fn record_history(&self, store: &Arc<Box<dyn Store>>, snapshot: Vec<u8>) -> Result<()> {
+        let Some(options) = &self.options.history else {
+            return Ok(());
+        };
+
+        let last_version_at = *self.last_version_at.lock().unwrap();
+        let last_version_at = match last_version_at {
+            Some(last_version_at) => Some(last_version_at),
+            None => list_versions(store.as_ref().as_ref(), &self.doc_id)
+                .await?
+                .last()
+                .map(|version| version.created_at),
+        };
+
+        let now = now_millis();
+        if last_version_at
+            .is_some_and(|last| now.saturating_sub(last) < options.min_interval.as_millis() as u64)
+        {
+            *self.last_version_at.lock().unwrap() = last_version_at;
+            return Ok(());
+        }
+
+        record_version(
+            store.as_ref().as_ref(),
+            &self.doc_id,
+            snapshot,
+            now,
+            options,
+        )
+        .await?;
+        *self.last_version_at.lock().unwrap() = Some(now);
+        Ok(())
+    }
+
+    /// Merge the snapshot currently in the store into this document, and
+    /// base the next write on its version.
+    async fn merge_remote(&self, store: &Arc<Box<dyn Store>>) -> Result<()> {
+        let Some((snapshot, version)) = store
+            .get_versioned(&self.key)
+            .await
+            .context("Failed to get from store.")?
+        else {
+            // The snapshot was removed, so the next write should recreate it.
+            *self.version.lock().unwrap() = None;
+            return Ok(());
+        };
+
+        let remote = SyncKv::from_data(
+            decode_snapshot(&snapshot).context("Failed to decode remote snapshot.")?,
+        );
+        let update = {
+            let doc = Doc::new();
+            let mut txn = doc.transact_mut();
+            remote
+                .load_doc(DOC_NAME, &mut txn)
+                .map_err(|_| anyhow!("Failed to load remote doc"))?;
+            txn.encode_state_as_update_v1(&StateVector::default())
+        };
+
+        self.apply_remote_update(&update)?;
+        *self.version.lock().unwrap() = Some(version);
+        Ok(())
+    }
+
+    /// Apply an update written by another writer, and pass it on to the
+    /// remote update callback.
+    fn apply_remote_update(&self, update: &[u8]) -> Result<()> {
+        self.push_update(DOC_NAME, update)
+            .map_err(|_| anyhow!("Failed to push remote update"))?;
+        self.flush_doc_with(DOC_NAME, Default::default())
+            .map_err(|_| anyhow!("Failed to flush remote update"))?;
+
+        if let Some(callback) = self.remote_update_callback.get() {
+            callback(update);
+        }
+
+        Ok(())
+    }
+
+    /// A store-less `SyncKv` over existing data, used to decode snapshots.
+    pub(crate) fn from_data(data: BTreeMap<Vec<u8>, Vec<u8>>) -> Self {
+        Self {
+            size: AtomicUsize::new(data_size(&data)),
+            data: Arc::new(Mutex::new(data)),
+            store: None,
+            doc_id: String::new(),
+            key: String::new(),
+            options: PersistenceOptions::default(),
+            dirty: AtomicBool::new(false),
+            dirty_callback: Box::new(|| ()),
+            version: Mutex::new(None),
+            remote_update_callback: OnceLock::new(),
+            last_version_at: Mutex::new(None),
+            log: Mutex::new(LogState::default()),
+        }
+    }
+
     #[cfg(test)]
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+    pub(crate) fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
         let map = self.data.lock().unwrap();
         map.get(key).cloned()
     }
 
     #[cfg(test)]
-    fn set(&self, key: &[u8], value: &[u8]) {
+    pub(crate) fn data(&self) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        self.data.lock().unwrap().clone()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set(&self, key: &[u8], value: &[u8]) {
         let mut map = self.data.lock().unwrap();
-        map.insert(key.to_vec(), value.to_vec());
+        self.insert(&mut map, key, value);
         self.mark_dirty();
     }
 
@@ -88,6 +506,64 @@ impl SyncKv {
     pub fn is_empty(&self) -> bool {
         self.data.lock().unwrap().is_empty()
     }
+
+    /// Total size in bytes of the stored keys and values, which approximates the
+    /// size of the encoded document.
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Insert into `map`, which must be the locked `data`, keeping `size` in step.
+    fn insert(&self, map: &mut BTreeMap<Vec<u8>, Vec<u8>>, key: &[u8], value: &[u8]) {
+        self.size
+            .fetch_add(key.len() + value.len(), Ordering::Relaxed);
+        if let Some(old) = map.insert(key.to_vec(), value.to_vec()) {
+            self.size
+                .fetch_sub(key.len() + old.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Remove from `map`, which must be the locked `data`, keeping `size` in step.
+    fn remove_entry(&self, map: &mut BTreeMap<Vec<u8>, Vec<u8>>, key: &[u8]) {
+        if let Some(old) = map.remove(key) {
+            self.size
+                .fetch_sub(key.len() + old.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Replace the stored data with a fresh encoding of the document read by `txn`,
+    /// if that is smaller. The document is then marked dirty, so that the next
+    /// checkpoint writes the compacted snapshot.
+    pub fn compact<T: ReadTxn>(&self, txn: &T) -> Result<Compaction> {
+        let fresh = SyncKv::from_data(BTreeMap::new());
+        fresh
+            .insert_doc(DOC_NAME, txn)
+            .map_err(|_| anyhow!("Failed to encode doc"))?;
+        let fresh = std::mem::take(&mut *fresh.data.lock().unwrap());
+        let after = data_size(&fresh);
+
+        let before = {
+            let mut data = self.data.lock().unwrap();
+            let before = data_size(&data);
+            if after >= before {
+                return Ok(Compaction {
+                    before,
+                    after: before,
+                });
+            }
+            *data = fresh;
+            self.size.store(after, Ordering::Relaxed);
+            before
+        };
+        self.mark_dirty();
+        Ok(Compaction { before, after })
+    }
+}
+
+fn data_size(data: &BTreeMap<Vec<u8>, Vec<u8>>) -> usize {
+    data.iter()
+        .map(|(key, value)| key.len() + value.len())
+        .sum()
 }
 
 impl<'d> DocOps<'d> for SyncKv {}
@@ -142,7 +618,7 @@ impl<'a> yrs_kvstore::KVStore<'a> for SyncKv {
 
     fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
         let mut map = self.data.lock().unwrap();
-        map.remove(key);
+        self.remove_entry(&mut map, key);
         self.mark_dirty();
         Ok(())
     }
@@ -166,7 +642,7 @@ impl<'a> yrs_kvstore::KVStore<'a> for SyncKv {
 
     fn upsert(&self, key: &[u8], value: &[u8]) -> Result<(), Self::Error> {
         let mut map = self.data.lock().unwrap();
-        map.insert(key.to_vec(), value.to_vec());
+        self.insert(&mut map, key, value);
         self.mark_dirty();
         Ok(())
     }
@@ -174,7 +650,7 @@ impl<'a> yrs_kvstore::KVStore<'a> for SyncKv {
     fn remove_range(&self, from: &[u8], to: &[u8]) -> Result<(), Self::Error> {
         for entry in self.iter_range(from, to)? {
             let mut map = self.data.lock().unwrap();
-            map.remove(&entry.key);
+            self.remove_entry(&mut map, &entry.key);
         }
         self.mark_dirty();
         Ok(())
@@ -184,43 +660,10 @@ impl<'a> yrs_kvstore::KVStore<'a> for SyncKv {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::store::Result;
-    use async_trait::async_trait;
-    use dashmap::DashMap;
-    use std::sync::atomic::AtomicUsize;
+    use crate::store::memory::MemoryStore;
+    use std::{sync::atomic::AtomicUsize, time::Duration};
     use tokio;
 
-    #[derive(Default, Clone)]
-    struct MemoryStore {
-        data: Arc<DashMap<String, Vec<u8>>>,
-    }
-
-    #[cfg_attr(not(feature = "single-threaded"), async_trait)]
-    #[cfg_attr(feature = "single-threaded", async_trait(?Send))]
-    impl Store for MemoryStore {
-        async fn init(&self) -> Result<()> {
-            Ok(())
-        }
-
-        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
-            Ok(self.data.get(key).map(|v| v.clone()))
-        }
-
-        async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
-            self.data.insert(key.to_owned(), value);
-            Ok(())
-        }
-
-        async fn remove(&self, key: &str) -> Result<()> {
-            self.data.remove(key);
-            Ok(())
-        }
-
-        async fn exists(&self, key: &str) -> Result<bool> {
-            Ok(self.data.contains_key(key))
-        }
-    }
-
     #[derive(Default, Clone)]
     struct CallbackCounter {
         data: Arc<AtomicUsize>,
@@ -251,7 +694,7 @@ mod test {
         sync_kv.set(b"foo", b"bar");
         assert_eq!(sync_kv.get(b"foo"), Some(b"bar".to_vec()));
 
-        assert!(store.data.is_empty());
+        assert!(store.is_empty());
 
         // We should have received a dirty callback.
         assert_eq!(c.count(), 1);
@@ -274,7 +717,7 @@ mod test {
             sync_kv.set(b"foo", b"bar");
             assert_eq!(sync_kv.get(b"foo"), Some(b"bar".to_vec()));
 
-            assert!(store.data.is_empty());
+            assert!(store.is_empty());
 
             sync_kv.persist().await.unwrap();
         }
@@ -287,4 +730,256 @@ mod test {
             assert_eq!(sync_kv.get(b"foo"), Some(b"bar".to_vec()));
         }
     }
+
+    fn text_update(client_id: u64, text: &str) -> Vec<u8> {
+        use yrs::Text;
+        let doc = Doc::with_client_id(client_id);
+        let root = doc.get_or_insert_text("text");
+        let mut txn = doc.transact_mut();
+        root.insert(&mut txn, 0, text);
+        txn.encode_state_as_update_v1(&StateVector::default())
+    }
+
+    fn read_text(sync_kv: &SyncKv) -> String {
+        use yrs::GetString;
+        let doc = Doc::new();
+        let root = doc.get_or_insert_text("text");
+        let mut txn = doc.transact_mut();
+        sync_kv.load_doc(DOC_NAME, &mut txn).unwrap();
+        root.get_string(&txn)
+    }
+
+    #[tokio::test]
+    async fn merges_concurrent_snapshot() {
+        let store: Arc<Box<dyn Store>> = Arc::new(Box::new(MemoryStore::default()));
+
+        let first = SyncKv::new(Some(store.clone()), "foo", || ())
+            .await
+            .unwrap();
+        let second = SyncKv::new(Some(store.clone()), "foo", || ())
+            .await
+            .unwrap();
+
+        first.push_update(DOC_NAME, &text_update(1, "abc")).unwrap();
+        first.flush_doc_with(DOC_NAME, Default::default()).unwrap();
+        first.persist().await.unwrap();
+
+        // The second writer's snapshot is based on an older version, so it must
+        // merge the first writer's changes instead of overwriting them.
+        second
+            .push_update(DOC_NAME, &text_update(2, "xyz"))
+            .unwrap();
+        second.flush_doc_with(DOC_NAME, Default::default()).unwrap();
+        second.persist().await.unwrap();
+
+        let loaded = SyncKv::new(Some(store.clone()), "foo", || ())
+            .await
+            .unwrap();
+        let text = read_text(&loaded);
+        assert_eq!(text.len(), 6);
+        assert!(text.contains("abc"));
+        assert!(text.contains("xyz"));
+    }
+
+    #[tokio::test]
+    async fn applies_merged_snapshot_to_live_doc() {
+        use crate::doc_sync::DocWithSyncKv;
+        use yrs::GetString;
+
+        let store: Arc<Box<dyn Store>> = Arc::new(Box::new(MemoryStore::default()));
+
+        let live = DocWithSyncKv::new("foo", Some(store.clone()), || ())
+            .await
+            .unwrap();
+        live.sync_kv().persist().await.unwrap();
+
+        let other = SyncKv::new(Some(store.clone()), "foo", || ())
+            .await
+            .unwrap();
+        other
+            .push_update(DOC_NAME, &text_update(2, "remote"))
+            .unwrap();
+        other.flush_doc_with(DOC_NAME, Default::default()).unwrap();
+        other.persist().await.unwrap();
+
+        live.apply_update(&text_update(1, "local")).unwrap();
+        live.sync_kv().persist().await.unwrap();
+
+        let awareness = live.awareness();
+        let awareness = awareness.read().unwrap();
+        let root = awareness.doc.get_or_insert_text("text");
+        let text = root.get_string(&awareness.doc.transact());
+        assert!(text.contains("remote"));
+        assert!(text.contains("local"));
+    }
+
+    #[tokio::test]
+    async fn records_history_versions() {
+        use crate::history::{list_versions, load_version};
+
+        let store = MemoryStore::default();
+        let options = PersistenceOptions {
+            history: Some(HistoryOptions {
+                max_versions: 2,
+                max_age: None,
+                min_interval: Duration::ZERO,
+            }),
+            ..Default::default()
+        };
+        let sync_kv = SyncKv::new_with_options(
+            Some(Arc::new(Box::new(store.clone()))),
+            "foo",
+            options,
+            || (),
+        )
+        .await
+        .unwrap();
+
+        for value in [b"1", b"2", b"3"] {
+            sync_kv.set(b"value", value);
+            sync_kv.persist().await.unwrap();
+            // Versions are identified by their time in milliseconds.
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+
+        let versions = list_versions(&store, "foo").await.unwrap();
+        assert_eq!(versions.len(), 2);
+        let latest = load_version(&store, "foo", &versions[1].id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest.get(b"value".as_slice()), Some(&b"3".to_vec()));
+        assert!(load_version(&store, "foo", "../data")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    fn log_options(compact_after_segments: usize) -> PersistenceOptions {
+        PersistenceOptions {
+            update_log: Some(UpdateLogOptions {
+                compact_after_segments,
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn apply_and_log(sync_kv: &SyncKv, update: &[u8]) {
+        sync_kv.push_update(DOC_NAME, update).unwrap();
+        sync_kv
+            .flush_doc_with(DOC_NAME, Default::default())
+            .unwrap();
+        sync_kv.log_update(update);
+    }
+
+    #[tokio::test]
+    async fn persists_through_update_log() {
+        let store = MemoryStore::default();
+        let shared: Arc<Box<dyn Store>> = Arc::new(Box::new(store.clone()));
+
+        let sync_kv = SyncKv::new_with_options(Some(shared.clone()), "foo", log_options(3), || ())
+            .await
+            .unwrap();
+        for (client_id, text) in [(1, "a"), (2, "b")] {
+            apply_and_log(&sync_kv, &text_update(client_id, text));
+            sync_kv.persist().await.unwrap();
+        }
+        assert!(!store.exists("foo/data.ysweet").await.unwrap());
+        assert_eq!(list_segments(&store, "foo").await.unwrap(), vec![0, 1]);
+        assert!(!sync_kv.log_compaction_due());
+
+        // A new writer replays the log and continues after its last segment.
+        let sync_kv = SyncKv::new_with_options(Some(shared.clone()), "foo", log_options(3), || ())
+            .await
+            .unwrap();
+        assert_eq!(read_text(&sync_kv).len(), 2);
+        apply_and_log(&sync_kv, &text_update(3, "c"));
+        sync_kv.persist().await.unwrap();
+
+        // The third segment makes the log due for compaction into the snapshot,
+        // which the checkpoint leaves for later.
+        assert!(!store.exists("foo/data.ysweet").await.unwrap());
+        assert!(sync_kv.log_compaction_due());
+        sync_kv.compact_log().await.unwrap();
+        assert!(!sync_kv.log_compaction_due());
+        assert!(store.exists("foo/data.ysweet").await.unwrap());
+        assert!(list_segments(&store, "foo").await.unwrap().is_empty());
+
+        let loaded = SyncKv::new(Some(shared), "foo", || ()).await.unwrap();
+        let text = read_text(&loaded);
+        assert_eq!(text.len(), 3);
+        assert!(text.contains('a') && text.contains('b') && text.contains('c'));
+    }
+
+    #[tokio::test]
+    async fn merges_concurrent_log_segments() {
+        let store = MemoryStore::default();
+        let shared: Arc<Box<dyn Store>> = Arc::new(Box::new(store.clone()));
+
+        let first = SyncKv::new_with_options(Some(shared.clone()), "foo", log_options(10), || ())
+            .await
+            .unwrap();
+        let second = SyncKv::new_with_options(Some(shared.clone()), "foo", log_options(10), || ())
+            .await
+            .unwrap();
+
+        apply_and_log(&first, &text_update(1, "abc"));
+        first.persist().await.unwrap();
+
+        // Both writers start at the same sequence number; the second must take
+        // the next one and pick up the first writer's segment.
+        apply_and_log(&second, &text_update(2, "xyz"));
+        second.persist().await.unwrap();
+        assert_eq!(list_segments(&store, "foo").await.unwrap(), vec![0, 1]);
+        assert_eq!(read_text(&second).len(), 6);
+
+        // The log is only read when it is enabled.
+        let without_log = SyncKv::new(Some(shared.clone()), "foo", || ())
+            .await
+            .unwrap();
+        assert!(read_text(&without_log).is_empty());
+
+        let loaded = SyncKv::new_with_options(Some(shared), "foo", log_options(10), || ())
+            .await
+            .unwrap();
+        let text = read_text(&loaded);
+        assert!(text.contains("abc") && text.contains("xyz"));
+    }
+
+    #[tokio::test]
+    async fn compacts_unmerged_updates() {
+        use crate::doc_sync::DocWithSyncKv;
+
+        let store: Arc<Box<dyn Store>> = Arc::new(Box::new(MemoryStore::default()));
+        let live = DocWithSyncKv::new("foo", Some(store.clone()), || ())
+            .await
+            .unwrap();
+        live.apply_update(&text_update(1, "abc")).unwrap();
+        live.sync_kv().persist().await.unwrap();
+
+        // An update entry that was never merged, as left by a snapshot taken
+        // between an update and its merge.
+        let sync_kv = live.sync_kv();
+        sync_kv
+            .push_update(DOC_NAME, &text_update(1, "abc"))
+            .unwrap();
+        let before = sync_kv.size();
+        // The size is tracked as entries change, without rescanning the data.
+        assert_eq!(before, data_size(&sync_kv.data()));
+
+        let compaction = live.compact().unwrap();
+        assert_eq!(compaction.before, before);
+        assert!(compaction.reclaimed() > 0);
+        assert_eq!(sync_kv.size(), compaction.after);
+        assert!(sync_kv.is_dirty());
+        assert_eq!(read_text(&sync_kv), "abc");
+
+        // Compacting again finds nothing to reclaim.
+        assert_eq!(live.compact().unwrap().reclaimed(), 0);
+
+        sync_kv.persist().await.unwrap();
+        let loaded = SyncKv::new(Some(store), "foo", || ()).await.unwrap();
+        assert_eq!(loaded.data(), sync_kv.data());
+        assert_eq!(loaded.size(), sync_kv.size());
+    }
 }
@@ -0,0 +1,59 @@
+//! Incremental persistence through an append-only log of updates.
+//!
+//! Instead of rewriting the whole snapshot on every checkpoint, the updates made
+//! since the previous checkpoint are merged and written as a new segment at
+//! `{doc_id}/log/{seq}`. Segments are written only if they do not already exist,
+//! so concurrent writers never overwrite each other's segments. Once enough
+//! segments accumulate, they are compacted into the snapshot in the background
+//! and removed.
+//!
+//! The log is only read while it is enabled, so it must stay enabled until any
+//! remaining segments have been compacted; a document loaded with the log
+//! disabled does not see them.
+//!
+//! Applying a yrs update more than once has no effect, so replaying a segment
+//! whose contents are already in the snapshot (e.g. after a crash part-way
+//! through compaction) is harmless.
+
+use crate::store::Store;
+use anyhow::{Context, Result};
+
+/// Options for persisting documents through an update log.
+#[derive(Clone, Debug)]
+pub struct UpdateLogOptions {
+    /// Compact the log into the snapshot once it has this many segments.
+    pub compact_after_segments: usize,
+}
+
+fn log_prefix(doc_id: &str) -> String {
+    format!("{}/log/", doc_id)
+}
+
+pub(crate) fn segment_key(doc_id: &str, seq: u64) -> String {
+    // Zero-padded so that segments list in order.
+    format!("{}{:020}", log_prefix(doc_id), seq)
+}
+
+/// List the sequence numbers of a document's log segments, in order.
+pub(crate) async fn list_segments(store: &dyn Store, doc_id: &str) -> Result<Vec<u64>> {
+    let prefix = log_prefix(doc_id);
+    let mut segments = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = store
+            .list(&prefix, cursor.as_deref(), 1000)
+            .await
+            .context("Failed to list update log.")?;
+        segments.extend(
+            page.keys
+                .iter()
+                .filter_map(|key| key.strip_prefix(&prefix)?.parse::<u64>().ok()),
+        );
+        match page.next_cursor {
+            Some(next_cursor) => cursor = Some(next_cursor),
+            None => break,
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 use std::{str::FromStr, time::Duration};
 use worker::Env;
 use y_sweet_core::auth::KeyId;
-use y_sweet_core::store::s3::S3Config;
+use y_sweet_core::store::s3::{parse_key_value_pairs, S3Config, ServerSideEncryption};
 
 const BUCKET: &str = "Y_SWEET_DATA";
 const BUCKET_KIND: &str = "BUCKET_KIND";
@@ -14,6 +14,11 @@ const S3_REGION: &str = "AWS_REGION";
 const S3_ENDPOINT: &str = "AWS_ENDPOINT_URL_S3";
 const S3_BUCKET_PREFIX: &str = "S3_BUCKET_PREFIX";
 const S3_BUCKET_NAME: &str = "S3_BUCKET_NAME";
+const S3_SERVER_SIDE_ENCRYPTION: &str = "Y_SWEET_S3_SSE";
+const S3_SERVER_SIDE_ENCRYPTION_KEY: &str = "Y_SWEET_S3_SSE_KEY";
+const S3_STORAGE_CLASS: &str = "Y_SWEET_S3_STORAGE_CLASS";
+const S3_METADATA: &str = "Y_SWEET_S3_METADATA";
+const S3_TAGS: &str = "Y_SWEET_S3_TAGS";
 
 // Note: unlike the native server, the worker checkpoint frequency is not configurable because
 // it directly relates to Cloudflare platform configuration. Per their docs:
@@ -72,16 +77,18 @@ fn parse_s3_config(env: &Env) -> anyhow::Result<S3Config> {
     );
 
     Ok(S3Config {
-        key: env
-            .var(S3_ACCESS_KEY_ID)
-            .map_err(|_| anyhow::anyhow!("AWS_ACCESS_KEY_ID env var not supplied"))?
-            .to_string(),
+        key: Some(
+            env.var(S3_ACCESS_KEY_ID)
+                .map_err(|_| anyhow::anyhow!("AWS_ACCESS_KEY_ID env var not supplied"))?
+                .to_string(),
+        ),
         region,
         endpoint,
-        secret: env
-            .var(S3_SECRET_ACCESS_KEY)
-            .map_err(|_| anyhow::anyhow!("AWS_SECRET_ACCESS_KEY env var not supplied"))?
-            .to_string(),
+        secret: Some(
+            env.var(S3_SECRET_ACCESS_KEY)
+                .map_err(|_| anyhow::anyhow!("AWS_SECRET_ACCESS_KEY env var not supplied"))?
+                .to_string(),
+        ),
         token: env.var(S3_SESSION_TOKEN).map(|s| s.to_string()).ok(),
         bucket: env
             .var(S3_BUCKET_NAME)
@@ -89,6 +96,31 @@ fn parse_s3_config(env: &Env) -> anyhow::Result<S3Config> {
             .to_string(),
         bucket_prefix: env.var(S3_BUCKET_PREFIX).ok().map(|t| t.to_string()),
         path_style: false,
+        multipart_threshold: S3Config::DEFAULT_MULTIPART_THRESHOLD,
+        multipart_part_size: S3Config::DEFAULT_MULTIPART_PART_SIZE,
+        max_retries: S3Config::DEFAULT_MAX_RETRIES,
+        request_timeout_secs: S3Config::DEFAULT_REQUEST_TIMEOUT_SECS,
+        server_side_encryption: env
+            .var(S3_SERVER_SIDE_ENCRYPTION)
+            .ok()
+            .map(|mode| {
+                ServerSideEncryption::parse(
+                    &mode.to_string(),
+                    env.var(S3_SERVER_SIDE_ENCRYPTION_KEY)
+                        .ok()
+                        .map(|key| key.to_string()),
+                )
+            })
+            .transpose()?,
+        storage_class: env.var(S3_STORAGE_CLASS).ok().map(|s| s.to_string()),
+        metadata: env.var(S3_METADATA).map_or_else(
+            |_| Ok(Default::default()),
+            |s| parse_key_value_pairs(&s.to_string()),
+        )?,
+        tags: env.var(S3_TAGS).map_or_else(
+            |_| Ok(Default::default()),
+            |s| parse_key_value_pairs(&s.to_string()),
+        )?,
     })
 }
 
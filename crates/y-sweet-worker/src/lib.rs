@@ -50,7 +50,7 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
     console_error_panic_hook::set_once();
 
     let configuration = Configuration::try_from(&env).map_err(|e| e.to_string())?;
-    let context = ServerContext::new(configuration, &env);
+    let context = ServerContext::new(configuration, &env).map_err(|e| e.to_string())?;
 
     let router = router(context);
     let router = match router {
@@ -176,6 +176,7 @@ async fn check_store(
     let result = match result {
         Ok(_) => json!({"ok": true}),
         Err(StoreError::ConnectionError(_)) => json!({"ok": false, "error": "Connection error."}),
+        Err(StoreError::Throttled(_)) => json!({"ok": false, "error": "Throttled."}),
         Err(StoreError::BucketDoesNotExist(_)) => {
             json!({"ok": false, "error": "Bucket does not exist."})
         }
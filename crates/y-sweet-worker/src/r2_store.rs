@@ -1,6 +1,10 @@
 use async_trait::async_trait;
-use worker::Bucket;
-use y_sweet_core::store::{Result, Store, StoreError};
+use worker::{Bucket, Conditional};
+use y_sweet_core::store::{ListResult, ObjectVersion, Result, Store, StoreError};
+
+/// The most keys that R2 returns from one list request.
+const MAX_R2_LIST_LIMIT: usize = 1000;
+
 pub struct R2Store {
     bucket: Bucket,
     path_prefix: Option<String>,
@@ -21,6 +25,16 @@ impl R2Store {
             key.to_string()
         }
     }
+
+    fn unprefixed_key(&self, key: String) -> Option<String> {
+        if let Some(path_prefix) = &self.path_prefix {
+            key.strip_prefix(path_prefix.as_str())
+                .and_then(|key| key.strip_prefix('/'))
+                .map(|key| key.to_string())
+        } else {
+            Some(key)
+        }
+    }
 }
 
 #[async_trait(?Send)]
@@ -51,6 +65,78 @@ impl Store for R2Store {
         }
     }
 
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        let object = self
+            .bucket
+            .get(self.prefixed_key(key))
+            .execute()
+            .await
+            .map_err(|_| StoreError::ConnectionError("Failed to get object".into()))?;
+        if let Some(object) = object {
+            let version = ObjectVersion(object.etag());
+            let bytes = object
+                .body()
+                .ok_or_else(|| StoreError::ConnectionError("Object does not have body.".into()))?
+                .bytes()
+                .await
+                .map_err(|e| {
+                    StoreError::ConnectionError(format!("Failed to get object bytes {e}"))
+                })?;
+            Ok(Some((bytes, version)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        let condition = match expected {
+            Some(ObjectVersion(etag)) => Conditional {
+                etag_matches: Some(etag.clone()),
+                ..Default::default()
+            },
+            None => Conditional {
+                etag_does_not_match: Some("*".to_string()),
+                ..Default::default()
+            },
+        };
+
+        let result = self
+            .bucket
+            .put(self.prefixed_key(key), value)
+            .only_if(condition)
+            .execute()
+            .await;
+
+        match result {
+            Ok(object) => Ok(ObjectVersion(object.etag())),
+            Err(e) => {
+                // R2 signals a failed precondition by returning no object, so check
+                // whether the object moved on before reporting a connection error.
+                let current = self
+                    .bucket
+                    .head(self.prefixed_key(key))
+                    .await
+                    .map_err(|e| StoreError::ConnectionError(format!("Failed to head object {e}")))?
+                    .map(|object| ObjectVersion(object.etag()));
+                if current.as_ref() != expected {
+                    Err(StoreError::Conflict(format!(
+                        "Expected version {:?} of {}, found {:?}.",
+                        expected, key, current
+                    )))
+                } else {
+                    Err(StoreError::ConnectionError(format!(
+                        "Failed to put object {e}"
+                    )))
+                }
+            }
+        }
+    }
+
     async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
         self.bucket
             .put(self.prefixed_key(key), value)
@@ -71,4 +157,51 @@ impl Store for R2Store {
             .map(|r| r.is_some())
             .map_err(|e| StoreError::ConnectionError(format!("Failed to head object {e}")))
     }
+
+    async fn list(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<ListResult> {
+        if limit == 0 {
+            return Ok(ListResult::default());
+        }
+
+        // Start the listing after our cursor key, then follow R2's own cursor if
+        // it returns fewer keys than we asked for.
+        let mut keys = Vec::new();
+        let mut r2_cursor: Option<String> = None;
+        loop {
+            let remaining = (limit - keys.len()).min(MAX_R2_LIST_LIMIT);
+            let mut request = self
+                .bucket
+                .list()
+                .prefix(self.prefixed_key(prefix))
+                .limit(remaining as u32);
+            if let Some(r2_cursor) = r2_cursor.take() {
+                request = request.cursor(r2_cursor);
+            } else if let Some(cursor) = cursor {
+                request = request.start_after(self.prefixed_key(cursor));
+            }
+            let objects = request
+                .execute()
+                .await
+                .map_err(|e| StoreError::ConnectionError(format!("Failed to list objects {e}")))?;
+
+            keys.extend(
+                objects
+                    .objects()
+                    .into_iter()
+                    .filter_map(|object| self.unprefixed_key(object.key())),
+            );
+
+            if !objects.truncated() {
+                return Ok(ListResult {
+                    keys,
+                    next_cursor: None,
+                });
+            }
+            if keys.len() >= limit {
+                let next_cursor = keys.last().cloned();
+                return Ok(ListResult { keys, next_cursor });
+            }
+            r2_cursor = objects.cursor();
+        }
+    }
 }
@@ -14,21 +14,25 @@ pub struct ServerContext {
 }
 
 impl ServerContext {
-    pub fn new(config: Configuration, env: &Env) -> Self {
+    pub fn new(config: Configuration, env: &Env) -> Result<Self, Error> {
         let bucket = env.bucket(&config.bucket).unwrap();
         let store: Box<dyn Store> = if let Some(s3) = config.s3_store_config.as_ref() {
-            Box::new(S3Store::new(s3.clone()))
+            let store = S3Store::new(s3.clone()).map_err(|e| Error::ConfigurationError {
+                field: "s3_store_config".to_string(),
+                value: e.to_string(),
+            })?;
+            Box::new(store)
         } else {
             Box::new(R2Store::new(bucket, config.bucket_prefix.clone()))
         };
         #[allow(clippy::arc_with_non_send_sync)] // Arc required for compatibility with core.
         let store: Arc<Box<dyn Store>> = Arc::new(store);
 
-        Self {
+        Ok(Self {
             config,
             store,
             auth: None,
-        }
+        })
     }
 
     pub fn auth(&mut self) -> Result<Option<&Authenticator>, Error> {
@@ -96,6 +100,6 @@ impl ServerContext {
         let config: Configuration =
             serde_json::from_str(context_header_val).map_err(|_| Error::InternalError)?;
 
-        Ok(Self::new(config, env))
+        Self::new(config, env)
     }
 }
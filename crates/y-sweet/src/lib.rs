@@ -2,5 +2,6 @@
 
 pub mod cli;
 pub mod convert;
+pub mod migrate;
 pub mod server;
 pub mod stores;
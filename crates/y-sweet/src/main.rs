@@ -1,11 +1,13 @@
 use anyhow::Context;
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use serde_json::json;
 use std::{
     env,
     net::{IpAddr, Ipv4Addr, SocketAddr},
     path::PathBuf,
+    sync::Arc,
+    time::Duration,
 };
 use tokio::io::AsyncReadExt;
 use tokio::net::TcpListener;
@@ -14,16 +16,29 @@ use tracing::metadata::LevelFilter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 use url::Url;
 use y_sweet::cli::{print_auth_message, print_server_url};
-use y_sweet::stores::filesystem::FileSystemStore;
+use y_sweet::migrate::{migrate, MigrateOptions};
+use y_sweet::server::ResidentLimits;
+use y_sweet::stores::{
+    caching::{CacheOptions, CacheWriteMode, CachingStore},
+    registry::{parse_s3_config_from_env_and_args, StoreRegistry},
+};
 use y_sweet_core::{
     auth::Authenticator,
+    compaction::CompactionOptions,
+    history::HistoryOptions,
+    limits::DocLimits,
+    quarantine::RecoveryPolicy,
+    snapshot::SnapshotCompression,
     store::{
-        s3::{S3Config, S3Store},
+        encrypted::{EncryptedStore, KeyRing},
+        mirror::{MirrorConsistency, MirrorStore},
+        s3::S3Store,
         Store,
     },
+    sync_kv::PersistenceOptions,
+    update_log::UpdateLogOptions,
 };
 
-const DEFAULT_S3_REGION: &str = "us-east-1";
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[derive(Parser)]
@@ -33,11 +48,15 @@ struct Opts {
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)] // Parsed once at startup.
 enum ServSubcommand {
     Serve {
         #[clap(env = "Y_SWEET_STORE")]
         store: Option<String>,
 
+        #[clap(flatten)]
+        encryption: EncryptionArgs,
+
         #[clap(long, default_value = "8080", env = "PORT")]
         port: u16,
         #[clap(long, env = "Y_SWEET_HOST")]
@@ -45,12 +64,56 @@ enum ServSubcommand {
         #[clap(long, default_value = "10", env = "Y_SWEET_CHECKPOINT_FREQ_SECONDS")]
         checkpoint_freq_seconds: u64,
 
+        /// On shutdown, how long to spend closing connections and persisting documents
+        /// before exiting.
+        #[clap(long, default_value = "20", env = "Y_SWEET_SHUTDOWN_TIMEOUT_SECONDS")]
+        shutdown_timeout_seconds: u64,
+
+        /// How many checkpoints a document may fail to persist for before /ready
+        /// returns 503. /live always returns 200.
+        #[clap(long, default_value = "6", env = "Y_SWEET_UNREADY_AFTER_CHECKPOINTS")]
+        unready_after_checkpoints: u32,
+
         #[clap(long, env = "Y_SWEET_AUTH")]
         auth: Option<String>,
 
         #[clap(long, env = "Y_SWEET_URL_PREFIX")]
         url_prefix: Option<Url>,
 
+        /// Compression for newly written snapshots: "none" or "lz4".
+        /// Existing snapshots are readable regardless of this setting.
+        #[clap(long, default_value = "none", env = "Y_SWEET_SNAPSHOT_COMPRESSION")]
+        snapshot_compression: SnapshotCompression,
+
+        /// What to do when a document's snapshot cannot be decoded: "fail", or
+        /// "quarantine" to set it aside and recover from history and the update log.
+        #[clap(long, default_value = "fail", env = "Y_SWEET_SNAPSHOT_RECOVERY")]
+        snapshot_recovery: RecoveryPolicy,
+
+        #[clap(flatten)]
+        history: HistoryArgs,
+
+        /// Persist incremental updates to an append-only log, compacting it into
+        /// the snapshot in the background once it has this many segments. By default, the whole
+        /// snapshot is written on every checkpoint. The log is only read while it is enabled.
+        #[clap(long, env = "Y_SWEET_UPDATE_LOG_COMPACT_AFTER")]
+        update_log_compact_after: Option<usize>,
+
+        #[clap(flatten)]
+        limits: LimitsArgs,
+
+        #[clap(flatten)]
+        compaction: CompactionArgs,
+
+        #[clap(flatten)]
+        cache: CacheArgs,
+
+        #[clap(flatten)]
+        resident: ResidentArgs,
+
+        #[clap(flatten)]
+        mirror: MirrorArgs,
+
         #[clap(long)]
         prod: bool,
     },
@@ -69,6 +132,33 @@ enum ServSubcommand {
 
         /// The ID of the document to write.
         doc_id: String,
+
+        #[clap(flatten)]
+        encryption: EncryptionArgs,
+    },
+
+    /// Copy all documents from one store to another, checking that each copy
+    /// loads to the same state as the original.
+    Migrate {
+        /// The store to copy documents from.
+        source: String,
+
+        /// The store to copy documents to.
+        destination: String,
+
+        /// Skip documents whose objects all exist in the destination, to continue
+        /// an interrupted migration.
+        #[clap(long)]
+        resume: bool,
+
+        /// Check which documents would be copied, without writing anything.
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Encryption settings for both stores. Documents are re-encrypted with
+        /// the first key.
+        #[clap(flatten)]
+        encryption: EncryptionArgs,
     },
 
     Version,
@@ -82,74 +172,298 @@ enum ServSubcommand {
 
         #[clap(long, default_value = "10", env = "Y_SWEET_CHECKPOINT_FREQ_SECONDS")]
         checkpoint_freq_seconds: u64,
+
+        /// On shutdown, how long to spend closing connections and persisting documents
+        /// before exiting.
+        #[clap(long, default_value = "20", env = "Y_SWEET_SHUTDOWN_TIMEOUT_SECONDS")]
+        shutdown_timeout_seconds: u64,
+
+        /// Compression for newly written snapshots: "none" or "lz4".
+        /// Existing snapshots are readable regardless of this setting.
+        #[clap(long, default_value = "none", env = "Y_SWEET_SNAPSHOT_COMPRESSION")]
+        snapshot_compression: SnapshotCompression,
+
+        /// What to do when a document's snapshot cannot be decoded: "fail", or
+        /// "quarantine" to set it aside and recover from history and the update log.
+        #[clap(long, default_value = "fail", env = "Y_SWEET_SNAPSHOT_RECOVERY")]
+        snapshot_recovery: RecoveryPolicy,
+
+        #[clap(flatten)]
+        history: HistoryArgs,
+
+        /// Persist incremental updates to an append-only log, compacting it into
+        /// the snapshot in the background once it has this many segments. By default, the whole
+        /// snapshot is written on every checkpoint. The log is only read while it is enabled.
+        #[clap(long, env = "Y_SWEET_UPDATE_LOG_COMPACT_AFTER")]
+        update_log_compact_after: Option<usize>,
+
+        #[clap(flatten)]
+        limits: LimitsArgs,
+
+        #[clap(flatten)]
+        compaction: CompactionArgs,
+
+        #[clap(flatten)]
+        encryption: EncryptionArgs,
     },
 }
 
-const S3_ACCESS_KEY_ID: &str = "AWS_ACCESS_KEY_ID";
-const S3_SECRET_ACCESS_KEY: &str = "AWS_SECRET_ACCESS_KEY";
-const S3_SESSION_TOKEN: &str = "AWS_SESSION_TOKEN";
-const S3_REGION: &str = "AWS_REGION";
-const S3_ENDPOINT: &str = "AWS_ENDPOINT_URL_S3";
-const S3_USE_PATH_STYLE: &str = "AWS_S3_USE_PATH_STYLE";
-fn parse_s3_config_from_env_and_args(
-    bucket: String,
-    prefix: Option<String>,
-) -> anyhow::Result<S3Config> {
-    let use_path_style = env::var(S3_USE_PATH_STYLE).ok();
-    let path_style = if let Some(use_path_style) = use_path_style {
-        if use_path_style.to_lowercase() == "true" {
-            true
-        } else if use_path_style.to_lowercase() == "false" || use_path_style.is_empty() {
-            false
-        } else {
-            anyhow::bail!(
-                "If AWS_S3_USE_PATH_STYLE is set, it must be either \"true\" or \"false\""
+#[derive(Args)]
+struct EncryptionArgs {
+    /// Encrypt stored documents with these keys, given as comma-separated
+    /// `key_id:base64_key` pairs. The first key encrypts new writes; the rest
+    /// are only used to read objects written under older keys.
+    #[clap(long, env = "Y_SWEET_ENCRYPTION_KEYS", hide_env_values = true)]
+    encryption_keys: Option<KeyRing>,
+
+    /// Read objects that are not encrypted as they are, instead of failing. Only
+    /// use this while migrating a store to encryption, since unencrypted objects
+    /// are not authenticated.
+    #[clap(long, env = "Y_SWEET_ALLOW_UNENCRYPTED_READS")]
+    allow_unencrypted_reads: bool,
+}
+
+impl EncryptionArgs {
+    fn wrap(&self, store: Box<dyn Store>) -> Box<dyn Store> {
+        if let Some(keys) = &self.encryption_keys {
+            Box::new(
+                EncryptedStore::new(store, keys.clone())
+                    .with_unencrypted_reads(self.allow_unencrypted_reads),
             )
+        } else {
+            store
         }
-    } else {
-        false
-    };
-
-    Ok(S3Config {
-        key: env::var(S3_ACCESS_KEY_ID)
-            .map_err(|_| anyhow::anyhow!("{} env var not supplied", S3_ACCESS_KEY_ID))?,
-        region: env::var(S3_REGION).unwrap_or_else(|_| DEFAULT_S3_REGION.to_string()),
-        endpoint: env::var(S3_ENDPOINT).unwrap_or_else(|_| {
-            format!(
-                "https://s3.dualstack.{}.amazonaws.com",
-                env::var(S3_REGION).unwrap_or_else(|_| DEFAULT_S3_REGION.to_string())
-            )
-        }),
-        secret: env::var(S3_SECRET_ACCESS_KEY)
-            .map_err(|_| anyhow::anyhow!("{} env var not supplied", S3_SECRET_ACCESS_KEY))?,
-        token: env::var(S3_SESSION_TOKEN).ok(),
-        bucket,
-        bucket_prefix: prefix,
-        // If the endpoint is overridden, we assume that the user wants path-style URLs.
-        path_style,
-    })
+    }
 }
 
-fn get_store_from_opts(store_path: &str) -> Result<Box<dyn Store>> {
-    if store_path.starts_with("s3://") {
-        let url = url::Url::parse(store_path)?;
-        let bucket = url
-            .host_str()
-            .ok_or_else(|| anyhow::anyhow!("Invalid S3 URL"))?
-            .to_owned();
-        let bucket_prefix = url.path().trim_start_matches('/').to_owned();
-        let bucket_prefix = (!bucket_prefix.is_empty()).then_some(bucket_prefix); // "" => None
-        let config = parse_s3_config_from_env_and_args(bucket, bucket_prefix)?;
-        let store = S3Store::new(config);
+#[derive(Args)]
+struct HistoryArgs {
+    /// Keep up to this many historical versions of each document. 0 disables history.
+    #[clap(long, default_value = "0", env = "Y_SWEET_HISTORY_MAX_VERSIONS")]
+    history_max_versions: usize,
+
+    /// Remove historical versions older than this many seconds.
+    #[clap(long, env = "Y_SWEET_HISTORY_MAX_AGE_SECONDS")]
+    history_max_age_seconds: Option<u64>,
+
+    /// The minimum number of seconds between historical versions of a document.
+    #[clap(long, default_value = "300", env = "Y_SWEET_HISTORY_INTERVAL_SECONDS")]
+    history_interval_seconds: u64,
+}
+
+impl HistoryArgs {
+    fn options(&self) -> Option<HistoryOptions> {
+        (self.history_max_versions > 0).then(|| HistoryOptions {
+            max_versions: self.history_max_versions,
+            max_age: self.history_max_age_seconds.map(Duration::from_secs),
+            min_interval: Duration::from_secs(self.history_interval_seconds),
+        })
+    }
+}
+
+#[derive(Args)]
+struct LimitsArgs {
+    /// Reject updates that would grow a document beyond this many bytes, and make
+    /// the document read-only.
+    #[clap(long, env = "Y_SWEET_MAX_DOC_SIZE")]
+    max_doc_size: Option<usize>,
+
+    /// Reject single updates larger than this many bytes.
+    #[clap(long, env = "Y_SWEET_MAX_UPDATE_SIZE")]
+    max_update_size: Option<usize>,
+}
+
+impl LimitsArgs {
+    fn limits(&self) -> DocLimits {
+        DocLimits {
+            max_doc_size: self.max_doc_size,
+            max_update_size: self.max_update_size,
+        }
+    }
+}
+
+#[derive(Args)]
+struct CompactionArgs {
+    /// Compact a document in the background when it has changed and this many
+    /// seconds have passed since it was last compacted.
+    #[clap(long, env = "Y_SWEET_COMPACT_INTERVAL_SECONDS")]
+    compact_interval_seconds: Option<u64>,
+
+    /// Compact a document in the background when its stored size has grown by
+    /// this factor since it was last compacted.
+    #[clap(long, env = "Y_SWEET_COMPACT_GROWTH_FACTOR")]
+    compact_growth_factor: Option<f64>,
+}
+
+impl CompactionArgs {
+    fn options(&self) -> CompactionOptions {
+        CompactionOptions {
+            interval: self.compact_interval_seconds.map(Duration::from_secs),
+            growth_factor: self.compact_growth_factor,
+        }
+    }
+}
+
+#[derive(Args)]
+struct ResidentArgs {
+    /// Evict idle documents from memory, least recently used first, when more than
+    /// this many are loaded.
+    #[clap(long, env = "Y_SWEET_MAX_RESIDENT_DOCS")]
+    max_resident_docs: Option<usize>,
+
+    /// Evict idle documents from memory, least recently used first, when loaded
+    /// documents total more than this many bytes.
+    #[clap(long, env = "Y_SWEET_MAX_RESIDENT_BYTES")]
+    max_resident_bytes: Option<usize>,
+}
+
+impl ResidentArgs {
+    fn limits(&self) -> ResidentLimits {
+        ResidentLimits {
+            max_docs: self.max_resident_docs,
+            max_bytes: self.max_resident_bytes,
+        }
+    }
+}
+
+#[derive(Args)]
+struct CacheArgs {
+    /// Cache objects from the store in this local directory.
+    #[clap(long, env = "Y_SWEET_CACHE_DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// The maximum size of the local cache, in bytes.
+    #[clap(long, default_value = "1073741824", env = "Y_SWEET_CACHE_MAX_BYTES")]
+    cache_max_bytes: u64,
+
+    /// Acknowledge unconditional writes once they are in the local cache, and
+    /// write them to the store in the background.
+    #[clap(long, env = "Y_SWEET_CACHE_WRITE_BEHIND")]
+    cache_write_behind: bool,
+}
+
+impl CacheArgs {
+    fn options(&self) -> Option<CacheOptions> {
+        self.cache_dir.as_ref().map(|dir| CacheOptions {
+            dir: dir.clone(),
+            max_bytes: self.cache_max_bytes,
+            write_mode: if self.cache_write_behind {
+                CacheWriteMode::WriteBehind
+            } else {
+                CacheWriteMode::WriteThrough
+            },
+        })
+    }
+}
+
+#[derive(Args)]
+struct MirrorArgs {
+    /// Also write every object to this store, e.g. a second bucket or a local
+    /// directory, and read from it when the main store is unavailable.
+    #[clap(long, env = "Y_SWEET_MIRROR_STORE")]
+    mirror_store: Option<String>,
+
+    /// Whether the mirror is required: "primary" (only the main store must be
+    /// available) or "both" (the mirror must be available at startup, and a write
+    /// fails unless both stores accept it). Either way, failed writes to the
+    /// mirror are queued and repaired by reconciliation.
+    #[clap(long, default_value = "primary", env = "Y_SWEET_MIRROR_CONSISTENCY")]
+    mirror_consistency: MirrorConsistency,
+
+    /// How often to copy objects missing from the mirror, in seconds.
+    #[clap(
+        long,
+        default_value = "3600",
+        env = "Y_SWEET_MIRROR_RECONCILE_INTERVAL_SECONDS"
+    )]
+    mirror_reconcile_interval_seconds: u64,
+}
+
+impl MirrorArgs {
+    /// Wrap `primary` with the mirror, if one is set, and reconcile it in the
+    /// background until `cancellation_token` is cancelled.
+    fn wrap(
+        &self,
+        registry: &StoreRegistry,
+        primary: Box<dyn Store>,
+        cancellation_token: CancellationToken,
+    ) -> Result<Box<dyn Store>> {
+        let Some(mirror_store) = &self.mirror_store else {
+            return Ok(primary);
+        };
+        let mirror = registry.open(mirror_store)?;
+        let store = MirrorStore::new(primary, mirror, self.mirror_consistency);
+
+        let reconciler = store.clone();
+        let interval = Duration::from_secs(self.mirror_reconcile_interval_seconds);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                let result = tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    _ = interval.tick() => tokio::select! {
+                        _ = cancellation_token.cancelled() => break,
+                        result = reconciler.reconcile("") => result,
+                    },
+                };
+                match result {
+                    Ok(0) => {}
+                    Ok(copied) => tracing::info!(copied, "Reconciled mirror store."),
+                    Err(e) => tracing::error!(?e, "Failed to reconcile mirror store."),
+                }
+            }
+        });
+
         Ok(Box::new(store))
-    } else {
-        Ok(Box::new(FileSystemStore::new(PathBuf::from(store_path))?))
     }
 }
 
+/// Wait for SIGINT (Ctrl+C) or SIGTERM.
+async fn shutdown_signal() {
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {
+            tracing::info!("Received Ctrl+C, shutting down.");
+        },
+        _ = async {
+            #[cfg(unix)]
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                Ok(mut signal) => signal.recv().await,
+                Err(e) => {
+                    tracing::error!("Failed to install SIGTERM handler: {}", e);
+                    std::future::pending::<Option<()>>().await
+                }
+            }
+
+            #[cfg(not(unix))]
+            std::future::pending::<Option<()>>().await
+        } => {
+            tracing::info!("Received SIGTERM, shutting down.");
+        }
+    }
+}
+
+fn get_store_from_opts(
+    registry: &StoreRegistry,
+    store_path: &str,
+    encryption: &EncryptionArgs,
+    cache: Option<CacheOptions>,
+    mirror: Option<(&MirrorArgs, CancellationToken)>,
+) -> Result<Box<dyn Store>> {
+    let mut store = registry.open(store_path)?;
+    if let Some((mirror, cancellation_token)) = mirror {
+        store = mirror.wrap(registry, store, cancellation_token)?;
+    }
+    if let Some(cache) = cache {
+        // Cache below encryption, so that cached objects are encrypted at rest too.
+        store = Box::new(CachingStore::new(store, cache)?);
+    }
+    Ok(encryption.wrap(store))
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let opts = Opts::parse();
+    let registry = StoreRegistry::default();
 
     let filter = EnvFilter::builder()
         .with_default_directive(LevelFilter::INFO.into())
@@ -164,9 +478,21 @@ async fn main() -> Result<()> {
             port,
             host,
             checkpoint_freq_seconds,
+            shutdown_timeout_seconds,
+            unready_after_checkpoints,
             store,
+            encryption,
             auth,
             url_prefix,
+            snapshot_compression,
+            snapshot_recovery,
+            history,
+            update_log_compact_after,
+            limits,
+            compaction,
+            cache,
+            resident,
+            mirror,
             prod,
         } => {
             let auth = if let Some(auth) = auth {
@@ -183,12 +509,28 @@ async fn main() -> Result<()> {
 
             let listener = TcpListener::bind(addr).await?;
             let addr = listener.local_addr()?;
+            let token = CancellationToken::new();
 
             let store = if let Some(store) = store {
-                let store = get_store_from_opts(store)?;
+                let store = get_store_from_opts(
+                    &registry,
+                    store,
+                    encryption,
+                    cache.options(),
+                    Some((mirror, token.clone())),
+                )?;
                 store.init().await?;
                 Some(store)
             } else {
+                if encryption.encryption_keys.is_some() {
+                    anyhow::bail!("Encryption keys were given, but no store is set.");
+                }
+                if cache.cache_dir.is_some() {
+                    anyhow::bail!("A cache directory was given, but no store is set.");
+                }
+                if mirror.mirror_store.is_some() {
+                    anyhow::bail!("A mirror store was given, but no store is set.");
+                }
                 tracing::warn!("No store set. Documents will be stored in memory only.");
                 None
             };
@@ -197,8 +539,6 @@ async fn main() -> Result<()> {
                 print_server_url(auth.as_ref(), url_prefix.as_ref(), addr);
             }
 
-            let token = CancellationToken::new();
-
             let server = y_sweet::server::Server::new(
                 store,
                 std::time::Duration::from_secs(*checkpoint_freq_seconds),
@@ -207,7 +547,22 @@ async fn main() -> Result<()> {
                 token.clone(),
                 true,
             )
-            .await?;
+            .await?
+            .with_persistence_options(PersistenceOptions {
+                compression: *snapshot_compression,
+                recovery: *snapshot_recovery,
+                history: history.options(),
+                update_log: update_log_compact_after.map(|compact_after_segments| {
+                    UpdateLogOptions {
+                        compact_after_segments,
+                    }
+                }),
+            })
+            .with_doc_limits(limits.limits())
+            .with_compaction_options(compaction.options())
+            .with_shutdown_timeout(Duration::from_secs(*shutdown_timeout_seconds))
+            .with_unready_after_checkpoints(*unready_after_checkpoints)
+            .with_resident_limits(resident.limits());
 
             let prod = *prod;
             let handle = tokio::spawn(async move {
@@ -216,11 +571,7 @@ async fn main() -> Result<()> {
 
             tracing::info!("Listening on ws://{}", addr);
 
-            tokio::signal::ctrl_c()
-                .await
-                .expect("Failed to install CTRL+C signal handler");
-
-            tracing::info!("Shutting down.");
+            shutdown_signal().await;
             token.cancel();
 
             handle.await?;
@@ -240,8 +591,12 @@ async fn main() -> Result<()> {
                 print_auth_message(&auth);
             }
         }
-        ServSubcommand::ConvertFromUpdate { store, doc_id } => {
-            let store = get_store_from_opts(store)?;
+        ServSubcommand::ConvertFromUpdate {
+            store,
+            doc_id,
+            encryption,
+        } => {
+            let store = get_store_from_opts(&registry, store, encryption, None, None)?;
             store.init().await?;
 
             let mut stdin = tokio::io::stdin();
@@ -250,6 +605,38 @@ async fn main() -> Result<()> {
 
             y_sweet::convert::convert(store, &buf, doc_id).await?;
         }
+        ServSubcommand::Migrate {
+            source,
+            destination,
+            resume,
+            dry_run,
+            encryption,
+        } => {
+            let source = get_store_from_opts(&registry, source, encryption, None, None)?;
+            source.init().await?;
+            let destination = get_store_from_opts(&registry, destination, encryption, None, None)?;
+            destination.init().await?;
+
+            let options = MigrateOptions {
+                dry_run: *dry_run,
+                resume: *resume,
+            };
+            let report = migrate(Arc::new(source), Arc::new(destination), options).await?;
+
+            println!(
+                "{} {} documents, skipped {}, failed {}.",
+                if *dry_run { "Would copy" } else { "Copied" },
+                report.copied.len(),
+                report.skipped.len(),
+                report.failed.len()
+            );
+            for (doc_id, error) in &report.failed {
+                println!("  {}: {}", doc_id, error);
+            }
+            if !report.failed.is_empty() {
+                anyhow::bail!("Failed to migrate {} documents.", report.failed.len());
+            }
+        }
         ServSubcommand::Version => {
             println!("{}", VERSION);
         }
@@ -257,6 +644,14 @@ async fn main() -> Result<()> {
             port,
             host,
             checkpoint_freq_seconds,
+            shutdown_timeout_seconds,
+            snapshot_compression,
+            snapshot_recovery,
+            history,
+            update_log_compact_after,
+            limits,
+            compaction,
+            encryption,
         } => {
             let doc_id = env::var("SESSION_BACKEND_KEY").expect("SESSION_BACKEND_KEY must be set");
 
@@ -282,8 +677,8 @@ async fn main() -> Result<()> {
                 };
 
                 let s3_config = parse_s3_config_from_env_and_args(bucket, prefix)?;
-                let store = S3Store::new(s3_config);
-                let store: Box<dyn Store> = Box::new(store);
+                let store = S3Store::new(s3_config)?;
+                let store = encryption.wrap(Box::new(store));
                 store.init().await?;
                 Some(store)
             } else {
@@ -303,7 +698,20 @@ async fn main() -> Result<()> {
                 cancellation_token.clone(),
                 false,
             )
-            .await?;
+            .await?
+            .with_persistence_options(PersistenceOptions {
+                compression: *snapshot_compression,
+                recovery: *snapshot_recovery,
+                history: history.options(),
+                update_log: update_log_compact_after.map(|compact_after_segments| {
+                    UpdateLogOptions {
+                        compact_after_segments,
+                    }
+                }),
+            })
+            .with_doc_limits(limits.limits())
+            .with_compaction_options(compaction.options())
+            .with_shutdown_timeout(Duration::from_secs(*shutdown_timeout_seconds));
 
             // Load the one document we're operating with
             server
@@ -319,34 +727,16 @@ async fn main() -> Result<()> {
             let listener = TcpListener::bind(addr).await?;
             let addr = listener.local_addr()?;
 
-            tokio::spawn(async move {
+            let handle = tokio::spawn(async move {
                 server.serve_doc(listener, false).await.unwrap();
             });
 
             tracing::info!("Listening on http://{}", addr);
 
-            tokio::select! {
-                _ = tokio::signal::ctrl_c() => {
-                    tracing::info!("Received Ctrl+C, shutting down.");
-                },
-                _ = async {
-                    #[cfg(unix)]
-                    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
-                        Ok(mut signal) => signal.recv().await,
-                        Err(e) => {
-                            tracing::error!("Failed to install SIGTERM handler: {}", e);
-                            std::future::pending::<Option<()>>().await
-                        }
-                    }
-
-                    #[cfg(not(unix))]
-                    std::future::pending::<Option<()>>().await
-                } => {
-                    tracing::info!("Received SIGTERM, shutting down.");
-                }
-            }
-
+            shutdown_signal().await;
             cancellation_token.cancel();
+
+            handle.await?;
             tracing::info!("Server shut down.");
         }
     }
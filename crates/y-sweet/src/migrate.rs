@@ -0,0 +1,233 @@
+use anyhow::{Context, Result};
+use std::{collections::BTreeMap, sync::Arc};
+use y_sweet_core::{doc_sync::DocWithSyncKv, store::Store};
+
+const LIST_PAGE_SIZE: usize = 1000;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MigrateOptions {
+    /// Check and report what would be copied without writing anything.
+    pub dry_run: bool,
+    /// Skip documents whose objects all exist in the destination already, so that
+    /// an interrupted migration can be continued.
+    pub resume: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct MigrateReport {
+    /// Documents that were copied and verified, or that would be with `dry_run`.
+    pub copied: Vec<String>,
+    /// Documents skipped because `resume` found them in the destination already.
+    pub skipped: Vec<String>,
+    /// Documents that could not be copied or verified, with the reason.
+    pub failed: Vec<(String, String)>,
+}
+
+/// List every object in `store`, grouped by the document it belongs to.
+async fn list_documents(store: &dyn Store) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut documents: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut cursor = None;
+    loop {
+        let page = store
+            .list("", cursor.as_deref(), LIST_PAGE_SIZE)
+            .await
+            .context("Failed to list source store.")?;
+        for key in page.keys {
+            if let Some((doc_id, _)) = key.split_once('/') {
+                documents.entry(doc_id.to_string()).or_default().push(key);
+            }
+        }
+        match page.next_cursor {
+            Some(next) => cursor = Some(next),
+            None => return Ok(documents),
+        }
+    }
+}
+
+/// Load a document through `DocWithSyncKv`, returning its state as an update.
+async fn load_doc(store: &Arc<Box<dyn Store>>, doc_id: &str) -> Result<Vec<u8>> {
+    let doc = DocWithSyncKv::new(doc_id, Some(store.clone()), || ()).await?;
+    Ok(doc.as_update())
+}
+
+/// Copy one document's objects, then check that the copy loads to the same state
+/// as the original.
+async fn migrate_doc(
+    source: &Arc<Box<dyn Store>>,
+    destination: &Arc<Box<dyn Store>>,
+    doc_id: &str,
+    keys: &[String],
+    dry_run: bool,
+) -> Result<()> {
+    let expected = load_doc(source, doc_id)
+        .await
+        .context("Failed to load source document.")?;
+    if dry_run {
+        return Ok(());
+    }
+
+    // The snapshot is written last, so that a document only looks complete in the
+    // destination once everything it refers to has been copied.
+    let snapshot_key = format!("{}/data.ysweet", doc_id);
+    let (snapshot, objects): (Vec<&String>, Vec<&String>) =
+        keys.iter().partition(|key| **key == snapshot_key);
+    for key in objects.into_iter().chain(snapshot) {
+        // Objects removed since they were listed, like compacted log segments,
+        // are not needed.
+        if let Some(value) = source.get(key).await? {
+            destination.set(key, value).await?;
+        }
+    }
+
+    let actual = load_doc(destination, doc_id)
+        .await
+        .context("Failed to load copied document.")?;
+    anyhow::ensure!(
+        actual == expected,
+        "Copied document does not match the source."
+    );
+    Ok(())
+}
+
+/// Copy every document in `source` to `destination`, verifying each copy by
+/// loading it. Documents that fail are reported rather than stopping the migration.
+pub async fn migrate(
+    source: Arc<Box<dyn Store>>,
+    destination: Arc<Box<dyn Store>>,
+    options: MigrateOptions,
+) -> Result<MigrateReport> {
+    let documents = list_documents(source.as_ref().as_ref()).await?;
+    tracing::info!(documents = documents.len(), "Listed source documents.");
+
+    let mut report = MigrateReport::default();
+    for (doc_id, keys) in documents {
+        if options.resume && all_exist(destination.as_ref().as_ref(), &keys).await? {
+            tracing::info!(doc_id, "Already migrated, skipping.");
+            report.skipped.push(doc_id);
+            continue;
+        }
+
+        match migrate_doc(&source, &destination, &doc_id, &keys, options.dry_run).await {
+            Ok(()) => {
+                tracing::info!(
+                    doc_id,
+                    objects = keys.len(),
+                    dry_run = options.dry_run,
+                    "Migrated document."
+                );
+                report.copied.push(doc_id);
+            }
+            Err(e) => {
+                tracing::error!(doc_id, ?e, "Failed to migrate document.");
+                report.failed.push((doc_id, format!("{:#}", e)));
+            }
+        }
+    }
+    Ok(report)
+}
+
+async fn all_exist(store: &dyn Store, keys: &[String]) -> Result<bool> {
+    for key in keys {
+        if !store.exists(key).await? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use y_sweet_core::store::memory::MemoryStore;
+    use yrs::{Doc, GetString, ReadTxn, StateVector, Text, Transact};
+
+    async fn write_doc(store: &MemoryStore, doc_id: &str, content: &str) {
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text("text");
+        text.insert(&mut doc.transact_mut(), 0, content);
+        let update = doc
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+        crate::convert::convert(Box::new(store.clone()), &update, doc_id)
+            .await
+            .unwrap();
+    }
+
+    fn stores() -> (MemoryStore, MemoryStore) {
+        (MemoryStore::new(), MemoryStore::new())
+    }
+
+    async fn run(
+        source: &MemoryStore,
+        destination: &MemoryStore,
+        options: MigrateOptions,
+    ) -> MigrateReport {
+        migrate(
+            Arc::new(Box::new(source.clone())),
+            Arc::new(Box::new(destination.clone())),
+            options,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn copies_and_verifies_documents() {
+        let (source, destination) = stores();
+        write_doc(&source, "a", "hello").await;
+        write_doc(&source, "b", "world").await;
+        Store::set(&source, "c/data.ysweet", b"not a snapshot".to_vec())
+            .await
+            .unwrap();
+
+        let report = run(&source, &destination, MigrateOptions::default()).await;
+        assert_eq!(report.copied, vec!["a", "b"]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, "c");
+
+        let store: Arc<Box<dyn Store>> = Arc::new(Box::new(destination.clone()));
+        let doc = DocWithSyncKv::new("b", Some(store), || ()).await.unwrap();
+        let awareness = doc.awareness();
+        let awareness = awareness.read().unwrap();
+        let text = awareness.doc.get_or_insert_text("text");
+        assert_eq!(text.get_string(&awareness.doc.transact()), "world");
+    }
+
+    #[tokio::test]
+    async fn dry_run_writes_nothing() {
+        let (source, destination) = stores();
+        write_doc(&source, "a", "hello").await;
+
+        let report = run(
+            &source,
+            &destination,
+            MigrateOptions {
+                dry_run: true,
+                resume: false,
+            },
+        )
+        .await;
+        assert_eq!(report.copied, vec!["a"]);
+        assert!(destination.is_empty());
+    }
+
+    #[tokio::test]
+    async fn resumes_by_skipping_copied_documents() {
+        let (source, destination) = stores();
+        write_doc(&source, "a", "hello").await;
+        run(&source, &destination, MigrateOptions::default()).await;
+        write_doc(&source, "b", "world").await;
+
+        let report = run(
+            &source,
+            &destination,
+            MigrateOptions {
+                dry_run: false,
+                resume: true,
+            },
+        )
+        .await;
+        assert_eq!(report.skipped, vec!["a"]);
+        assert_eq!(report.copied, vec!["b"]);
+    }
+}
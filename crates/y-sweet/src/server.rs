@@ -2,25 +2,29 @@ use anyhow::{anyhow, Result};
 use axum::{
     body::Bytes,
     extract::{
-        ws::{Message, WebSocket},
+        ws::{close_code, CloseFrame, Message, WebSocket},
         Path, Query, Request, State, WebSocketUpgrade,
     },
     http::{
-        header::{HeaderMap, HeaderName},
+        header::{self, HeaderMap, HeaderName},
         StatusCode,
     },
     middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, post},
     Json, Router,
 };
 use axum_extra::typed_header::TypedHeader;
 use dashmap::{mapref::one::MappedRef, DashMap};
-use futures::{SinkExt, StreamExt};
+use futures::{future::join_all, SinkExt, StreamExt};
 use serde::Deserialize;
 use serde_json::{json, Value};
 use std::{
-    sync::{Arc, RwLock},
+    future::IntoFuture,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock, Weak,
+    },
     time::Duration,
 };
 use tokio::{
@@ -33,17 +37,31 @@ use url::Url;
 use y_sweet_core::{
     api_types::{
         validate_doc_name, AuthDocRequest, Authorization, ClientToken, DocCreationRequest,
-        NewDocResponse,
+        DocListResponse, DocVersion, DocVersionsResponse, NewDocResponse, QuarantineResponse,
+        QuarantinedSnapshot, UnpersistedDoc, UnpersistedDocsResponse,
     },
     auth::{Authenticator, ExpirationTimeEpochMillis, DEFAULT_EXPIRATION_SECONDS},
+    compaction::CompactionOptions,
     doc_connection::DocConnection,
     doc_sync::DocWithSyncKv,
+    history::{list_versions, load_version},
+    limits::{load_limits, save_limits, DocLimiter, DocLimits, LimitExceeded, StoredLimits},
+    quarantine::{list_quarantined, load_quarantined, remove_quarantined},
+    snapshot::{decode_snapshot, SnapshotData},
     store::Store,
     sync::awareness::Awareness,
-    sync_kv::SyncKv,
+    sync_kv::{PersistenceOptions, SyncKv},
 };
 
 const PLANE_VERIFIED_USER_DATA_HEADER: &str = "x-verified-user-data";
+const DEFAULT_DOC_LIST_LIMIT: usize = 100;
+const MAX_DOC_LIST_LIMIT: usize = 1000;
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(20);
+/// The longest to wait between attempts to persist a document that keeps failing.
+const MAX_PERSIST_RETRY_DELAY: Duration = Duration::from_secs(300);
+/// By default, the server is not ready once a document has failed to persist
+/// for this many checkpoints.
+const DEFAULT_UNREADY_AFTER_CHECKPOINTS: u32 = 6;
 
 fn current_time_epoch_millis() -> u64 {
     let now = std::time::SystemTime::now();
@@ -76,6 +94,14 @@ impl std::fmt::Display for AppError {
 
 pub struct Server {
     docs: Arc<DashMap<String, DocWithSyncKv>>,
+    /// A lock per document that is being loaded, so that concurrent requests for a
+    /// document that is not loaded yet wait for a single load.
+    loading: DashMap<String, Arc<tokio::sync::Mutex<()>>>,
+    /// When each loaded document was last requested, for LRU eviction.
+    last_used: DashMap<String, std::time::Instant>,
+    resident_limits: ResidentLimits,
+    /// Held while evicting, so that only one caller evicts at a time.
+    evicting: tokio::sync::Mutex<()>,
     doc_worker_tracker: TaskTracker,
     store: Option<Arc<Box<dyn Store>>>,
     checkpoint_freq: Duration,
@@ -85,6 +111,121 @@ pub struct Server {
     /// Whether to garbage collect docs that are no longer in use.
     /// Disabled for single-doc mode, since we only have one doc.
     doc_gc: bool,
+    persistence_options: PersistenceOptions,
+    /// Limits applied to every document, unless overridden for that document.
+    doc_limits: DocLimits,
+    doc_limit_overrides: Arc<DashMap<String, DocLimits>>,
+    /// Held while writing a document's limits to the store, so that each write
+    /// stores the latest limits.
+    saving_limits: Arc<tokio::sync::Mutex<()>>,
+    /// How long to spend persisting documents when shutting down.
+    shutdown_timeout: Duration,
+    compaction_options: CompactionOptions,
+    metrics: Arc<Metrics>,
+    /// Documents whose most recent persist failed, which are being retried.
+    persist_failures: Arc<DashMap<String, PersistFailure>>,
+    /// How many checkpoints a document may fail to persist for before the server
+    /// reports that it is not ready.
+    unready_after_checkpoints: u32,
+}
+
+/// How long to wait before persisting a document again after `failures`
+/// consecutive failures: the checkpoint frequency, doubling with each failure.
+fn retry_delay(checkpoint_freq: Duration, failures: u32) -> Duration {
+    checkpoint_freq
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(MAX_PERSIST_RETRY_DELAY)
+}
+
+/// Store a document's own limits, and whether it is read-only according to
+/// `limiter` (if it is loaded), so that they survive it being reloaded.
+async fn save_doc_limits(
+    store: &dyn Store,
+    doc_limit_overrides: &DashMap<String, DocLimits>,
+    saving_limits: &tokio::sync::Mutex<()>,
+    doc_id: &str,
+    limiter: Option<&DocLimiter>,
+) -> Result<()> {
+    let _saving = saving_limits.lock().await;
+    let limits = StoredLimits {
+        limits: doc_limit_overrides
+            .get(doc_id)
+            .map(|limits| *limits)
+            .unwrap_or_default(),
+        read_only: limiter.is_some_and(|limiter| limiter.is_read_only()),
+    };
+    save_limits(store, doc_id, &limits).await
+}
+
+struct PersistFailure {
+    failures: u32,
+    /// When persisting first failed, in milliseconds since the Unix epoch.
+    since: u64,
+    last_error: String,
+}
+
+/// Counters reported by the metrics endpoint.
+#[derive(Default)]
+pub struct Metrics {
+    pub compactions: AtomicU64,
+    pub compaction_bytes_reclaimed: AtomicU64,
+}
+
+impl Metrics {
+    /// The counters in the Prometheus text format.
+    fn render(&self) -> String {
+        let counters = [
+            (
+                "y_sweet_compactions_total",
+                "Documents compacted.",
+                &self.compactions,
+            ),
+            (
+                "y_sweet_compaction_bytes_reclaimed_total",
+                "Bytes of stored document data reclaimed by compaction.",
+                &self.compaction_bytes_reclaimed,
+            ),
+        ];
+        counters
+            .iter()
+            .map(|(name, help, value)| {
+                format!(
+                    "# HELP {name} {help}\n# TYPE {name} counter\n{name} {}\n",
+                    value.load(Ordering::Relaxed)
+                )
+            })
+            .collect()
+    }
+}
+
+/// Caps on the documents held in memory. When a load exceeds them, idle documents
+/// are persisted and evicted, least recently used first. Documents with connected
+/// clients are never evicted.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ResidentLimits {
+    /// The maximum number of loaded documents.
+    pub max_docs: Option<usize>,
+    /// The maximum total size of loaded documents, in bytes of stored data.
+    pub max_bytes: Option<usize>,
+}
+
+impl ResidentLimits {
+    fn exceeded(&self, docs: usize, bytes: usize) -> bool {
+        self.max_docs.is_some_and(|max| docs > max) || self.max_bytes.is_some_and(|max| bytes > max)
+    }
+}
+
+/// What happened to a document when it was persisted on shutdown.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FlushOutcome {
+    /// The document was persisted.
+    Persisted,
+    /// The document had no changes to persist.
+    Clean,
+    /// Persisting the document failed.
+    Failed(String),
+    /// The shutdown timeout passed before the document was persisted.
+    TimedOut,
 }
 
 impl Server {
@@ -98,6 +239,10 @@ impl Server {
     ) -> Result<Self> {
         Ok(Self {
             docs: Arc::new(DashMap::new()),
+            loading: DashMap::new(),
+            last_used: DashMap::new(),
+            resident_limits: ResidentLimits::default(),
+            evicting: tokio::sync::Mutex::new(()),
             doc_worker_tracker: TaskTracker::new(),
             store: store.map(Arc::new),
             checkpoint_freq,
@@ -105,9 +250,92 @@ impl Server {
             url_prefix,
             cancellation_token,
             doc_gc,
+            persistence_options: PersistenceOptions::default(),
+            doc_limits: DocLimits::default(),
+            doc_limit_overrides: Arc::new(DashMap::new()),
+            saving_limits: Arc::new(tokio::sync::Mutex::new(())),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            compaction_options: CompactionOptions::default(),
+            metrics: Arc::new(Metrics::default()),
+            persist_failures: Arc::new(DashMap::new()),
+            unready_after_checkpoints: DEFAULT_UNREADY_AFTER_CHECKPOINTS,
         })
     }
 
+    /// Set how documents are persisted to the store, e.g. snapshot compression.
+    pub fn with_persistence_options(mut self, persistence_options: PersistenceOptions) -> Self {
+        self.persistence_options = persistence_options;
+        self
+    }
+
+    /// Set how long to spend persisting documents when shutting down.
+    pub fn with_shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// Set how many checkpoints a document may fail to persist for before the
+    /// readiness check fails.
+    pub fn with_unready_after_checkpoints(mut self, checkpoints: u32) -> Self {
+        self.unready_after_checkpoints = checkpoints;
+        self
+    }
+
+    /// Set caps on the documents held in memory. They are checked whenever a
+    /// document is loaded, and only apply when there is a store to evict to.
+    pub fn with_resident_limits(mut self, resident_limits: ResidentLimits) -> Self {
+        self.resident_limits = resident_limits;
+        self
+    }
+
+    /// Set when documents are compacted in the background. Compaction is disabled
+    /// by default.
+    pub fn with_compaction_options(mut self, compaction_options: CompactionOptions) -> Self {
+        self.compaction_options = compaction_options;
+        self
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Set the size limits applied to every document.
+    pub fn with_doc_limits(mut self, doc_limits: DocLimits) -> Self {
+        self.doc_limits = doc_limits;
+        self
+    }
+
+    /// The limits in effect for a document: its own limits where set, and the
+    /// server-wide limits otherwise.
+    pub fn doc_limits(&self, doc_id: &str) -> DocLimits {
+        match self.doc_limit_overrides.get(doc_id) {
+            Some(limits) => limits.or(self.doc_limits),
+            None => self.doc_limits,
+        }
+    }
+
+    /// Override the server-wide limits for one document, returning the limits now
+    /// in effect. Overrides are stored with the document when there is a store.
+    pub async fn set_doc_limits(&self, doc_id: &str, limits: DocLimits) -> Result<DocLimits> {
+        self.doc_limit_overrides.insert(doc_id.to_string(), limits);
+        let limits = self.doc_limits(doc_id);
+        let limiter = self.docs.get(doc_id).map(|dwskv| dwskv.limiter());
+        if let Some(limiter) = &limiter {
+            limiter.set_limits(limits);
+        }
+        if let Some(store) = &self.store {
+            save_doc_limits(
+                store.as_ref().as_ref(),
+                &self.doc_limit_overrides,
+                &self.saving_limits,
+                doc_id,
+                limiter.as_deref(),
+            )
+            .await?;
+        }
+        Ok(limits)
+    }
+
     pub async fn doc_exists(&self, doc_id: &str) -> bool {
         if self.docs.contains_key(doc_id) {
             return true;
@@ -122,6 +350,109 @@ impl Server {
         }
     }
 
+    /// List up to `limit` document IDs starting with `prefix`, in lexicographic order,
+    /// beginning after the document ID `cursor`. Returns the IDs and, if more
+    /// documents may remain, the cursor for the next page.
+    pub async fn list_docs(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        let Some(store) = &self.store else {
+            let mut docs: Vec<String> = self
+                .docs
+                .iter()
+                .map(|entry| entry.key().clone())
+                .filter(|doc_id| doc_id.starts_with(prefix))
+                .filter(|doc_id| cursor.is_none_or(|cursor| doc_id.as_str() > cursor))
+                .collect();
+            docs.sort();
+            let next_cursor = if docs.len() > limit {
+                docs.truncate(limit);
+                docs.last().cloned()
+            } else {
+                None
+            };
+            return Ok((docs, next_cursor));
+        };
+
+        let page = store.list_docs(prefix, cursor, limit).await?;
+        Ok((page.keys, page.next_cursor))
+    }
+
+    /// List the historical versions of a document, oldest first. Empty if there
+    /// is no store or history is disabled.
+    pub async fn list_doc_versions(&self, doc_id: &str) -> Result<Vec<DocVersion>> {
+        match &self.store {
+            Some(store) => list_versions(store.as_ref().as_ref(), doc_id).await,
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Restore a document to one of its historical versions. Returns `false` if
+    /// the version does not exist.
+    pub async fn restore_doc_version(&self, doc_id: &str, version_id: &str) -> Result<bool> {
+        let Some(store) = &self.store else {
+            return Ok(false);
+        };
+        let Some(snapshot) = load_version(store.as_ref().as_ref(), doc_id, version_id).await?
+        else {
+            return Ok(false);
+        };
+
+        let dwskv = self.get_or_create_doc(doc_id).await?;
+        dwskv.restore(snapshot)?;
+        tracing::info!(doc_id, version_id, "Restored doc version");
+        Ok(true)
+    }
+
+    /// List a document's quarantined snapshots, oldest first. Empty if there is
+    /// no store.
+    pub async fn list_quarantined(&self, doc_id: &str) -> Result<Vec<QuarantinedSnapshot>> {
+        match &self.store {
+            Some(store) => list_quarantined(store.as_ref().as_ref(), doc_id).await,
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Read a quarantined snapshot, or `None` if it does not exist.
+    pub async fn load_quarantined(
+        &self,
+        doc_id: &str,
+        quarantine_id: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        match &self.store {
+            Some(store) => load_quarantined(store.as_ref().as_ref(), doc_id, quarantine_id).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Restore a document to a quarantined snapshot, and remove it from quarantine.
+    pub async fn restore_quarantined(
+        &self,
+        doc_id: &str,
+        quarantine_id: &str,
+        snapshot: SnapshotData,
+    ) -> Result<()> {
+        self.get_or_create_doc(doc_id).await?.restore(snapshot)?;
+        self.discard_quarantined(doc_id, quarantine_id).await?;
+        tracing::info!(doc_id, quarantine_id, "Restored quarantined snapshot");
+        Ok(())
+    }
+
+    /// Discard a quarantined snapshot. Returns `false` if it does not exist.
+    pub async fn discard_quarantined(&self, doc_id: &str, quarantine_id: &str) -> Result<bool> {
+        let Some(store) = &self.store else {
+            return Ok(false);
+        };
+        let removed = remove_quarantined(store.as_ref().as_ref(), doc_id, quarantine_id).await?;
+        if removed {
+            tracing::info!(doc_id, quarantine_id, "Discarded quarantined snapshot");
+        }
+        Ok(removed)
+    }
+
     pub async fn create_doc(&self) -> Result<String> {
         let doc_id = nanoid::nanoid!();
         self.load_doc(&doc_id).await?;
@@ -132,11 +463,62 @@ impl Server {
     pub async fn load_doc(&self, doc_id: &str) -> Result<()> {
         let (send, recv) = channel(1024);
 
-        let dwskv = DocWithSyncKv::new(doc_id, self.store.clone(), move || {
-            send.try_send(()).unwrap();
-        })
+        let dwskv = DocWithSyncKv::new_with_options(
+            doc_id,
+            self.store.clone(),
+            self.persistence_options.clone(),
+            move || {
+                // The channel is closed once the doc's worker has exited, and a full
+                // channel already has a persist pending.
+                let _ = send.try_send(());
+            },
+        )
         .await?;
 
+        let stored_limits = match &self.store {
+            Some(store) => load_limits(store.as_ref().as_ref(), doc_id).await?,
+            None => None,
+        };
+        if let Some(stored_limits) = stored_limits {
+            self.doc_limit_overrides
+                .insert(doc_id.to_string(), stored_limits.limits);
+        }
+        dwskv.set_limits(self.doc_limits(doc_id));
+        if stored_limits.is_some_and(|stored_limits| stored_limits.read_only) {
+            dwskv.limiter().set_read_only();
+        }
+        if let Some(store) = self.store.clone() {
+            let limiter = Arc::downgrade(&dwskv.limiter());
+            let doc_limit_overrides = self.doc_limit_overrides.clone();
+            let saving_limits = self.saving_limits.clone();
+            let tracker = self.doc_worker_tracker.clone();
+            let doc_id = doc_id.to_string();
+            dwskv.limiter().set_read_only_callback(move || {
+                // The callback is called by the limiter, so it is still alive.
+                let limiter = limiter.upgrade();
+                let store = store.clone();
+                let doc_limit_overrides = doc_limit_overrides.clone();
+                let saving_limits = saving_limits.clone();
+                let doc_id = doc_id.clone();
+                tracker.spawn(
+                    async move {
+                        if let Err(e) = save_doc_limits(
+                            store.as_ref().as_ref(),
+                            &doc_limit_overrides,
+                            &saving_limits,
+                            &doc_id,
+                            limiter.as_deref(),
+                        )
+                        .await
+                        {
+                            tracing::error!(?e, "Failed to store that the doc is read-only");
+                        }
+                    }
+                    .in_current_span(),
+                );
+            });
+        }
+
         dwskv
             .sync_kv()
             .persist()
@@ -144,10 +526,12 @@ impl Server {
             .map_err(|e| anyhow!("Error persisting: {:?}", e))?;
 
         {
-            let sync_kv = dwskv.sync_kv();
+            let sync_kv = Arc::downgrade(&dwskv.sync_kv());
             let checkpoint_freq = self.checkpoint_freq;
             let doc_id = doc_id.to_string();
-            let cancellation_token = self.cancellation_token.clone();
+            // Cancelled when the server shuts down, or when the doc is unloaded and
+            // its persistence worker exits, so that this doc's workers all stop.
+            let cancellation_token = self.cancellation_token.child_token();
 
             // Spawn a task to save the document to the store when it changes.
             self.doc_worker_tracker.spawn(
@@ -156,15 +540,32 @@ impl Server {
                     sync_kv,
                     checkpoint_freq,
                     doc_id.clone(),
+                    self.persist_failures.clone(),
+                    self.doc_worker_tracker.clone(),
                     cancellation_token.clone(),
                 )
                 .instrument(span!(Level::INFO, "save_loop", doc_id=?doc_id)),
             );
 
+            if self.compaction_options.is_enabled() {
+                self.doc_worker_tracker.spawn(
+                    Self::doc_compaction_worker(
+                        self.docs.clone(),
+                        doc_id.clone(),
+                        self.compaction_options,
+                        self.metrics.clone(),
+                        checkpoint_freq,
+                        cancellation_token.clone(),
+                    )
+                    .instrument(span!(Level::INFO, "compaction_loop", doc_id=?doc_id)),
+                );
+            }
+
             if self.doc_gc {
                 self.doc_worker_tracker.spawn(
                     Self::doc_gc_worker(
                         self.docs.clone(),
+                        self.doc_limit_overrides.clone(),
                         doc_id.clone(),
                         checkpoint_freq,
                         cancellation_token,
@@ -180,6 +581,7 @@ impl Server {
 
     async fn doc_gc_worker(
         docs: Arc<DashMap<String, DocWithSyncKv>>,
+        doc_limit_overrides: Arc<DashMap<String, DocLimits>>,
         doc_id: String,
         checkpoint_freq: Duration,
         cancellation_token: CancellationToken,
@@ -203,9 +605,14 @@ impl Server {
                     }
 
                     if checkpoints_without_refs >= 2 {
-                        tracing::info!("GCing doc");
-                        docs.remove(&doc_id);
-                        break;
+                        // Only GC a doc whose latest state has been persisted.
+                        if docs.remove_if(&doc_id, |_, doc| !doc.sync_kv().is_dirty()).is_some() {
+                            // Overrides are reloaded from the store on the next load.
+                            doc_limit_overrides.remove(&doc_id);
+                            tracing::info!("GCing doc");
+                            break;
+                        }
+                        tracing::warn!("doc has unpersisted changes, not GCing");
                     }
                 }
                 _ = cancellation_token.cancelled() => {
@@ -216,18 +623,82 @@ impl Server {
         tracing::info!("Exiting gc_loop");
     }
 
+    /// Check every checkpoint whether the document is due for compaction, and
+    /// compact it if so. The persistence worker then writes the compacted snapshot,
+    /// except with the update log, where this writes it by compacting the log.
+    async fn doc_compaction_worker(
+        docs: Arc<DashMap<String, DocWithSyncKv>>,
+        doc_id: String,
+        options: CompactionOptions,
+        metrics: Arc<Metrics>,
+        checkpoint_freq: Duration,
+        cancellation_token: CancellationToken,
+    ) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(checkpoint_freq) => {
+                    let (result, sync_kv) = {
+                        let Some(doc) = docs.get(&doc_id) else {
+                            break;
+                        };
+                        (doc.compact_if_due(&options), doc.sync_kv())
+                    };
+                    match result {
+                        Ok(Some(compaction)) if compaction.reclaimed() > 0 => {
+                            metrics.compactions.fetch_add(1, Ordering::Relaxed);
+                            metrics
+                                .compaction_bytes_reclaimed
+                                .fetch_add(compaction.reclaimed() as u64, Ordering::Relaxed);
+                            tracing::info!(
+                                event = "doc_compacted",
+                                before = compaction.before,
+                                after = compaction.after,
+                                reclaimed = compaction.reclaimed(),
+                                "Compacted doc"
+                            );
+                            // Checkpoints only append to the log, so they would not
+                            // write the compacted data.
+                            if sync_kv.has_update_log() {
+                                if let Err(e) = sync_kv.compact_log().await {
+                                    tracing::error!(?e, "Error writing compacted snapshot.");
+                                }
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => tracing::error!(?e, "Error compacting."),
+                    }
+                }
+                _ = cancellation_token.cancelled() => {
+                    break;
+                }
+            };
+        }
+        tracing::info!("Exiting compaction_loop");
+    }
+
+    /// Persist the document when it changes. Only holds a weak reference, so
+    /// that unloading the document drops it, which closes `recv` and ends the loop.
     async fn doc_persistence_worker(
         mut recv: Receiver<()>,
-        sync_kv: Arc<SyncKv>,
+        sync_kv: Weak<SyncKv>,
         checkpoint_freq: Duration,
         doc_id: String,
+        persist_failures: Arc<DashMap<String, PersistFailure>>,
+        tracker: TaskTracker,
         cancellation_token: CancellationToken,
     ) {
         let mut last_save = std::time::Instant::now();
+        // Consecutive failed persists. While this is nonzero, the document is
+        // persisted again after a backoff, even if it receives no more changes.
+        let mut failures = 0;
 
         loop {
             let is_done = tokio::select! {
                 v = recv.recv() => v.is_none(),
+                _ = tokio::time::sleep(retry_delay(checkpoint_freq, failures)), if failures > 0 => {
+                    tracing::info!(failures, "Retrying persist.");
+                    false
+                }
                 _ = cancellation_token.cancelled() => true,
             };
 
@@ -258,11 +729,55 @@ impl Server {
                     tracing::info!("Done throttling.");
                 }
             }
+            if cancellation_token.is_cancelled() {
+                // The server persists every document once connections are closed.
+                break;
+            }
+
+            let Some(sync_kv) = sync_kv.upgrade() else {
+                break;
+            };
             tracing::info!("Persisting.");
-            if let Err(e) = sync_kv.persist().await {
-                tracing::error!(?e, "Error persisting.");
-            } else {
-                tracing::info!("Done persisting.");
+            match sync_kv.persist().await {
+                Ok(()) => {
+                    if failures > 0 {
+                        tracing::info!(failures, "Persisted after failures.");
+                        persist_failures.remove(&doc_id);
+                    } else {
+                        tracing::info!("Done persisting.");
+                    }
+                    failures = 0;
+
+                    if sync_kv.log_compaction_due() {
+                        let sync_kv = sync_kv.clone();
+                        tracker.spawn(
+                            async move {
+                                if let Err(e) = sync_kv.compact_log().await {
+                                    // The segments stay in the log, so it is retried
+                                    // after the next checkpoint.
+                                    tracing::error!(?e, "Error compacting update log.");
+                                }
+                            }
+                            .in_current_span(),
+                        );
+                    }
+                }
+                Err(e) => {
+                    failures += 1;
+                    let retry_in = retry_delay(checkpoint_freq, failures);
+                    tracing::error!(?e, failures, ?retry_in, "Error persisting.");
+                    persist_failures
+                        .entry(doc_id.clone())
+                        .and_modify(|failure| {
+                            failure.failures = failures;
+                            failure.last_error = e.to_string();
+                        })
+                        .or_insert_with(|| PersistFailure {
+                            failures,
+                            since: current_time_epoch_millis(),
+                            last_error: e.to_string(),
+                        });
+                }
             }
             last_save = std::time::Instant::now();
 
@@ -270,23 +785,173 @@ impl Server {
                 break;
             }
         }
+        // Stop the doc's other workers too.
+        cancellation_token.cancel();
         tracing::info!("Terminating loop for {}", doc_id);
     }
 
+    /// The loaded documents whose latest changes could not be persisted, longest
+    /// failing first.
+    pub fn unpersisted_docs(&self) -> Vec<UnpersistedDoc> {
+        let mut docs: Vec<UnpersistedDoc> = self
+            .persist_failures
+            .iter()
+            .map(|entry| UnpersistedDoc {
+                doc_id: entry.key().clone(),
+                failures: entry.failures,
+                failing_since: entry.since,
+                last_error: entry.last_error.clone(),
+            })
+            .collect();
+        docs.sort_by_key(|doc| doc.failing_since);
+        docs
+    }
+
+    /// The number of documents that have failed to persist for longer than the
+    /// readiness threshold, as of `now` milliseconds since the Unix epoch.
+    fn stale_unpersisted_docs(&self, now: u64) -> usize {
+        let threshold = self
+            .checkpoint_freq
+            .saturating_mul(self.unready_after_checkpoints)
+            .as_millis() as u64;
+        self.persist_failures
+            .iter()
+            .filter(|entry| now.saturating_sub(entry.since) > threshold)
+            .count()
+    }
+
+    /// Persist every document with unsaved changes, within the shutdown timeout.
+    /// Returns the outcome for each loaded document.
+    pub async fn flush_all(&self) -> Vec<(String, FlushOutcome)> {
+        self.flush_all_until(tokio::time::Instant::now() + self.shutdown_timeout)
+            .await
+    }
+
+    async fn flush_all_until(&self, deadline: tokio::time::Instant) -> Vec<(String, FlushOutcome)> {
+        let docs: Vec<(String, Arc<SyncKv>)> = self
+            .docs
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.sync_kv()))
+            .collect();
+
+        join_all(docs.into_iter().map(|(doc_id, sync_kv)| async move {
+            if !sync_kv.is_dirty() {
+                return (doc_id, FlushOutcome::Clean);
+            }
+            let outcome = match tokio::time::timeout_at(deadline, sync_kv.persist()).await {
+                Ok(Ok(())) => FlushOutcome::Persisted,
+                Ok(Err(e)) => FlushOutcome::Failed(e.to_string()),
+                Err(_) => FlushOutcome::TimedOut,
+            };
+            (doc_id, outcome)
+        }))
+        .await
+    }
+
     pub async fn get_or_create_doc(
         &self,
         doc_id: &str,
-    ) -> Result<MappedRef<String, DocWithSyncKv, DocWithSyncKv>> {
-        if !self.docs.contains_key(doc_id) {
-            tracing::info!(doc_id=?doc_id, "Loading doc");
-            self.load_doc(doc_id).await?;
+    ) -> Result<MappedRef<'_, String, DocWithSyncKv, DocWithSyncKv>> {
+        loop {
+            if let Some(doc) = self.docs.get(doc_id) {
+                self.last_used
+                    .insert(doc_id.to_string(), std::time::Instant::now());
+                return Ok(doc.map(|d| d));
+            }
+
+            let lock = self.loading.entry(doc_id.to_string()).or_default().clone();
+            let guard = lock.lock().await;
+            // The lock is removed from `loading` by whoever holds it, once they are
+            // done. If that has happened, check for the doc again and, if it is
+            // still missing, wait on the current lock instead.
+            let is_current = self
+                .loading
+                .get(doc_id)
+                .is_some_and(|current| Arc::ptr_eq(&current, &lock));
+            if !is_current {
+                continue;
+            }
+
+            let result = if self.docs.contains_key(doc_id) {
+                Ok(())
+            } else {
+                tracing::info!(doc_id=?doc_id, "Loading doc");
+                self.load_doc(doc_id).await
+            };
+            self.loading.remove(doc_id);
+            result?;
+            drop(guard);
+            self.evict_idle_docs(doc_id).await;
         }
+    }
 
-        Ok(self
-            .docs
-            .get(doc_id)
-            .ok_or_else(|| anyhow!("Failed to get-or-create doc"))?
-            .map(|d| d))
+    /// If the loaded documents exceed the resident limits, persist and evict idle
+    /// documents other than `keep`, least recently used first, until they do not.
+    async fn evict_idle_docs(&self, keep: &str) {
+        if self.store.is_none() || !self.doc_gc {
+            return;
+        }
+        let Ok(_guard) = self.evicting.try_lock() else {
+            // Another caller is already evicting.
+            return;
+        };
+
+        let mut resident_docs = self.docs.len();
+        let mut resident_bytes: usize = self.docs.iter().map(|entry| entry.sync_kv().size()).sum();
+        if !self.resident_limits.exceeded(resident_docs, resident_bytes) {
+            return;
+        }
+
+        self.last_used
+            .retain(|doc_id, _| self.docs.contains_key(doc_id));
+        let mut candidates: Vec<(std::time::Instant, String)> = self
+            .last_used
+            .iter()
+            .filter(|entry| entry.key() != keep)
+            .map(|entry| (*entry.value(), entry.key().clone()))
+            .collect();
+        candidates.sort();
+
+        for (_, doc_id) in candidates {
+            if !self.resident_limits.exceeded(resident_docs, resident_bytes) {
+                break;
+            }
+            let Some(sync_kv) = self
+                .docs
+                .get(&doc_id)
+                .filter(|doc| !has_clients(doc))
+                .map(|doc| doc.sync_kv())
+            else {
+                continue;
+            };
+
+            if let Err(e) = sync_kv.persist().await {
+                tracing::error!(doc_id, ?e, "Failed to persist doc before eviction");
+                continue;
+            }
+            // A client may have connected or made changes while persisting.
+            let evicted = self
+                .docs
+                .remove_if(&doc_id, |_, doc| {
+                    !has_clients(doc) && !doc.sync_kv().is_dirty()
+                })
+                .is_some();
+            if evicted {
+                self.last_used.remove(&doc_id);
+                self.doc_limit_overrides.remove(&doc_id);
+                resident_docs -= 1;
+                resident_bytes = resident_bytes.saturating_sub(sync_kv.size());
+                tracing::info!(doc_id, "Evicted idle doc");
+            }
+        }
+
+        if self.resident_limits.exceeded(resident_docs, resident_bytes) {
+            tracing::warn!(
+                resident_docs,
+                resident_bytes,
+                "Loaded docs exceed the resident limits, but no more docs are idle"
+            );
+        }
     }
 
     pub fn check_auth(
@@ -320,15 +985,34 @@ impl Server {
     pub fn routes(self: &Arc<Self>) -> Router {
         Router::new()
             .route("/ready", get(ready))
+            .route("/live", get(live))
             .route("/check_store", post(check_store))
             .route("/check_store", get(check_store_deprecated))
+            .route("/metrics", get(metrics))
+            .route("/docs", get(list_docs))
+            .route("/docs/unpersisted", get(list_unpersisted_docs))
             .route("/doc/ws/:doc_id", get(handle_socket_upgrade_deprecated))
             .route("/doc/new", post(new_doc))
             .route("/doc/:doc_id/auth", post(auth_doc))
+            .route("/doc/:doc_id/limits", post(set_doc_limits))
+            .route("/doc/:doc_id/quarantine", get(list_quarantined_snapshots))
+            .route(
+                "/doc/:doc_id/quarantine/:quarantine_id",
+                delete(discard_quarantined_snapshot),
+            )
+            .route(
+                "/doc/:doc_id/quarantine/:quarantine_id/restore",
+                post(restore_quarantined_snapshot),
+            )
             .route("/doc/:doc_id/as-update", get(get_doc_as_update_deprecated))
             .route("/doc/:doc_id/update", post(update_doc_deprecated))
             .route("/d/:doc_id/as-update", get(get_doc_as_update))
             .route("/d/:doc_id/update", post(update_doc))
+            .route("/d/:doc_id/versions", get(list_doc_versions))
+            .route(
+                "/d/:doc_id/versions/:version_id/restore",
+                post(restore_doc_version),
+            )
             .route(
                 "/d/:doc_id/ws/:doc_id2",
                 get(handle_socket_upgrade_full_path),
@@ -341,6 +1025,7 @@ impl Server {
             .route("/ws/:doc_id", get(handle_socket_upgrade_single))
             .route("/as-update", get(get_doc_as_update_single))
             .route("/update", post(update_doc_single))
+            .route("/metrics", get(metrics))
             .with_state(self.clone())
     }
 
@@ -358,12 +1043,65 @@ impl Server {
             routes.layer(middleware::from_fn(Self::redact_error_middleware))
         };
 
-        axum::serve(listener, app.into_make_service())
+        let serve = axum::serve(listener, app.into_make_service())
             .with_graceful_shutdown(async move { token.cancelled().await })
-            .await?;
+            .into_future();
+        tokio::pin!(serve);
+
+        // The shutdown timeout bounds draining connections and workers as well as
+        // persisting, so it starts as soon as shutdown does.
+        let stopped = tokio::select! {
+            result = &mut serve => {
+                result?;
+                true
+            }
+            _ = self.cancellation_token.cancelled() => false,
+        };
+        let deadline = tokio::time::Instant::now() + self.shutdown_timeout;
+
+        if !stopped {
+            match tokio::time::timeout_at(deadline, &mut serve).await {
+                Ok(result) => result?,
+                Err(_) => tracing::warn!("Timed out waiting for connections to close"),
+            }
+        }
 
         self.doc_worker_tracker.close();
-        self.doc_worker_tracker.wait().await;
+        if tokio::time::timeout_at(deadline, self.doc_worker_tracker.wait())
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                remaining = self.doc_worker_tracker.len(),
+                "Timed out waiting for doc workers and connections to finish"
+            );
+        }
+
+        let outcomes = self.flush_all_until(deadline).await;
+        let mut failed = 0;
+        for (doc_id, outcome) in &outcomes {
+            match outcome {
+                FlushOutcome::Persisted => tracing::info!(doc_id, "Persisted doc on shutdown"),
+                FlushOutcome::Clean => tracing::debug!(doc_id, "Doc had no unsaved changes"),
+                FlushOutcome::Failed(e) => {
+                    failed += 1;
+                    tracing::error!(doc_id, error = e, "Failed to persist doc on shutdown");
+                }
+                FlushOutcome::TimedOut => {
+                    failed += 1;
+                    tracing::error!(doc_id, "Timed out persisting doc on shutdown");
+                }
+            }
+        }
+        tracing::info!(
+            docs = outcomes.len(),
+            persisted = outcomes
+                .iter()
+                .filter(|(_, outcome)| *outcome == FlushOutcome::Persisted)
+                .count(),
+            failed,
+            "Flushed docs on shutdown"
+        );
 
         Ok(())
     }
@@ -404,11 +1142,25 @@ impl Server {
     }
 }
 
+/// Whether a document has connected clients, each of which holds its awareness.
+fn has_clients(doc: &DocWithSyncKv) -> bool {
+    // Bound first, so that the temporary from `awareness()` is not counted.
+    let awareness = Arc::downgrade(&doc.awareness());
+    awareness.strong_count() > 1
+}
+
 #[derive(Deserialize)]
 struct HandlerParams {
     token: Option<String>,
 }
 
+#[derive(Deserialize)]
+struct ListDocsParams {
+    prefix: Option<String>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
 async fn get_doc_as_update(
     State(server_state): State<Arc<Server>>,
     Path(doc_id): Path<String>,
@@ -482,6 +1234,10 @@ async fn update_doc_inner(
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
 
     if let Err(err) = dwskv.apply_update(&body) {
+        if err.is::<LimitExceeded>() {
+            tracing::warn!(?err, "Rejected update exceeding document limits");
+            return Err(AppError(StatusCode::PAYLOAD_TOO_LARGE, err));
+        }
         tracing::error!(?err, "Failed to apply update");
         return Err(AppError(StatusCode::INTERNAL_SERVER_ERROR, err));
     }
@@ -489,6 +1245,51 @@ async fn update_doc_inner(
     Ok(StatusCode::OK.into_response())
 }
 
+async fn list_doc_versions(
+    Path(doc_id): Path<String>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Json<DocVersionsResponse>, AppError> {
+    // All authorization types allow reading the document's history.
+    let token = get_token_from_header(auth_header);
+    let _ = server_state.verify_doc_token(token.as_deref(), &doc_id)?;
+
+    let versions = server_state
+        .list_doc_versions(&doc_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    Ok(Json(DocVersionsResponse { versions }))
+}
+
+async fn restore_doc_version(
+    Path((doc_id, version_id)): Path<(String, String)>,
+    State(server_state): State<Arc<Server>>,
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+) -> Result<Response, AppError> {
+    let token = get_token_from_header(auth_header);
+    let authorization = server_state.verify_doc_token(token.as_deref(), &doc_id)?;
+    if !matches!(authorization, Authorization::Full) {
+        return Err(AppError(StatusCode::FORBIDDEN, anyhow!("Unauthorized.")));
+    }
+
+    let restored = server_state
+        .restore_doc_version(&doc_id, &version_id)
+        .await
+        .map_err(|e| {
+            tracing::error!(?e, "Failed to restore version");
+            (StatusCode::INTERNAL_SERVER_ERROR, e)
+        })?;
+    if !restored {
+        return Err(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("Version {} not found.", version_id),
+        ));
+    }
+
+    Ok(StatusCode::OK.into_response())
+}
+
 async fn update_doc_single(
     State(server_state): State<Arc<Server>>,
     headers: HeaderMap,
@@ -519,10 +1320,19 @@ async fn handle_socket_upgrade(
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
     let awareness = dwskv.awareness();
+    let limiter = dwskv.limiter();
     let cancellation_token = server_state.cancellation_token.clone();
+    // Tracked so that shutdown waits for connections to close before the final persist.
+    let tracker = server_state.doc_worker_tracker.clone();
 
     Ok(ws.on_upgrade(move |socket| {
-        handle_socket(socket, awareness, authorization, cancellation_token)
+        tracker.track_future(handle_socket(
+            socket,
+            awareness,
+            limiter,
+            authorization,
+            cancellation_token,
+        ))
     }))
 }
 
@@ -578,23 +1388,28 @@ async fn handle_socket_upgrade_single(
 async fn handle_socket(
     socket: WebSocket,
     awareness: Arc<RwLock<Awareness>>,
+    limiter: Arc<DocLimiter>,
     authorization: Authorization,
     cancellation_token: CancellationToken,
 ) {
     let (mut sink, mut stream) = socket.split();
     let (send, mut recv) = channel(1024);
 
-    tokio::spawn(async move {
+    let sink_task = tokio::spawn(async move {
         while let Some(msg) = recv.recv().await {
-            let _ = sink.send(Message::Binary(msg)).await;
+            let _ = sink.send(msg).await;
         }
     });
 
-    let connection = DocConnection::new(awareness, authorization, move |bytes| {
-        if let Err(e) = send.try_send(bytes.to_vec()) {
-            tracing::warn!(?e, "Error sending message");
-        }
-    });
+    let connection = {
+        let send = send.clone();
+        DocConnection::new(awareness, authorization, move |bytes| {
+            if let Err(e) = send.try_send(Message::Binary(bytes.to_vec())) {
+                tracing::warn!(?e, "Error sending message");
+            }
+        })
+        .with_limiter(limiter)
+    };
 
     loop {
         tokio::select! {
@@ -619,10 +1434,21 @@ async fn handle_socket(
             }
             _ = cancellation_token.cancelled() => {
                 tracing::debug!("Closing doc connection due to server cancel...");
+                let _ = send
+                    .send(Message::Close(Some(CloseFrame {
+                        code: close_code::AWAY,
+                        reason: "Server is shutting down.".into(),
+                    })))
+                    .await;
                 break;
             }
         }
     }
+
+    // Let queued messages, including any close frame, reach the client.
+    drop(connection);
+    drop(send);
+    let _ = sink_task.await;
 }
 
 async fn check_store(
@@ -650,21 +1476,94 @@ async fn check_store_deprecated(
     check_store(auth_header, State(server_state)).await
 }
 
-/// Always returns a 200 OK response, as long as we are listening.
-async fn ready() -> Result<Json<Value>, AppError> {
-    Ok(Json(json!({"ok": true})))
+/// Returns 503 Service Unavailable once a document has failed to persist for
+/// longer than the configured number of checkpoints, and 200 OK otherwise. Also
+/// reports how many documents have changes that could not be persisted.
+async fn ready(State(server_state): State<Arc<Server>>) -> Response {
+    let stale = server_state.stale_unpersisted_docs(current_time_epoch_millis());
+    let status = if stale > 0 {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    let body = json!({
+        "ok": stale == 0,
+        "unpersistedDocs": server_state.persist_failures.len(),
+        "staleUnpersistedDocs": stale,
+    });
+    (status, Json(body)).into_response()
 }
 
-async fn new_doc(
+/// Always returns a 200 OK response, as long as we are listening, so that it is
+/// safe to use as a liveness check: restarting would lose unpersisted changes.
+async fn live() -> Json<Value> {
+    Json(json!({"ok": true}))
+}
+
+async fn list_unpersisted_docs(
     auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
     State(server_state): State<Arc<Server>>,
-    Json(body): Json<DocCreationRequest>,
-) -> Result<Json<NewDocResponse>, AppError> {
+) -> Result<Json<UnpersistedDocsResponse>, AppError> {
     server_state.check_auth(auth_header)?;
 
-    let doc_id = if let Some(doc_id) = body.doc_id {
-        if !validate_doc_name(doc_id.as_str()) {
-            Err((StatusCode::BAD_REQUEST, anyhow!("Invalid document name")))?
+    Ok(Json(UnpersistedDocsResponse {
+        docs: server_state.unpersisted_docs(),
+    }))
+}
+
+async fn metrics(
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    State(server_state): State<Arc<Server>>,
+) -> Result<Response, AppError> {
+    server_state.check_auth(auth_header)?;
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        server_state.metrics.render(),
+    )
+        .into_response())
+}
+
+async fn list_docs(
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    State(server_state): State<Arc<Server>>,
+    Query(params): Query<ListDocsParams>,
+) -> Result<Json<DocListResponse>, AppError> {
+    server_state.check_auth(auth_header)?;
+
+    let limit = params.limit.unwrap_or(DEFAULT_DOC_LIST_LIMIT);
+    if limit == 0 || limit > MAX_DOC_LIST_LIMIT {
+        Err((
+            StatusCode::BAD_REQUEST,
+            anyhow!("limit must be between 1 and {}", MAX_DOC_LIST_LIMIT),
+        ))?
+    }
+
+    let (docs, next_cursor) = server_state
+        .list_docs(
+            params.prefix.as_deref().unwrap_or_default(),
+            params.cursor.as_deref(),
+            limit,
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!(?e, "Failed to list docs");
+            (StatusCode::INTERNAL_SERVER_ERROR, e)
+        })?;
+
+    Ok(Json(DocListResponse { docs, next_cursor }))
+}
+
+async fn new_doc(
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    State(server_state): State<Arc<Server>>,
+    Json(body): Json<DocCreationRequest>,
+) -> Result<Json<NewDocResponse>, AppError> {
+    server_state.check_auth(auth_header)?;
+
+    let doc_id = if let Some(doc_id) = body.doc_id {
+        if !validate_doc_name(doc_id.as_str()) {
+            Err((StatusCode::BAD_REQUEST, anyhow!("Invalid document name")))?
         }
 
         server_state
@@ -686,6 +1585,96 @@ async fn new_doc(
     Ok(Json(NewDocResponse { doc_id }))
 }
 
+async fn set_doc_limits(
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    State(server_state): State<Arc<Server>>,
+    Path(doc_id): Path<String>,
+    Json(limits): Json<DocLimits>,
+) -> Result<Json<DocLimits>, AppError> {
+    server_state.check_auth(auth_header)?;
+
+    if !server_state.doc_exists(&doc_id).await {
+        Err((StatusCode::NOT_FOUND, anyhow!("Doc {} not found", doc_id)))?;
+    }
+
+    let limits = server_state
+        .set_doc_limits(&doc_id, limits)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(Json(limits))
+}
+
+async fn list_quarantined_snapshots(
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    State(server_state): State<Arc<Server>>,
+    Path(doc_id): Path<String>,
+) -> Result<Json<QuarantineResponse>, AppError> {
+    server_state.check_auth(auth_header)?;
+
+    let snapshots = server_state
+        .list_quarantined(&doc_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    Ok(Json(QuarantineResponse { snapshots }))
+}
+
+async fn restore_quarantined_snapshot(
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    State(server_state): State<Arc<Server>>,
+    Path((doc_id, quarantine_id)): Path<(String, String)>,
+) -> Result<Response, AppError> {
+    server_state.check_auth(auth_header)?;
+
+    let snapshot = server_state
+        .load_quarantined(&doc_id, &quarantine_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?
+        .ok_or_else(|| {
+            AppError(
+                StatusCode::NOT_FOUND,
+                anyhow!("Quarantined snapshot {} not found.", quarantine_id),
+            )
+        })?;
+    let snapshot = decode_snapshot(&snapshot).map_err(|e| {
+        AppError(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            e.context("Quarantined snapshot cannot be decoded."),
+        )
+    })?;
+
+    server_state
+        .restore_quarantined(&doc_id, &quarantine_id, snapshot)
+        .await
+        .map_err(|e| {
+            tracing::error!(?e, "Failed to restore quarantined snapshot");
+            (StatusCode::INTERNAL_SERVER_ERROR, e)
+        })?;
+
+    Ok(StatusCode::OK.into_response())
+}
+
+async fn discard_quarantined_snapshot(
+    auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    State(server_state): State<Arc<Server>>,
+    Path((doc_id, quarantine_id)): Path<(String, String)>,
+) -> Result<Response, AppError> {
+    server_state.check_auth(auth_header)?;
+
+    let discarded = server_state
+        .discard_quarantined(&doc_id, &quarantine_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    if !discarded {
+        return Err(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("Quarantined snapshot {} not found.", quarantine_id),
+        ));
+    }
+
+    Ok(StatusCode::OK.into_response())
+}
+
 async fn auth_doc(
     auth_header: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
     TypedHeader(host): TypedHeader<headers::Host>,
@@ -775,7 +1764,10 @@ fn get_authorization_from_plane_header(headers: HeaderMap) -> Result<Authorizati
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::stores::filesystem::FileSystemStore;
     use y_sweet_core::api_types::Authorization;
+    use y_sweet_core::store::memory::MemoryStore;
+    use y_sweet_core::store::{ListResult, ObjectVersion, Result as StoreResult, StoreError};
 
     #[tokio::test]
     async fn test_auth_doc() {
@@ -847,4 +1839,776 @@ mod test {
         assert_eq!(token.doc_id, doc_id);
         assert!(token.token.is_none());
     }
+
+    #[tokio::test]
+    async fn test_list_docs() {
+        let path = std::env::temp_dir().join(format!("y-sweet-test-{}", nanoid::nanoid!()));
+        let store = FileSystemStore::new(path.clone()).unwrap();
+        let server_state = Server::new(
+            Some(Box::new(store)),
+            Duration::from_secs(60),
+            None,
+            None,
+            CancellationToken::new(),
+            true,
+        )
+        .await
+        .unwrap();
+
+        for doc_id in ["doc-b", "doc-a", "doc-c", "other"] {
+            server_state.get_or_create_doc(doc_id).await.unwrap();
+        }
+        let server_state = Arc::new(server_state);
+
+        let Json(page) = list_docs(
+            None,
+            State(server_state.clone()),
+            Query(ListDocsParams {
+                prefix: Some("doc-".to_string()),
+                cursor: None,
+                limit: Some(2),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(page.docs, vec!["doc-a", "doc-b"]);
+        assert_eq!(page.next_cursor.as_deref(), Some("doc-b"));
+
+        let Json(page) = list_docs(
+            None,
+            State(server_state.clone()),
+            Query(ListDocsParams {
+                prefix: Some("doc-".to_string()),
+                cursor: page.next_cursor,
+                limit: Some(2),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(page.docs, vec!["doc-c"]);
+        assert_eq!(page.next_cursor, None);
+
+        let Json(page) = list_docs(
+            None,
+            State(server_state.clone()),
+            Query(ListDocsParams {
+                prefix: None,
+                cursor: None,
+                limit: None,
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(page.docs, vec!["doc-a", "doc-b", "doc-c", "other"]);
+
+        // Pages are ordered by doc ID, even where that differs from key order.
+        for doc_id in ["x-y", "x"] {
+            server_state.get_or_create_doc(doc_id).await.unwrap();
+        }
+        let mut cursor = None;
+        for expected in ["x", "x-y"] {
+            let Json(page) = list_docs(
+                None,
+                State(server_state.clone()),
+                Query(ListDocsParams {
+                    prefix: Some("x".to_string()),
+                    cursor,
+                    limit: Some(1),
+                }),
+            )
+            .await
+            .unwrap();
+            assert_eq!(page.docs, vec![expected]);
+            cursor = page.next_cursor;
+        }
+
+        let Err(AppError(status, _)) = list_docs(
+            None,
+            State(server_state),
+            Query(ListDocsParams {
+                prefix: None,
+                cursor: None,
+                limit: Some(0),
+            }),
+        )
+        .await
+        else {
+            panic!("Expected an empty page to be rejected");
+        };
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restore_doc_version() {
+        use y_sweet_core::history::HistoryOptions;
+        use yrs::{updates::decoder::Decode, GetString, Text, Transact, Update};
+
+        let path = std::env::temp_dir().join(format!("y-sweet-test-{}", nanoid::nanoid!()));
+        let store = FileSystemStore::new(path.clone()).unwrap();
+        let server_state = Server::new(
+            Some(Box::new(store)),
+            Duration::from_secs(60),
+            None,
+            None,
+            CancellationToken::new(),
+            true,
+        )
+        .await
+        .unwrap()
+        .with_persistence_options(PersistenceOptions {
+            history: Some(HistoryOptions {
+                max_versions: 10,
+                max_age: None,
+                min_interval: Duration::ZERO,
+            }),
+            ..Default::default()
+        });
+        let server_state = Arc::new(server_state);
+
+        let read_text = || {
+            let dwskv = server_state.docs.get("doc").unwrap();
+            let doc = yrs::Doc::new();
+            let text = doc.get_or_insert_text("text");
+            let mut txn = doc.transact_mut();
+            txn.apply_update(Update::decode_v1(&dwskv.as_update()).unwrap());
+            text.get_string(&txn)
+        };
+
+        for content in ["first", "second"] {
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            let dwskv = server_state.get_or_create_doc("doc").await.unwrap();
+            {
+                let awareness = dwskv.awareness();
+                let awareness = awareness.write().unwrap();
+                let text = awareness.doc.get_or_insert_text("text");
+                let mut txn = awareness.doc.transact_mut();
+                let len = text.len(&txn);
+                text.remove_range(&mut txn, 0, len);
+                text.insert(&mut txn, 0, content);
+            }
+            dwskv.sync_kv().persist().await.unwrap();
+        }
+        assert_eq!(read_text(), "second");
+
+        let Json(response) =
+            list_doc_versions(Path("doc".to_string()), State(server_state.clone()), None)
+                .await
+                .unwrap();
+        // The initial empty snapshot and one for each edit.
+        assert_eq!(response.versions.len(), 3);
+
+        restore_doc_version(
+            Path(("doc".to_string(), response.versions[1].id.clone())),
+            State(server_state.clone()),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(read_text(), "first");
+
+        let missing = restore_doc_version(
+            Path(("doc".to_string(), "123".to_string())),
+            State(server_state.clone()),
+            None,
+        )
+        .await;
+        assert_eq!(missing.unwrap_err().0, StatusCode::NOT_FOUND);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_doc_limits() {
+        use y_sweet_core::limits::load_limits;
+        use yrs::{ReadTxn, StateVector, Text, Transact};
+
+        let store = MemoryStore::new();
+        let new_server = || async {
+            let server_state = Server::new(
+                Some(Box::new(store.clone())),
+                Duration::from_secs(60),
+                None,
+                None,
+                CancellationToken::new(),
+                true,
+            )
+            .await
+            .unwrap()
+            .with_doc_limits(DocLimits {
+                max_doc_size: Some(10_000),
+                max_update_size: Some(100),
+            });
+            Arc::new(server_state)
+        };
+        let server_state = new_server().await;
+        server_state.get_or_create_doc("doc").await.unwrap();
+
+        let update = |content: &str| {
+            let doc = yrs::Doc::new();
+            let text = doc.get_or_insert_text("text");
+            text.insert(&mut doc.transact_mut(), 0, content);
+            let update = doc
+                .transact()
+                .encode_state_as_update_v1(&StateVector::default());
+            Bytes::from(update)
+        };
+        let send = |body: Bytes| {
+            update_doc(
+                Path("doc".to_string()),
+                State(server_state.clone()),
+                None,
+                body,
+            )
+        };
+
+        send(update("small")).await.unwrap();
+        let err = send(update(&"x".repeat(200))).await.unwrap_err();
+        assert_eq!(err.0, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // A per-document limit overrides the server-wide one.
+        let Json(limits) = set_doc_limits(
+            None,
+            State(server_state.clone()),
+            Path("doc".to_string()),
+            Json(DocLimits {
+                max_doc_size: Some(500),
+                max_update_size: Some(1000),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(limits.max_update_size, Some(1000));
+        send(update(&"x".repeat(200))).await.unwrap();
+
+        // Exceeding the document size makes the document read-only.
+        let err = send(update(&"y".repeat(400))).await.unwrap_err();
+        assert_eq!(err.0, StatusCode::PAYLOAD_TOO_LARGE);
+        let err = send(update("z")).await.unwrap_err();
+        assert_eq!(err.0, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // The limits and read-only state are stored, and apply after a restart.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !load_limits(&store, "doc")
+                .await
+                .unwrap()
+                .is_some_and(|stored| stored.read_only)
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("read-only state was not stored");
+        let restarted = new_server().await;
+        let dwskv = restarted.get_or_create_doc("doc").await.unwrap();
+        assert_eq!(restarted.doc_limits("doc").max_doc_size, Some(500));
+        assert!(dwskv.limiter().is_read_only());
+    }
+
+    #[tokio::test]
+    async fn test_quarantine() {
+        use y_sweet_core::quarantine::RecoveryPolicy;
+        use yrs::{
+            updates::decoder::Decode, GetString, ReadTxn, StateVector, Text, Transact, Update,
+        };
+
+        let path = std::env::temp_dir().join(format!("y-sweet-test-{}", nanoid::nanoid!()));
+        let store = FileSystemStore::new(path.clone()).unwrap();
+        store
+            .set("doc/data.ysweet", b"YSWEET\x02garbage".to_vec())
+            .await
+            .unwrap();
+        let server_state = Server::new(
+            Some(Box::new(FileSystemStore::new(path.clone()).unwrap())),
+            Duration::from_secs(60),
+            None,
+            None,
+            CancellationToken::new(),
+            true,
+        )
+        .await
+        .unwrap()
+        .with_persistence_options(PersistenceOptions {
+            recovery: RecoveryPolicy::Quarantine,
+            ..Default::default()
+        });
+        let server_state = Arc::new(server_state);
+
+        server_state.get_or_create_doc("doc").await.unwrap();
+        let Json(response) =
+            list_quarantined_snapshots(None, State(server_state.clone()), Path("doc".to_string()))
+                .await
+                .unwrap();
+        assert_eq!(response.snapshots.len(), 1);
+        assert!(response.snapshots[0].error.is_some());
+        let unreadable = response.snapshots[0].id.clone();
+
+        let err = restore_quarantined_snapshot(
+            None,
+            State(server_state.clone()),
+            Path(("doc".to_string(), unreadable.clone())),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, StatusCode::UNPROCESSABLE_ENTITY);
+
+        discard_quarantined_snapshot(
+            None,
+            State(server_state.clone()),
+            Path(("doc".to_string(), unreadable.clone())),
+        )
+        .await
+        .unwrap();
+        let err = discard_quarantined_snapshot(
+            None,
+            State(server_state.clone()),
+            Path(("doc".to_string(), unreadable)),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, StatusCode::NOT_FOUND);
+
+        // A quarantined snapshot that is readable can be restored.
+        let doc = yrs::Doc::new();
+        let text = doc.get_or_insert_text("text");
+        text.insert(&mut doc.transact_mut(), 0, "restored");
+        let update = doc
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+        crate::convert::convert(
+            Box::new(FileSystemStore::new(path.clone()).unwrap()),
+            &update,
+            "other",
+        )
+        .await
+        .unwrap();
+        let readable = store.get("other/data.ysweet").await.unwrap().unwrap();
+        store
+            .set("doc/quarantine/00000000000000000001", readable)
+            .await
+            .unwrap();
+        restore_quarantined_snapshot(
+            None,
+            State(server_state.clone()),
+            Path(("doc".to_string(), "00000000000000000001".to_string())),
+        )
+        .await
+        .unwrap();
+        assert!(server_state
+            .list_quarantined("doc")
+            .await
+            .unwrap()
+            .is_empty());
+        let update = server_state.docs.get("doc").unwrap().as_update();
+        let doc = yrs::Doc::new();
+        let text = doc.get_or_insert_text("text");
+        let mut txn = doc.transact_mut();
+        txn.apply_update(Update::decode_v1(&update).unwrap());
+        assert_eq!(text.get_string(&txn), "restored");
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flush_all() {
+        use yrs::{Text, Transact};
+
+        let server_state = Server::new(
+            Some(Box::new(MemoryStore::new())),
+            Duration::from_secs(60),
+            None,
+            None,
+            CancellationToken::new(),
+            true,
+        )
+        .await
+        .unwrap();
+
+        for doc_id in ["clean", "dirty"] {
+            server_state.get_or_create_doc(doc_id).await.unwrap();
+        }
+        {
+            let dwskv = server_state.get_or_create_doc("dirty").await.unwrap();
+            let awareness = dwskv.awareness();
+            let awareness = awareness.write().unwrap();
+            let text = awareness.doc.get_or_insert_text("text");
+            text.insert(&mut awareness.doc.transact_mut(), 0, "unsaved");
+        }
+
+        let mut outcomes = server_state.flush_all().await;
+        outcomes.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            outcomes,
+            vec![
+                ("clean".to_string(), FlushOutcome::Clean),
+                ("dirty".to_string(), FlushOutcome::Persisted),
+            ]
+        );
+        assert!(!server_state.docs.get("dirty").unwrap().sync_kv().is_dirty());
+    }
+
+    #[tokio::test]
+    async fn test_compaction_worker() {
+        use y_sweet_core::{doc_connection::DOC_NAME, update_log::UpdateLogOptions};
+        use yrs::{ReadTxn, StateVector, Text, Transact};
+        use yrs_kvstore::DocOps;
+
+        let update = {
+            let doc = yrs::Doc::new();
+            let text = doc.get_or_insert_text("text");
+            let mut txn = doc.transact_mut();
+            text.insert(&mut txn, 0, "hello");
+            txn.encode_state_as_update_v1(&StateVector::default())
+        };
+
+        for update_log in [
+            None,
+            Some(UpdateLogOptions {
+                compact_after_segments: 1000,
+            }),
+        ] {
+            let store = MemoryStore::new();
+            let server_state = Arc::new(
+                Server::new(
+                    Some(Box::new(store.clone())),
+                    Duration::from_millis(10),
+                    None,
+                    None,
+                    CancellationToken::new(),
+                    true,
+                )
+                .await
+                .unwrap()
+                .with_persistence_options(PersistenceOptions {
+                    update_log,
+                    ..Default::default()
+                })
+                .with_compaction_options(CompactionOptions {
+                    interval: Some(Duration::ZERO),
+                    growth_factor: None,
+                }),
+            );
+
+            let sync_kv = {
+                let dwskv = server_state.get_or_create_doc("doc").await.unwrap();
+                dwskv.apply_update(&update).unwrap();
+                // An update entry that was never merged, which compaction drops.
+                dwskv.sync_kv().push_update(DOC_NAME, &update).unwrap();
+                dwskv.sync_kv()
+            };
+
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while server_state.metrics().compactions.load(Ordering::Relaxed) == 0 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("doc was not compacted");
+
+            // The compacted data is written to the store, with or without the log.
+            tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    if let Some(snapshot) = store.get("doc/data.ysweet").await.unwrap() {
+                        let stored: usize = decode_snapshot(&snapshot)
+                            .unwrap()
+                            .iter()
+                            .map(|(key, value)| key.len() + value.len())
+                            .sum();
+                        if stored == sync_kv.size() {
+                            break;
+                        }
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("compacted snapshot was not written");
+
+            let response = metrics(None, State(server_state.clone())).await.unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert!(body.contains("y_sweet_compactions_total 1\n"), "{}", body);
+            assert!(body.contains("# TYPE y_sweet_compaction_bytes_reclaimed_total counter"));
+        }
+    }
+
+    /// A store whose writes can be made to fail, as if it were unreachable.
+    #[derive(Clone, Default)]
+    struct FailingWritesStore {
+        inner: MemoryStore,
+        down: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl FailingWritesStore {
+        fn check(&self) -> StoreResult<()> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(StoreError::ConnectionError("Store is down.".to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Store for FailingWritesStore {
+        async fn init(&self) -> StoreResult<()> {
+            Ok(())
+        }
+
+        async fn get(&self, key: &str) -> StoreResult<Option<Vec<u8>>> {
+            self.inner.get(key).await
+        }
+
+        async fn set(&self, key: &str, value: Vec<u8>) -> StoreResult<()> {
+            self.check()?;
+            self.inner.set(key, value).await
+        }
+
+        async fn remove(&self, key: &str) -> StoreResult<()> {
+            self.check()?;
+            self.inner.remove(key).await
+        }
+
+        async fn exists(&self, key: &str) -> StoreResult<bool> {
+            self.inner.exists(key).await
+        }
+
+        async fn list(
+            &self,
+            prefix: &str,
+            cursor: Option<&str>,
+            limit: usize,
+        ) -> StoreResult<ListResult> {
+            self.inner.list(prefix, cursor, limit).await
+        }
+
+        async fn get_versioned(&self, key: &str) -> StoreResult<Option<(Vec<u8>, ObjectVersion)>> {
+            self.inner.get_versioned(key).await
+        }
+
+        async fn set_if(
+            &self,
+            key: &str,
+            value: Vec<u8>,
+            expected: Option<&ObjectVersion>,
+        ) -> StoreResult<ObjectVersion> {
+            self.check()?;
+            self.inner.set_if(key, value, expected).await
+        }
+    }
+
+    async fn wait_until(condition: impl Fn() -> bool, message: &str) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !condition() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect(message);
+    }
+
+    #[tokio::test]
+    async fn test_retries_failed_persists() {
+        use yrs::{GetString, Text, Transact};
+
+        let store = FailingWritesStore::default();
+        let server_state = Arc::new(
+            Server::new(
+                Some(Box::new(store.clone())),
+                Duration::from_millis(10),
+                None,
+                None,
+                CancellationToken::new(),
+                true,
+            )
+            .await
+            .unwrap(),
+        );
+        server_state.get_or_create_doc("doc").await.unwrap();
+
+        store.down.store(true, Ordering::SeqCst);
+        {
+            let dwskv = server_state.get_or_create_doc("doc").await.unwrap();
+            let awareness = dwskv.awareness();
+            let awareness = awareness.write().unwrap();
+            let text = awareness.doc.get_or_insert_text("text");
+            text.insert(&mut awareness.doc.transact_mut(), 0, "unsaved");
+        }
+        wait_until(
+            || server_state.unpersisted_docs().len() == 1,
+            "persist did not fail",
+        )
+        .await;
+        // Failing for up to the default 6 checkpoints (60ms) is tolerated.
+        let since = server_state.unpersisted_docs()[0].failing_since;
+        assert_eq!(server_state.stale_unpersisted_docs(since + 60), 0);
+        assert_eq!(server_state.stale_unpersisted_docs(since + 61), 1);
+
+        // The doc has no clients, but is not GCed while its changes are unsaved.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(server_state.docs.contains_key("doc"));
+        let response = ready(State(server_state.clone())).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let ready_response: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(ready_response["ok"], false);
+        assert_eq!(ready_response["unpersistedDocs"], 1);
+        assert_eq!(ready_response["staleUnpersistedDocs"], 1);
+        let Json(live_response) = live().await;
+        assert_eq!(live_response["ok"], true);
+        let failure = &server_state.unpersisted_docs()[0];
+        assert_eq!(failure.doc_id, "doc");
+        assert!(failure.failures > 1, "{:?}", failure);
+        assert!(
+            failure.last_error.contains("Store is down."),
+            "{:?}",
+            failure
+        );
+
+        // Once the store is back, the doc is persisted without further changes,
+        // and can then be GCed.
+        store.down.store(false, Ordering::SeqCst);
+        wait_until(
+            || server_state.unpersisted_docs().is_empty(),
+            "persist was not retried",
+        )
+        .await;
+        wait_until(
+            || !server_state.docs.contains_key("doc"),
+            "doc was not GCed",
+        )
+        .await;
+        assert_eq!(
+            ready(State(server_state.clone())).await.status(),
+            StatusCode::OK
+        );
+        let dwskv = DocWithSyncKv::new("doc", Some(Arc::new(Box::new(store.inner.clone()))), || ())
+            .await
+            .unwrap();
+        let awareness = dwskv.awareness();
+        let awareness = awareness.read().unwrap();
+        let text = awareness.doc.get_or_insert_text("text");
+        assert_eq!(text.get_string(&awareness.doc.transact()), "unsaved");
+    }
+
+    // Multi-threaded, with a store that yields, so that the loads interleave.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_loads_share_one_doc() {
+        let path = std::env::temp_dir().join(format!("y-sweet-test-{}", nanoid::nanoid!()));
+        let server_state = Arc::new(
+            Server::new(
+                Some(Box::new(FileSystemStore::new(path.clone()).unwrap())),
+                Duration::from_secs(60),
+                None,
+                None,
+                CancellationToken::new(),
+                true,
+            )
+            .await
+            .unwrap(),
+        );
+
+        let tasks: Vec<_> = (0..50)
+            .map(|_| {
+                let server_state = server_state.clone();
+                tokio::spawn(async move {
+                    server_state
+                        .get_or_create_doc("cold")
+                        .await
+                        .unwrap()
+                        .awareness()
+                })
+            })
+            .collect();
+        let mut awarenesses = Vec::new();
+        for task in tasks {
+            awarenesses.push(task.await.unwrap());
+        }
+
+        let loaded = server_state.docs.get("cold").unwrap().awareness();
+        assert!(awarenesses
+            .iter()
+            .all(|awareness| Arc::ptr_eq(awareness, &loaded)));
+        // One persistence worker and one GC worker.
+        assert_eq!(server_state.doc_worker_tracker.len(), 2);
+        assert!(server_state.loading.is_empty());
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_evicts_idle_docs() {
+        use yrs::{GetString, Text, Transact};
+
+        let store = MemoryStore::new();
+        let server_state = Server::new(
+            Some(Box::new(store.clone())),
+            Duration::from_secs(60),
+            None,
+            None,
+            CancellationToken::new(),
+            true,
+        )
+        .await
+        .unwrap()
+        .with_resident_limits(ResidentLimits {
+            max_docs: Some(2),
+            max_bytes: None,
+        });
+
+        // A connected client holds the awareness of "a".
+        let client = server_state
+            .get_or_create_doc("a")
+            .await
+            .unwrap()
+            .awareness();
+        {
+            let dwskv = server_state.get_or_create_doc("b").await.unwrap();
+            let awareness = dwskv.awareness();
+            let awareness = awareness.write().unwrap();
+            let text = awareness.doc.get_or_insert_text("text");
+            text.insert(&mut awareness.doc.transact_mut(), 0, "unsaved");
+        }
+        let limits = DocLimits {
+            max_doc_size: Some(10_000),
+            max_update_size: None,
+        };
+        server_state.set_doc_limits("b", limits).await.unwrap();
+
+        let evicted = Arc::downgrade(&server_state.docs.get("b").unwrap().sync_kv());
+        let workers = server_state.doc_worker_tracker.len();
+
+        server_state.get_or_create_doc("c").await.unwrap();
+        let mut resident: Vec<String> = server_state.docs.iter().map(|e| e.key().clone()).collect();
+        resident.sort();
+        assert_eq!(resident, vec!["a", "c"]);
+
+        // The evicted doc is dropped, and its workers exit: "c" added two workers,
+        // and "b" had two.
+        assert!(evicted.upgrade().is_none());
+        assert!(!server_state.doc_limit_overrides.contains_key("b"));
+        wait_until(
+            || server_state.doc_worker_tracker.len() == workers,
+            "evicted doc's workers did not exit",
+        )
+        .await;
+
+        server_state.get_or_create_doc("d").await.unwrap();
+        let mut resident: Vec<String> = server_state.docs.iter().map(|e| e.key().clone()).collect();
+        resident.sort();
+        assert_eq!(resident, vec!["a", "d"]);
+        drop(client);
+
+        // "b" was persisted before it was evicted, and its limits are reloaded.
+        let dwskv = server_state.get_or_create_doc("b").await.unwrap();
+        assert_eq!(server_state.doc_limits("b").max_doc_size, Some(10_000));
+        let awareness = dwskv.awareness();
+        let awareness = awareness.read().unwrap();
+        let text = awareness.doc.get_or_insert_text("text");
+        assert_eq!(text.get_string(&awareness.doc.transact()), "unsaved");
+    }
 }
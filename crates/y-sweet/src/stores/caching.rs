@@ -0,0 +1,825 @@
+use super::filesystem::{blocking, io_error, FileSystemStore, TEMP_FILE_PREFIX};
+use async_trait::async_trait;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::create_dir_all,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tokio::sync::OwnedMutexGuard;
+use y_sweet_core::store::{ListResult, ObjectVersion, Result, Store, StoreError};
+
+/// How writes reach the underlying store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheWriteMode {
+    /// Writes go to the underlying store before returning.
+    WriteThrough,
+    /// Unconditional writes are acknowledged once they are on local disk, and
+    /// written to the underlying store in the background. A write waits for an
+    /// earlier write of the same object that is being flushed. Conditional writes
+    /// (`set_if`) are always written through, since only the underlying store
+    /// can decide whether their precondition holds.
+    WriteBehind,
+}
+
+#[derive(Clone, Debug)]
+pub struct CacheOptions {
+    /// The directory to keep cached objects in.
+    pub dir: PathBuf,
+    /// The maximum total size of cached objects on disk, in bytes. Entries that
+    /// have not been written to the underlying store yet are never evicted, so
+    /// the cache may exceed this while they are pending.
+    pub max_bytes: u64,
+    pub write_mode: CacheWriteMode,
+}
+
+const FLAG_CLEAN: u8 = 0;
+const FLAG_DIRTY: u8 = 1;
+
+/// A cached object as stored on disk: a flag byte, the length-prefixed version
+/// of the object in the underlying store, and the object's contents.
+struct CacheFile {
+    dirty: bool,
+    version: Option<ObjectVersion>,
+    data: Vec<u8>,
+}
+
+impl CacheFile {
+    fn encode(&self) -> Vec<u8> {
+        let version = self.version.as_ref().map(|v| v.0.as_bytes()).unwrap_or(&[]);
+        let mut result = Vec::with_capacity(3 + version.len() + self.data.len());
+        result.push(if self.dirty { FLAG_DIRTY } else { FLAG_CLEAN });
+        result.extend_from_slice(&(version.len() as u16).to_be_bytes());
+        result.extend_from_slice(version);
+        result.extend_from_slice(&self.data);
+        result
+    }
+
+    fn decode(mut bytes: Vec<u8>) -> Option<Self> {
+        let (&flag, rest) = bytes.split_first()?;
+        let version_len = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?) as usize;
+        let version = String::from_utf8(rest.get(2..2 + version_len)?.to_vec()).ok()?;
+        let data = bytes.split_off(3 + version_len);
+        Some(Self {
+            dirty: flag == FLAG_DIRTY,
+            version: (!version.is_empty()).then_some(ObjectVersion(version)),
+            data,
+        })
+    }
+}
+
+/// Map a key to a flat file name, escaping everything but a safe alphabet.
+fn file_name(key: &str) -> String {
+    let mut name = String::with_capacity(key.len());
+    for (i, b) in key.bytes().enumerate() {
+        if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || (b == b'.' && i > 0) {
+            name.push(b as char);
+        } else {
+            name.push_str(&format!("%{:02X}", b));
+        }
+    }
+    name
+}
+
+fn key_from_file_name(name: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut iter = name.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+struct CacheEntry {
+    size: u64,
+    last_used: u64,
+    /// Identifies the write that produced this entry.
+    written: u64,
+    /// Whether the entry has not been written to the underlying store yet.
+    dirty: bool,
+}
+
+/// Tracks which keys are cached and how recently they were used.
+#[derive(Default)]
+struct CacheIndex {
+    entries: HashMap<String, CacheEntry>,
+    by_last_used: BTreeMap<u64, String>,
+    total_bytes: u64,
+    clock: u64,
+}
+
+impl CacheIndex {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Mark `key` as used, returning whether it is cached.
+    fn touch(&mut self, key: &str) -> bool {
+        let now = self.tick();
+        let Some(entry) = self.entries.get_mut(key) else {
+            return false;
+        };
+        self.by_last_used.remove(&entry.last_used);
+        entry.last_used = now;
+        self.by_last_used.insert(now, key.to_string());
+        true
+    }
+
+    fn insert(&mut self, key: &str, size: u64, dirty: bool) {
+        self.remove(key);
+        let now = self.tick();
+        self.entries.insert(
+            key.to_string(),
+            CacheEntry {
+                size,
+                last_used: now,
+                written: now,
+                dirty,
+            },
+        );
+        self.by_last_used.insert(now, key.to_string());
+        self.total_bytes += size;
+    }
+
+    fn remove(&mut self, key: &str) -> Option<CacheEntry> {
+        let entry = self.entries.remove(key)?;
+        self.by_last_used.remove(&entry.last_used);
+        self.total_bytes -= entry.size;
+        Some(entry)
+    }
+
+    /// If `key` is cached and dirty, the write that produced it.
+    fn dirty_write(&self, key: &str) -> Option<u64> {
+        self.entries
+            .get(key)
+            .filter(|entry| entry.dirty)
+            .map(|entry| entry.written)
+    }
+
+    fn dirty_keys(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Remove least-recently-used clean entries until the cache fits in
+    /// `max_bytes`, returning the keys whose files should be deleted.
+    fn evict(&mut self, max_bytes: u64) -> Vec<String> {
+        let mut excess = self.total_bytes.saturating_sub(max_bytes);
+        let mut evicted = Vec::new();
+        for key in self.by_last_used.values() {
+            if excess == 0 {
+                break;
+            }
+            let entry = &self.entries[key];
+            if !entry.dirty {
+                excess = excess.saturating_sub(entry.size);
+                evicted.push(key.clone());
+            }
+        }
+        for key in &evicted {
+            self.remove(key);
+        }
+        evicted
+    }
+}
+
+struct Shared {
+    inner: Box<dyn Store>,
+    options: CacheOptions,
+    index: Mutex<CacheIndex>,
+    /// Per-key locks that serialize writing a dirty entry to the underlying store
+    /// with other writes and removals of the same object.
+    key_locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+/// A store that keeps a size-bounded local disk cache of another store, evicting
+/// least-recently-used entries.
+///
+/// Cached reads are validated against the underlying store's current version of
+/// the object (see [`Store::get_version`]), which is cheaper than fetching it, so
+/// objects changed by other nodes are never served stale. Since the cache is on
+/// disk, it survives restarts, and writes not yet flushed to the underlying store
+/// in [`CacheWriteMode::WriteBehind`] mode are flushed when the store is initialized.
+#[derive(Clone)]
+pub struct CachingStore {
+    shared: Arc<Shared>,
+}
+
+impl CachingStore {
+    pub fn new(
+        inner: Box<dyn Store>,
+        options: CacheOptions,
+    ) -> std::result::Result<Self, std::io::Error> {
+        let dir = &options.dir;
+        create_dir_all(dir)?;
+
+        // Rebuild the index from the files left by a previous run, oldest first.
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(TEMP_FILE_PREFIX) {
+                let _ = std::fs::remove_file(entry.path());
+                continue;
+            }
+            let Some(key) = key_from_file_name(&name) else {
+                continue;
+            };
+            let metadata = entry.metadata()?;
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            let dirty = Self::read_flag(&entry.path())? == Some(FLAG_DIRTY);
+            files.push((modified, key, metadata.len(), dirty));
+        }
+        files.sort();
+
+        let mut index = CacheIndex::default();
+        for (_, key, size, dirty) in files {
+            index.insert(&key, size, dirty);
+        }
+        for key in index.evict(options.max_bytes) {
+            let _ = std::fs::remove_file(dir.join(file_name(&key)));
+        }
+
+        Ok(Self {
+            shared: Arc::new(Shared {
+                inner,
+                options,
+                index: Mutex::new(index),
+                key_locks: Mutex::default(),
+            }),
+        })
+    }
+
+    fn read_flag(path: &Path) -> std::io::Result<Option<u8>> {
+        use std::io::Read;
+        let mut flag = [0];
+        let mut file = std::fs::File::open(path)?;
+        match file.read_exact(&mut flag) {
+            Ok(()) => Ok(Some(flag[0])),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write every pending entry to the underlying store.
+    pub async fn flush(&self) -> Result<()> {
+        let keys = self.shared.index.lock().unwrap().dirty_keys();
+        for key in keys {
+            Shared::flush_key(&self.shared, &key).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Shared {
+    async fn lock_key(&self, key: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.key_locks.lock().unwrap();
+            // Drop the locks that nobody holds or waits on.
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(key.to_string()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.options.dir.join(file_name(key))
+    }
+
+    async fn read(&self, key: &str) -> Result<Option<CacheFile>> {
+        let path = self.path(key);
+        let bytes = blocking(move || match std::fs::read(path) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(io_error(e, "Error reading cache file")),
+        })
+        .await?;
+        Ok(bytes.and_then(CacheFile::decode))
+    }
+
+    /// Write an entry to the cache, evicting others to make room. Clean entries
+    /// larger than the whole cache are not cached.
+    async fn write(&self, key: &str, file: CacheFile) -> Result<()> {
+        let dirty = file.dirty;
+        let bytes = file.encode();
+        let size = bytes.len() as u64;
+        if !dirty && size > self.options.max_bytes {
+            self.invalidate(key).await?;
+            return Ok(());
+        }
+
+        let path = self.path(key);
+        blocking(move || FileSystemStore::write_file(&path, &bytes)).await?;
+
+        let evicted = {
+            let mut index = self.index.lock().unwrap();
+            index.insert(key, size, dirty);
+            index.evict(self.options.max_bytes)
+        };
+        for key in evicted {
+            self.remove_file(&key).await?;
+        }
+        Ok(())
+    }
+
+    async fn invalidate(&self, key: &str) -> Result<()> {
+        self.index.lock().unwrap().remove(key);
+        self.remove_file(key).await
+    }
+
+    async fn remove_file(&self, key: &str) -> Result<()> {
+        let path = self.path(key);
+        blocking(move || match std::fs::remove_file(path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(io_error(e, "Error removing cache file")),
+        })
+        .await
+    }
+
+    fn is_dirty(&self, key: &str) -> bool {
+        self.index.lock().unwrap().dirty_write(key).is_some()
+    }
+
+    /// Write `value` to the underlying store unconditionally, returning the version
+    /// it was written as. `set` does not return a version, and reading it back
+    /// afterwards could see another writer's version, so this writes with `set_if`
+    /// against the current version, retrying if another writer gets in between.
+    async fn set_versioned(&self, key: &str, value: Vec<u8>) -> Result<ObjectVersion> {
+        loop {
+            let current = self.inner.get_version(key).await?;
+            match self
+                .inner
+                .set_if(key, value.clone(), current.as_ref())
+                .await
+            {
+                Ok(version) => return Ok(version),
+                Err(StoreError::Conflict(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Write the entry for `key` to the underlying store, if it is dirty.
+    async fn flush_key(this: &Arc<Self>, key: &str) -> Result<()> {
+        let _guard = this.lock_key(key).await;
+        let Some(written) = this.index.lock().unwrap().dirty_write(key) else {
+            return Ok(());
+        };
+        let Some(file) = this.read(key).await? else {
+            this.invalidate(key).await?;
+            return Ok(());
+        };
+
+        let version = this.set_versioned(key, file.data.clone()).await?;
+
+        // Only mark the entry clean if it was not written again in the meantime.
+        if this.index.lock().unwrap().dirty_write(key) != Some(written) {
+            return Ok(());
+        }
+        this.write(
+            key,
+            CacheFile {
+                dirty: false,
+                version: Some(version),
+                data: file.data,
+            },
+        )
+        .await
+    }
+
+    fn spawn_flush(this: &Arc<Self>, key: &str) {
+        let this = this.clone();
+        let key = key.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = Shared::flush_key(&this, &key).await {
+                // The entry stays dirty, so it is retried on the next write or restart.
+                tracing::error!(?e, key, "Failed to write cached object to store.");
+            }
+        });
+    }
+
+    async fn get_versioned(
+        this: &Arc<Self>,
+        key: &str,
+    ) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        // A pending write has no version in the underlying store yet.
+        Shared::flush_key(this, key).await?;
+
+        let cached = this.index.lock().unwrap().touch(key);
+        if cached {
+            if let Some(CacheFile {
+                version: Some(cached_version),
+                data,
+                ..
+            }) = this.read(key).await?
+            {
+                match this.inner.get_version(key).await? {
+                    Some(version) if version == cached_version => {
+                        return Ok(Some((data, version)));
+                    }
+                    Some(_) => {}
+                    None => {
+                        this.invalidate(key).await?;
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+
+        match this.inner.get_versioned(key).await? {
+            Some((data, version)) => {
+                this.write(
+                    key,
+                    CacheFile {
+                        dirty: false,
+                        version: Some(version.clone()),
+                        data: data.clone(),
+                    },
+                )
+                .await?;
+                Ok(Some((data, version)))
+            }
+            None => {
+                this.invalidate(key).await?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Store for CachingStore {
+    async fn init(&self) -> Result<()> {
+        self.shared.inner.init().await?;
+        let keys = self.shared.index.lock().unwrap().dirty_keys();
+        for key in keys {
+            tracing::info!(key, "Flushing cached write left from a previous run.");
+            Shared::spawn_flush(&self.shared, &key);
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        if self.shared.is_dirty(key) {
+            self.shared.index.lock().unwrap().touch(key);
+            if let Some(file) = self.shared.read(key).await? {
+                return Ok(Some(file.data));
+            }
+        }
+        Ok(Shared::get_versioned(&self.shared, key)
+            .await?
+            .map(|(data, _)| data))
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        match self.shared.options.write_mode {
+            CacheWriteMode::WriteThrough => {
+                let _guard = self.shared.lock_key(key).await;
+                let version = match self.shared.set_versioned(key, value.clone()).await {
+                    Ok(version) => version,
+                    Err(e) => {
+                        // The write may or may not have reached the store.
+                        self.shared.invalidate(key).await?;
+                        return Err(e);
+                    }
+                };
+                self.shared
+                    .write(
+                        key,
+                        CacheFile {
+                            dirty: false,
+                            version: Some(version),
+                            data: value,
+                        },
+                    )
+                    .await
+            }
+            CacheWriteMode::WriteBehind => {
+                {
+                    // A flush in progress marks the entry clean when it finishes,
+                    // so it must not overlap with replacing the entry.
+                    let _guard = self.shared.lock_key(key).await;
+                    self.shared
+                        .write(
+                            key,
+                            CacheFile {
+                                dirty: true,
+                                version: None,
+                                data: value,
+                            },
+                        )
+                        .await?;
+                }
+                Shared::spawn_flush(&self.shared, key);
+                Ok(())
+            }
+        }
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        let _guard = self.shared.lock_key(key).await;
+        self.shared.invalidate(key).await?;
+        self.shared.inner.remove(key).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        if self.shared.is_dirty(key) {
+            return Ok(true);
+        }
+        self.shared.inner.exists(key).await
+    }
+
+    /// Lists the underlying store, so objects whose writes are still pending in
+    /// write-behind mode are not included.
+    async fn list(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<ListResult> {
+        self.shared.inner.list(prefix, cursor, limit).await
+    }
+
+    async fn list_docs(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<ListResult> {
+        self.shared.inner.list_docs(prefix, cursor, limit).await
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        Shared::get_versioned(&self.shared, key).await
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<ObjectVersion>> {
+        Shared::flush_key(&self.shared, key).await?;
+        self.shared.inner.get_version(key).await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        Shared::flush_key(&self.shared, key).await?;
+        let _guard = self.shared.lock_key(key).await;
+        let version = match self.shared.inner.set_if(key, value.clone(), expected).await {
+            Ok(version) => version,
+            Err(e @ StoreError::Conflict(_)) => {
+                // Another writer changed the object, so the cached copy is stale.
+                self.shared.invalidate(key).await?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        self.shared
+            .write(
+                key,
+                CacheFile {
+                    dirty: false,
+                    version: Some(version.clone()),
+                    data: value,
+                },
+            )
+            .await?;
+        Ok(version)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+    use y_sweet_core::store::memory::MemoryStore;
+
+    /// Counts reads of object contents from the wrapped store.
+    #[derive(Clone, Default)]
+    struct CountingStore {
+        inner: MemoryStore,
+        reads: Arc<AtomicUsize>,
+        /// Conditional writes wait while this is locked for writing.
+        paused: Arc<tokio::sync::RwLock<()>>,
+    }
+
+    #[async_trait]
+    impl Store for CountingStore {
+        async fn init(&self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.inner.get(key).await
+        }
+
+        async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+            self.inner.set(key, value).await
+        }
+
+        async fn remove(&self, key: &str) -> Result<()> {
+            self.inner.remove(key).await
+        }
+
+        async fn exists(&self, key: &str) -> Result<bool> {
+            self.inner.exists(key).await
+        }
+
+        async fn list(
+            &self,
+            prefix: &str,
+            cursor: Option<&str>,
+            limit: usize,
+        ) -> Result<ListResult> {
+            self.inner.list(prefix, cursor, limit).await
+        }
+
+        async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.inner.get_versioned(key).await
+        }
+
+        async fn get_version(&self, key: &str) -> Result<Option<ObjectVersion>> {
+            self.inner.get_version(key).await
+        }
+
+        async fn set_if(
+            &self,
+            key: &str,
+            value: Vec<u8>,
+            expected: Option<&ObjectVersion>,
+        ) -> Result<ObjectVersion> {
+            let _paused = self.paused.read().await;
+            self.inner.set_if(key, value, expected).await
+        }
+    }
+
+    impl CountingStore {
+        fn reads(&self) -> usize {
+            self.reads.load(Ordering::SeqCst)
+        }
+    }
+
+    fn options(dir: &Path, max_bytes: u64, write_mode: CacheWriteMode) -> CacheOptions {
+        CacheOptions {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            write_mode,
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("y-sweet-cache-test-{}", nanoid::nanoid!()))
+    }
+
+    #[tokio::test]
+    async fn serves_cached_objects_until_they_change() {
+        let dir = temp_dir();
+        let inner = CountingStore::default();
+        let store = CachingStore::new(
+            Box::new(inner.clone()),
+            options(&dir, 1 << 20, CacheWriteMode::WriteThrough),
+        )
+        .unwrap();
+
+        store.set_if("doc", b"a".to_vec(), None).await.unwrap();
+        assert_eq!(store.get("doc").await.unwrap(), Some(b"a".to_vec()));
+        assert_eq!(inner.reads(), 0);
+
+        // Unconditional writes are cached too.
+        store.set("other", b"x".to_vec()).await.unwrap();
+        assert_eq!(store.get("other").await.unwrap(), Some(b"x".to_vec()));
+        assert_eq!(inner.reads(), 0);
+
+        // Another node writes the object, so the cached copy is stale.
+        inner.set("doc", b"b".to_vec()).await.unwrap();
+        assert_eq!(store.get("doc").await.unwrap(), Some(b"b".to_vec()));
+        assert_eq!(store.get("doc").await.unwrap(), Some(b"b".to_vec()));
+        assert_eq!(inner.reads(), 1);
+
+        // The cache survives a restart.
+        drop(store);
+        let store = CachingStore::new(
+            Box::new(inner.clone()),
+            options(&dir, 1 << 20, CacheWriteMode::WriteThrough),
+        )
+        .unwrap();
+        assert_eq!(store.get("doc").await.unwrap(), Some(b"b".to_vec()));
+        assert_eq!(inner.reads(), 1);
+
+        inner.remove("doc").await.unwrap();
+        assert_eq!(store.get("doc").await.unwrap(), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() {
+        let dir = temp_dir();
+        let inner = CountingStore::default();
+        // Room for two 100-byte objects and their headers, but not three.
+        let store = CachingStore::new(
+            Box::new(inner.clone()),
+            options(&dir, 250, CacheWriteMode::WriteThrough),
+        )
+        .unwrap();
+
+        for key in ["a", "b"] {
+            store.set_if(key, vec![0; 100], None).await.unwrap();
+        }
+        store.get("a").await.unwrap();
+        store.set_if("c", vec![0; 100], None).await.unwrap();
+
+        let mut cached: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        cached.sort();
+        assert_eq!(cached, vec!["a", "c"]);
+
+        assert_eq!(inner.reads(), 0);
+        store.get("b").await.unwrap();
+        assert_eq!(inner.reads(), 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn writes_behind() {
+        let dir = temp_dir();
+        let inner = CountingStore::default();
+        let store = CachingStore::new(
+            Box::new(inner.clone()),
+            options(&dir, 1 << 20, CacheWriteMode::WriteBehind),
+        )
+        .unwrap();
+
+        store.set("doc/version", b"a".to_vec()).await.unwrap();
+        assert_eq!(store.get("doc/version").await.unwrap(), Some(b"a".to_vec()));
+        assert!(store.exists("doc/version").await.unwrap());
+
+        store.flush().await.unwrap();
+        assert_eq!(
+            inner.inner.get("doc/version").await.unwrap(),
+            Some(b"a".to_vec())
+        );
+
+        // Once flushed, the entry is clean and validated like any other.
+        assert_eq!(store.get("doc/version").await.unwrap(), Some(b"a".to_vec()));
+        assert_eq!(inner.reads(), 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn writes_during_a_flush_are_not_lost() {
+        let dir = temp_dir();
+        let inner = CountingStore::default();
+        let store = CachingStore::new(
+            Box::new(inner.clone()),
+            options(&dir, 1 << 20, CacheWriteMode::WriteBehind),
+        )
+        .unwrap();
+
+        // The flush of the first write stalls in the underlying store.
+        let paused = inner.paused.write().await;
+        store.set("doc", b"a".to_vec()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let second = tokio::spawn({
+            let store = store.clone();
+            async move { store.set("doc", b"b".to_vec()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!second.is_finished());
+
+        drop(paused);
+        second.await.unwrap().unwrap();
+        store.flush().await.unwrap();
+        assert_eq!(inner.inner.get("doc").await.unwrap(), Some(b"b".to_vec()));
+        assert_eq!(store.get("doc").await.unwrap(), Some(b"b".to_vec()));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn escapes_file_names() {
+        for key in ["doc/data.ysweet", ".tmp-x", "a%2Fb", "ünï"] {
+            let name = file_name(key);
+            assert!(!name.contains('/') && !name.starts_with('.'));
+            assert_eq!(key_from_file_name(&name).as_deref(), Some(key));
+        }
+    }
+}
@@ -1,18 +1,165 @@
 use async_trait::async_trait;
 use std::{
-    fs::{create_dir_all, remove_file},
-    path::PathBuf,
+    fs::{create_dir_all, File, Metadata},
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::UNIX_EPOCH,
 };
-use y_sweet_core::store::{Result, Store, StoreError};
+use tokio::sync::Mutex;
+use y_sweet_core::store::{ListResult, ObjectVersion, Result, Store, StoreError};
+
+/// Files whose names start with this prefix are in-progress writes, not keys.
+pub(crate) const TEMP_FILE_PREFIX: &str = ".tmp-";
 
 pub struct FileSystemStore {
-    base_path: PathBuf,
+    base_path: Arc<PathBuf>,
+    /// Serializes conditional writes made by this process.
+    write_lock: Mutex<()>,
+}
+
+/// Map an IO error to the closest `StoreError`.
+pub(crate) fn io_error(e: std::io::Error, context: &str) -> StoreError {
+    let message = format!("{}: {}", context, e);
+    match e.kind() {
+        ErrorKind::NotFound => StoreError::DoesNotExist(message),
+        ErrorKind::PermissionDenied => StoreError::NotAuthorized(message),
+        _ => StoreError::ConnectionError(message),
+    }
+}
+
+/// Run blocking filesystem work off of the async worker threads.
+pub(crate) async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| StoreError::ConnectionError(format!("Filesystem task failed: {}", e)))?
 }
 
 impl FileSystemStore {
     pub fn new(base_path: PathBuf) -> std::result::Result<Self, std::io::Error> {
         create_dir_all(base_path.clone())?;
-        Ok(Self { base_path })
+        Ok(Self {
+            base_path: Arc::new(base_path),
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    /// Derive an object version from the file's inode, modification time, and size.
+    /// Since every write replaces the file, the inode changes on every write.
+    fn file_version(metadata: &Metadata) -> ObjectVersion {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_nanos())
+            .unwrap_or_default();
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        ObjectVersion(format!("{:x}-{:x}-{:x}", inode, modified, metadata.len()))
+    }
+
+    fn current_version(path: &Path) -> Result<Option<ObjectVersion>> {
+        match std::fs::metadata(path) {
+            Ok(metadata) => Ok(Some(Self::file_version(&metadata))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(io_error(e, "Error reading file metadata")),
+        }
+    }
+
+    fn read_file(path: &Path) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(io_error(e, "Error opening file")),
+        };
+        let metadata = file
+            .metadata()
+            .map_err(|e| io_error(e, "Error reading file metadata"))?;
+        let mut contents = Vec::with_capacity(metadata.len() as usize);
+        file.read_to_end(&mut contents)
+            .map_err(|e| io_error(e, "Error reading file"))?;
+        Ok(Some((contents, Self::file_version(&metadata))))
+    }
+
+    /// Atomically replace the file at `path` with `value`: write a temporary file
+    /// next to it, fsync it, rename it into place, and fsync the directory so that
+    /// a crash leaves either the old or the new contents, never a partial file.
+    pub(crate) fn write_file(path: &Path, value: &[u8]) -> Result<ObjectVersion> {
+        let dir = path.parent().expect("Bad parent");
+        create_dir_all(dir).map_err(|e| io_error(e, "Error creating directories"))?;
+
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .expect("Bad file name");
+        let temp_path = dir.join(format!(
+            "{}{}-{}",
+            TEMP_FILE_PREFIX,
+            nanoid::nanoid!(),
+            file_name
+        ));
+
+        let result = (|| {
+            let mut file =
+                File::create(&temp_path).map_err(|e| io_error(e, "Error creating file"))?;
+            file.write_all(value)
+                .map_err(|e| io_error(e, "Error writing file"))?;
+            file.sync_all()
+                .map_err(|e| io_error(e, "Error syncing file"))?;
+            let metadata = file
+                .metadata()
+                .map_err(|e| io_error(e, "Error reading file metadata"))?;
+            std::fs::rename(&temp_path, path).map_err(|e| io_error(e, "Error renaming file"))?;
+            Ok(Self::file_version(&metadata))
+        })();
+
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        let version = result?;
+
+        // Directories cannot be opened as files on Windows, and renames there are
+        // already durable once they return.
+        #[cfg(unix)]
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|e| io_error(e, "Error syncing directory"))?;
+
+        Ok(version)
+    }
+
+    /// Recursively collect the keys of all files under `dir`, where `key_prefix`
+    /// is the key corresponding to `dir` (empty or ending in `/`).
+    fn collect_keys(dir: &Path, key_prefix: &str, keys: &mut Vec<String>) -> std::io::Result<()> {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        for entry in entries {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(|s| s.to_string()) else {
+                continue;
+            };
+            if name.starts_with(TEMP_FILE_PREFIX) {
+                continue;
+            }
+            let key = format!("{}{}", key_prefix, name);
+            if entry.file_type()?.is_dir() {
+                Self::collect_keys(&entry.path(), &format!("{}/", key), keys)?;
+            } else {
+                keys.push(key);
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -24,32 +171,228 @@ impl Store for FileSystemStore {
 
     async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
         let path = self.base_path.join(key);
-        let contents = std::fs::read(path);
-        match contents {
-            Ok(contents) => Ok(Some(contents)),
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
-            Err(e) => Err(StoreError::ConnectionError(e.to_string())),
-        }
+        let result = blocking(move || Self::read_file(&path)).await?;
+        Ok(result.map(|(contents, _)| contents))
     }
 
     async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
         let path = self.base_path.join(key);
-        create_dir_all(path.parent().expect("Bad parent"))
-            .map_err(|_| StoreError::NotAuthorized("Error creating directories".to_string()))?;
-        std::fs::write(path, value)
-            .map_err(|_| StoreError::NotAuthorized("Error writing file.".to_string()))?;
+        blocking(move || Self::write_file(&path, &value)).await?;
         Ok(())
     }
 
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        let path = self.base_path.join(key);
+        blocking(move || Self::read_file(&path)).await
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<ObjectVersion>> {
+        let path = self.base_path.join(key);
+        blocking(move || Self::current_version(&path)).await
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        let path = self.base_path.join(key);
+        let key = key.to_string();
+        let expected = expected.cloned();
+        let _guard = self.write_lock.lock().await;
+
+        blocking(move || {
+            let current = Self::current_version(&path)?;
+            if current != expected {
+                return Err(StoreError::Conflict(format!(
+                    "Expected version {:?} of {}, found {:?}.",
+                    expected, key, current
+                )));
+            }
+            Self::write_file(&path, &value)
+        })
+        .await
+    }
+
     async fn remove(&self, key: &str) -> Result<()> {
         let path = self.base_path.join(key);
-        remove_file(path)
-            .map_err(|_| StoreError::NotAuthorized("Error removing file.".to_string()))?;
-        Ok(())
+        blocking(move || match std::fs::remove_file(path) {
+            Ok(()) => Ok(()),
+            // Removing a missing key is a no-op, as in the other stores.
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(io_error(e, "Error removing file")),
+        })
+        .await
     }
 
     async fn exists(&self, key: &str) -> Result<bool> {
         let path = self.base_path.join(key);
-        Ok(path.exists())
+        blocking(move || match std::fs::metadata(path) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(io_error(e, "Error reading file metadata")),
+        })
+        .await
+    }
+
+    async fn list(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<ListResult> {
+        // Only walk the deepest directory that every matching key must be under.
+        let dir_prefix = match prefix.rfind('/') {
+            Some(idx) => prefix[..=idx].to_string(),
+            None => String::new(),
+        };
+
+        let base_path = self.base_path.clone();
+        let mut keys = blocking(move || {
+            let mut keys = Vec::new();
+            Self::collect_keys(&base_path.join(&dir_prefix), &dir_prefix, &mut keys)
+                .map_err(|e| io_error(e, "Error listing directory"))?;
+            Ok(keys)
+        })
+        .await?;
+
+        keys.retain(|key| {
+            key.starts_with(prefix) && cursor.is_none_or(|cursor| key.as_str() > cursor)
+        });
+        keys.sort();
+
+        let next_cursor = if keys.len() > limit {
+            keys.truncate(limit);
+            keys.last().cloned()
+        } else {
+            None
+        };
+
+        Ok(ListResult { keys, next_cursor })
+    }
+
+    /// Reads only the top-level directories, rather than every document's files.
+    async fn list_docs(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<ListResult> {
+        let base_path = self.base_path.clone();
+        let prefix = prefix.to_string();
+        let cursor = cursor.map(str::to_string);
+        blocking(move || {
+            let mut names = Vec::new();
+            let entries = match std::fs::read_dir(base_path.as_ref()) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(ListResult::default()),
+                Err(e) => return Err(io_error(e, "Error listing directory")),
+            };
+            for entry in entries {
+                let entry = entry.map_err(|e| io_error(e, "Error listing directory"))?;
+                let Some(name) = entry.file_name().to_str().map(|s| s.to_string()) else {
+                    continue;
+                };
+                if name.starts_with(&prefix)
+                    && cursor.as_ref().is_none_or(|cursor| name > *cursor)
+                    && !name.starts_with(TEMP_FILE_PREFIX)
+                {
+                    names.push(name);
+                }
+            }
+            names.sort();
+
+            let mut keys = Vec::new();
+            for name in names {
+                if !base_path.join(&name).join("data.ysweet").is_file() {
+                    continue;
+                }
+                if keys.len() == limit {
+                    let next_cursor = keys.last().cloned();
+                    return Ok(ListResult { keys, next_cursor });
+                }
+                keys.push(name);
+            }
+            Ok(ListResult {
+                keys,
+                next_cursor: None,
+            })
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_store() -> (FileSystemStore, PathBuf) {
+        let path = std::env::temp_dir().join(format!("y-sweet-test-{}", nanoid::nanoid!()));
+        (FileSystemStore::new(path.clone()).unwrap(), path)
+    }
+
+    #[tokio::test]
+    async fn replaces_files_atomically() {
+        let (store, path) = temp_store();
+
+        store
+            .set("doc/data.ysweet", b"first".to_vec())
+            .await
+            .unwrap();
+        store
+            .set("doc/data.ysweet", b"second".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get("doc/data.ysweet").await.unwrap(),
+            Some(b"second".to_vec())
+        );
+
+        // No temporary files are left behind, and none show up in listings.
+        let entries: Vec<_> = std::fs::read_dir(path.join("doc"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, vec!["data.ysweet"]);
+        let listed = store.list("", None, 10).await.unwrap();
+        assert_eq!(listed.keys, vec!["doc/data.ysweet"]);
+
+        store.remove("doc/data.ysweet").await.unwrap();
+        store.remove("doc/data.ysweet").await.unwrap();
+        assert!(!store.exists("doc/data.ysweet").await.unwrap());
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_stale_conditional_writes() {
+        let (store, path) = temp_store();
+
+        let first = store
+            .set_if("doc/data.ysweet", b"a".to_vec(), None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.set_if("doc/data.ysweet", b"b".to_vec(), None).await,
+            Err(StoreError::Conflict(_))
+        ));
+
+        let second = store
+            .set_if("doc/data.ysweet", b"b".to_vec(), Some(&first))
+            .await
+            .unwrap();
+        assert_ne!(first, second);
+        assert!(matches!(
+            store
+                .set_if("doc/data.ysweet", b"c".to_vec(), Some(&first))
+                .await,
+            Err(StoreError::Conflict(_))
+        ));
+
+        let (contents, version) = store
+            .get_versioned("doc/data.ysweet")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(contents, b"b".to_vec());
+        assert_eq!(version, second);
+
+        std::fs::remove_dir_all(path).unwrap();
     }
 }
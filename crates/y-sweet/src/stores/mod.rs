@@ -1 +1,4 @@
+pub mod caching;
 pub mod filesystem;
+pub mod registry;
+pub mod sqlite;
@@ -0,0 +1,212 @@
+//! Resolving store URLs, like `s3://bucket/prefix` or `/path/to/dir`, to stores.
+
+use super::{filesystem::FileSystemStore, sqlite::SqliteStore};
+use anyhow::Result;
+use std::{collections::HashMap, env, path::PathBuf};
+use y_sweet_core::store::{
+    memory::MemoryStore,
+    s3::{parse_key_value_pairs, S3Config, S3Store, ServerSideEncryption},
+    Store,
+};
+
+/// Creates a store from a store URL.
+pub type StoreFactory = Box<dyn Fn(&str) -> Result<Box<dyn Store>> + Send + Sync>;
+
+/// Maps URL schemes (the part before `://`) to the factories that create stores
+/// for them. Values without a scheme are treated as filesystem paths.
+///
+/// The default registry handles `file://`, `s3://`, `sqlite://` and `memory://`;
+/// applications embedding y-sweet can add their own schemes or replace these.
+pub struct StoreRegistry {
+    factories: HashMap<String, StoreFactory>,
+}
+
+impl Default for StoreRegistry {
+    fn default() -> Self {
+        Self::empty()
+            .with_scheme("file", |url| file_store(strip_scheme(url)))
+            .with_scheme("s3", s3_store)
+            .with_scheme("sqlite", |url| {
+                Ok(Box::new(SqliteStore::new(&PathBuf::from(strip_scheme(
+                    url,
+                )))?))
+            })
+            .with_scheme("memory", |_| {
+                tracing::warn!(
+                    "Using an in-memory store. Documents will be lost when the server stops."
+                );
+                Ok(Box::new(MemoryStore::new()))
+            })
+    }
+}
+
+impl StoreRegistry {
+    /// A registry with no schemes, so that only filesystem paths are accepted.
+    pub fn empty() -> Self {
+        Self {
+            factories: HashMap::new(),
+        }
+    }
+
+    /// Register `factory` for URLs starting with `{scheme}://`, replacing any
+    /// factory previously registered for the scheme.
+    pub fn register<F>(&mut self, scheme: &str, factory: F)
+    where
+        F: Fn(&str) -> Result<Box<dyn Store>> + Send + Sync + 'static,
+    {
+        self.factories.insert(scheme.to_string(), Box::new(factory));
+    }
+
+    pub fn with_scheme<F>(mut self, scheme: &str, factory: F) -> Self
+    where
+        F: Fn(&str) -> Result<Box<dyn Store>> + Send + Sync + 'static,
+    {
+        self.register(scheme, factory);
+        self
+    }
+
+    /// Create the store for `url`.
+    pub fn open(&self, url: &str) -> Result<Box<dyn Store>> {
+        let Some((scheme, _)) = url.split_once("://") else {
+            return file_store(url);
+        };
+        let factory = self
+            .factories
+            .get(scheme)
+            .ok_or_else(|| anyhow::anyhow!("Unknown store scheme {:?} in {}", scheme, url))?;
+        factory(url)
+    }
+}
+
+fn strip_scheme(url: &str) -> &str {
+    url.split_once("://").map_or(url, |(_, rest)| rest)
+}
+
+fn file_store(path: &str) -> Result<Box<dyn Store>> {
+    Ok(Box::new(FileSystemStore::new(PathBuf::from(path))?))
+}
+
+fn s3_store(url: &str) -> Result<Box<dyn Store>> {
+    let url = url::Url::parse(url)?;
+    let bucket = url
+        .host_str()
+        .ok_or_else(|| anyhow::anyhow!("Invalid S3 URL"))?
+        .to_owned();
+    let bucket_prefix = url.path().trim_start_matches('/').to_owned();
+    let bucket_prefix = (!bucket_prefix.is_empty()).then_some(bucket_prefix); // "" => None
+    let config = parse_s3_config_from_env_and_args(bucket, bucket_prefix)?;
+    Ok(Box::new(S3Store::new(config)?))
+}
+
+const DEFAULT_S3_REGION: &str = "us-east-1";
+
+const S3_ACCESS_KEY_ID: &str = "AWS_ACCESS_KEY_ID";
+const S3_SECRET_ACCESS_KEY: &str = "AWS_SECRET_ACCESS_KEY";
+const S3_SESSION_TOKEN: &str = "AWS_SESSION_TOKEN";
+const S3_REGION: &str = "AWS_REGION";
+const S3_ENDPOINT: &str = "AWS_ENDPOINT_URL_S3";
+const S3_USE_PATH_STYLE: &str = "AWS_S3_USE_PATH_STYLE";
+const S3_MULTIPART_THRESHOLD: &str = "Y_SWEET_S3_MULTIPART_THRESHOLD";
+const S3_MULTIPART_PART_SIZE: &str = "Y_SWEET_S3_MULTIPART_PART_SIZE";
+const S3_MAX_RETRIES: &str = "Y_SWEET_S3_MAX_RETRIES";
+const S3_REQUEST_TIMEOUT_SECONDS: &str = "Y_SWEET_S3_REQUEST_TIMEOUT_SECONDS";
+const S3_SERVER_SIDE_ENCRYPTION: &str = "Y_SWEET_S3_SSE";
+const S3_SERVER_SIDE_ENCRYPTION_KEY: &str = "Y_SWEET_S3_SSE_KEY";
+const S3_STORAGE_CLASS: &str = "Y_SWEET_S3_STORAGE_CLASS";
+const S3_METADATA: &str = "Y_SWEET_S3_METADATA";
+const S3_TAGS: &str = "Y_SWEET_S3_TAGS";
+
+fn non_empty_env(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn parse_number_env<T: std::str::FromStr>(name: &str, default: T) -> anyhow::Result<T> {
+    match env::var(name) {
+        Ok(value) if !value.is_empty() => value
+            .parse()
+            .map_err(|_| anyhow::anyhow!("{} must be a non-negative integer", name)),
+        _ => Ok(default),
+    }
+}
+
+/// Build an `S3Config` for `bucket` from the standard AWS environment variables.
+pub fn parse_s3_config_from_env_and_args(
+    bucket: String,
+    prefix: Option<String>,
+) -> anyhow::Result<S3Config> {
+    let use_path_style = env::var(S3_USE_PATH_STYLE).ok();
+    let path_style = if let Some(use_path_style) = use_path_style {
+        if use_path_style.to_lowercase() == "true" {
+            true
+        } else if use_path_style.to_lowercase() == "false" || use_path_style.is_empty() {
+            false
+        } else {
+            anyhow::bail!(
+                "If AWS_S3_USE_PATH_STYLE is set, it must be either \"true\" or \"false\""
+            )
+        }
+    } else {
+        false
+    };
+
+    Ok(S3Config {
+        // Without static credentials, S3Store falls back to the rest of the AWS
+        // credential chain: profiles, web identity tokens and instance metadata.
+        key: env::var(S3_ACCESS_KEY_ID).ok(),
+        region: env::var(S3_REGION).unwrap_or_else(|_| DEFAULT_S3_REGION.to_string()),
+        endpoint: env::var(S3_ENDPOINT).unwrap_or_else(|_| {
+            format!(
+                "https://s3.dualstack.{}.amazonaws.com",
+                env::var(S3_REGION).unwrap_or_else(|_| DEFAULT_S3_REGION.to_string())
+            )
+        }),
+        secret: env::var(S3_SECRET_ACCESS_KEY).ok(),
+        token: env::var(S3_SESSION_TOKEN).ok(),
+        bucket,
+        bucket_prefix: prefix,
+        // If the endpoint is overridden, we assume that the user wants path-style URLs.
+        path_style,
+        multipart_threshold: parse_number_env(
+            S3_MULTIPART_THRESHOLD,
+            S3Config::DEFAULT_MULTIPART_THRESHOLD,
+        )?,
+        multipart_part_size: parse_number_env(
+            S3_MULTIPART_PART_SIZE,
+            S3Config::DEFAULT_MULTIPART_PART_SIZE,
+        )?,
+        max_retries: parse_number_env(S3_MAX_RETRIES, S3Config::DEFAULT_MAX_RETRIES)?,
+        request_timeout_secs: parse_number_env(
+            S3_REQUEST_TIMEOUT_SECONDS,
+            S3Config::DEFAULT_REQUEST_TIMEOUT_SECS,
+        )?,
+        server_side_encryption: non_empty_env(S3_SERVER_SIDE_ENCRYPTION)
+            .map(|mode| {
+                ServerSideEncryption::parse(&mode, non_empty_env(S3_SERVER_SIDE_ENCRYPTION_KEY))
+            })
+            .transpose()?,
+        storage_class: non_empty_env(S3_STORAGE_CLASS),
+        metadata: parse_key_value_pairs(&non_empty_env(S3_METADATA).unwrap_or_default())?,
+        tags: parse_key_value_pairs(&non_empty_env(S3_TAGS).unwrap_or_default())?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn resolves_registered_schemes() {
+        let shared = MemoryStore::new();
+        let registry = StoreRegistry::default().with_scheme("shared", {
+            let shared = shared.clone();
+            move |_| Ok(Box::new(shared.clone()))
+        });
+
+        let store = registry.open("shared://anything").unwrap();
+        store.set("doc", b"hello".to_vec()).await.unwrap();
+        assert_eq!(shared.len(), 1);
+
+        assert!(registry.open("memory://").is_ok());
+        assert!(registry.open("unknown://x").is_err());
+    }
+}
@@ -0,0 +1,300 @@
+use super::filesystem::blocking;
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use y_sweet_core::store::{ListResult, ObjectVersion, Result, Store, StoreError};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS objects (
+        key TEXT PRIMARY KEY NOT NULL,
+        value BLOB NOT NULL,
+        version INTEGER NOT NULL
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS meta (
+        name TEXT PRIMARY KEY NOT NULL,
+        value INTEGER NOT NULL
+    );
+    INSERT OR IGNORE INTO meta (name, value) VALUES ('next_version', 1);
+";
+
+fn sqlite_error(e: rusqlite::Error) -> StoreError {
+    StoreError::ConnectionError(format!("SQLite error: {}", e))
+}
+
+/// A store that keeps all objects in a single SQLite database file.
+///
+/// Every write is a transaction, and object versions are drawn from a counter in
+/// the database, so a version is never reused even if its object is removed.
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+        Ok(Self::from_connection(Connection::open(path)?)?)
+    }
+
+    #[cfg(test)]
+    fn in_memory() -> rusqlite::Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(conn: Connection) -> rusqlite::Result<Self> {
+        // WAL lets readers proceed during a write, and keeps writes durable with
+        // fewer fsyncs than the default rollback journal.
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Run `f` with the connection on a blocking thread.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        blocking(move || f(&mut conn.lock().unwrap()).map_err(sqlite_error)).await
+    }
+
+    /// Write `value` at a fresh version inside `txn`, returning the version.
+    fn write(txn: &rusqlite::Transaction, key: &str, value: &[u8]) -> rusqlite::Result<i64> {
+        let version: i64 = txn.query_row(
+            "UPDATE meta SET value = value + 1 WHERE name = 'next_version' RETURNING value - 1",
+            [],
+            |row| row.get(0),
+        )?;
+        txn.execute(
+            "INSERT INTO objects (key, value, version) VALUES (?1, ?2, ?3)
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value, version = excluded.version",
+            params![key, value, version],
+        )?;
+        Ok(version)
+    }
+}
+
+fn object_version(version: i64) -> ObjectVersion {
+    ObjectVersion(version.to_string())
+}
+
+#[async_trait]
+impl Store for SqliteStore {
+    async fn init(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let key = key.to_string();
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT value FROM objects WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        let key = key.to_string();
+        self.with_conn(move |conn| {
+            let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            Self::write(&txn, &key, &value)?;
+            txn.commit()
+        })
+        .await
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        let key = key.to_string();
+        self.with_conn(move |conn| {
+            conn.execute("DELETE FROM objects WHERE key = ?1", params![key])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        let key = key.to_string();
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM objects WHERE key = ?1)",
+                params![key],
+                |row| row.get(0),
+            )
+        })
+        .await
+    }
+
+    async fn list(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<ListResult> {
+        let prefix = prefix.to_string();
+        let cursor = cursor.map(str::to_string);
+        let mut keys: Vec<String> = self
+            .with_conn(move |conn| {
+                // Keys are scanned in order from the prefix, which uses the primary
+                // key index, and the scan stops at the first key without the prefix.
+                let mut statement = conn.prepare_cached(
+                    "SELECT key FROM objects WHERE key >= ?1 AND (?2 IS NULL OR key > ?2)
+                     ORDER BY key",
+                )?;
+                let rows = statement.query_map(params![prefix, cursor], |row| row.get(0))?;
+                let mut keys = Vec::new();
+                for key in rows {
+                    let key: String = key?;
+                    if !key.starts_with(&prefix) || keys.len() > limit {
+                        break;
+                    }
+                    keys.push(key);
+                }
+                Ok(keys)
+            })
+            .await?;
+
+        let next_cursor = if keys.len() > limit {
+            keys.truncate(limit);
+            keys.last().cloned()
+        } else {
+            None
+        };
+        Ok(ListResult { keys, next_cursor })
+    }
+
+    async fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, ObjectVersion)>> {
+        let key = key.to_string();
+        let result = self
+            .with_conn(move |conn| {
+                conn.query_row(
+                    "SELECT value, version FROM objects WHERE key = ?1",
+                    params![key],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()
+            })
+            .await?;
+        Ok(result.map(|(value, version)| (value, object_version(version))))
+    }
+
+    async fn get_version(&self, key: &str) -> Result<Option<ObjectVersion>> {
+        let key = key.to_string();
+        let version = self
+            .with_conn(move |conn| {
+                conn.query_row(
+                    "SELECT version FROM objects WHERE key = ?1",
+                    params![key],
+                    |row| row.get(0),
+                )
+                .optional()
+            })
+            .await?;
+        Ok(version.map(object_version))
+    }
+
+    async fn set_if(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expected: Option<&ObjectVersion>,
+    ) -> Result<ObjectVersion> {
+        let key = key.to_string();
+        let expected = expected.cloned();
+        let result = self
+            .with_conn(move |conn| {
+                let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let current: Option<i64> = txn
+                    .query_row(
+                        "SELECT version FROM objects WHERE key = ?1",
+                        params![key],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                let current = current.map(object_version);
+                if current != expected {
+                    return Ok(Err(StoreError::Conflict(format!(
+                        "Expected version {:?} of {}, found {:?}.",
+                        expected, key, current
+                    ))));
+                }
+                let version = Self::write(&txn, &key, &value)?;
+                txn.commit()?;
+                Ok(Ok(object_version(version)))
+            })
+            .await?;
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn stores_objects() {
+        let store = SqliteStore::in_memory().unwrap();
+
+        store.set("a/data.ysweet", b"a".to_vec()).await.unwrap();
+        store.set("b/data.ysweet", b"b".to_vec()).await.unwrap();
+        store.set("b/log/1", b"c".to_vec()).await.unwrap();
+        store.set("c/data.ysweet", b"d".to_vec()).await.unwrap();
+
+        assert_eq!(
+            store.get("b/data.ysweet").await.unwrap(),
+            Some(b"b".to_vec())
+        );
+        assert!(store.exists("c/data.ysweet").await.unwrap());
+
+        let page = store.list("b/", None, 1).await.unwrap();
+        assert_eq!(page.keys, vec!["b/data.ysweet"]);
+        let page = store
+            .list("b/", page.next_cursor.as_deref(), 1)
+            .await
+            .unwrap();
+        assert_eq!(page.keys, vec!["b/log/1"]);
+        assert_eq!(page.next_cursor, None);
+
+        store.remove("c/data.ysweet").await.unwrap();
+        assert!(!store.exists("c/data.ysweet").await.unwrap());
+        assert_eq!(store.get("c/data.ysweet").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_stale_conditional_writes() {
+        let store = SqliteStore::in_memory().unwrap();
+
+        let first = store.set_if("doc", b"a".to_vec(), None).await.unwrap();
+        assert!(matches!(
+            store.set_if("doc", b"b".to_vec(), None).await,
+            Err(StoreError::Conflict(_))
+        ));
+
+        let second = store
+            .set_if("doc", b"b".to_vec(), Some(&first))
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.set_if("doc", b"c".to_vec(), Some(&first)).await,
+            Err(StoreError::Conflict(_))
+        ));
+        assert_eq!(
+            store.get_version("doc").await.unwrap(),
+            Some(second.clone())
+        );
+
+        // Versions are not reused after an object is removed and written again.
+        store.remove("doc").await.unwrap();
+        let third = store.set_if("doc", b"d".to_vec(), None).await.unwrap();
+        assert_ne!(third, first);
+        assert_ne!(third, second);
+    }
+}